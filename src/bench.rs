@@ -0,0 +1,97 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use futures::{StreamExt, TryStreamExt, stream};
+use humansize::{DECIMAL, format_size};
+use object_store::{ObjectStore, WriteMultipart, path::Path};
+use tracing::debug;
+use ulid::Ulid;
+
+use crate::{BenchArgs, uploader::CHUNK_SIZE};
+
+pub struct Bench {
+    s3: Arc<dyn ObjectStore>,
+    sizes: Vec<u64>,
+    count: usize,
+    concurrency: usize,
+    part_concurrency: usize,
+}
+
+impl Bench {
+    pub fn new(cli: &BenchArgs) -> Result<Self> {
+        Ok(Self {
+            s3: cli.bucket.build()?,
+            sizes: cli.sizes.clone(),
+            count: cli.count,
+            concurrency: cli.concurrency,
+            part_concurrency: cli.part_concurrency,
+        })
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        for &size in &self.sizes {
+            // the content doesn't matter since it is uploaded as is
+            let data = Bytes::from(vec![0x5a; size as usize]);
+            let start = Instant::now();
+            let mut latencies: Vec<Duration> = stream::iter(0..self.count)
+                .map(|_| self.upload_one(data.clone()))
+                .buffer_unordered(self.concurrency)
+                .try_collect()
+                .await?;
+            let elapsed = start.elapsed();
+
+            latencies.sort();
+            let total = size * self.count as u64;
+            println!(
+                "{} x {}: {}/s, latency p50 {:.2?} p90 {:.2?} p99 {:.2?}",
+                self.count,
+                format_size(size, DECIMAL),
+                format_size((total as f64 / elapsed.as_secs_f64()) as u64, DECIMAL),
+                percentile(&latencies, 50),
+                percentile(&latencies, 90),
+                percentile(&latencies, 99),
+            );
+        }
+        Ok(())
+    }
+
+    /// upload `data` to a throwaway object the way a nar of this size would be, then delete it
+    async fn upload_one(&self, data: Bytes) -> Result<Duration> {
+        let path = Path::parse(format!("nixcp-bench-{}", Ulid::new()))?;
+        debug!("bench: writing {path}");
+        let start = Instant::now();
+        if data.len() <= CHUNK_SIZE {
+            self.s3.put(&path, data.into()).await?;
+        } else {
+            let upload = self.s3.put_multipart(&path).await?;
+            let mut s3_writer = WriteMultipart::new_with_chunk_size(upload, CHUNK_SIZE);
+            let mut offset = 0;
+            while offset < data.len() {
+                let end = (offset + CHUNK_SIZE).min(data.len());
+                s3_writer.wait_for_capacity(self.part_concurrency).await?;
+                s3_writer.put(data.slice(offset..end));
+                offset = end;
+            }
+            s3_writer.finish().await?;
+        }
+        let latency = start.elapsed();
+        self.s3
+            .delete(&path)
+            .await
+            .context(format!("delete {path}"))?;
+        Ok(latency)
+    }
+}
+
+/// `p`th percentile of `sorted`
+pub(crate) fn percentile<T: Copy + Default>(sorted: &[T], p: usize) -> T {
+    if sorted.is_empty() {
+        return T::default();
+    }
+    let i = (sorted.len() * p).div_ceil(100).saturating_sub(1);
+    sorted[i.min(sorted.len() - 1)]
+}
@@ -43,9 +43,9 @@ impl FfiNixStore {
     }
 }
 
-/// Obtain a handle to the Nix store.
-pub unsafe fn open_nix_store() -> Result<FfiNixStore> {
-    match ffi::open_nix_store() {
+/// Obtain a handle to the Nix store at `store_uri`, or the configured one if it is empty.
+pub unsafe fn open_nix_store(store_uri: &str) -> Result<FfiNixStore> {
+    match ffi::open_nix_store(store_uri) {
         Ok(ptr) => {
             let cell = UnsafeCell::new(ptr);
             Ok(FfiNixStore(cell))
@@ -216,8 +216,21 @@ mod ffi {
             sender: Box<AsyncWriteSender>,
         ) -> Result<()>;
 
-        /// Obtains a handle to the Nix store.
-        fn open_nix_store() -> Result<UniquePtr<CNixStore>>;
+        /// Returns the system a derivation builds for, or an empty string if the derivation
+        /// is not in the store.
+        fn derivation_system(self: Pin<&mut CNixStore>, store_path: &[u8]) -> Result<String>;
+
+        /// Returns the store paths that gc roots point to.
+        fn find_roots(self: Pin<&mut CNixStore>) -> Result<UniquePtr<CxxVector<CxxString>>>;
+
+        /// Returns every valid path in the store.
+        fn query_all_valid_paths(
+            self: Pin<&mut CNixStore>,
+        ) -> Result<UniquePtr<CxxVector<CxxString>>>;
+
+        /// Obtains a handle to the Nix store at `store_uri`, or the configured one if it is
+        /// empty.
+        fn open_nix_store(store_uri: &str) -> Result<UniquePtr<CNixStore>>;
 
         // =========
         // CPathInfo
@@ -228,10 +241,24 @@ mod ffi {
         /// Returns the size of the NAR.
         fn nar_size(self: Pin<&mut CPathInfo>) -> u64;
 
+        /// Returns the SHA-256 hash of the NAR.
+        fn nar_hash(self: Pin<&mut CPathInfo>) -> &[u8];
+
         /// Returns the references of the store path.
         fn references(self: Pin<&mut CPathInfo>) -> UniquePtr<CxxVector<CxxString>>;
 
         /// Returns the possibly invalid signatures attached to the store path.
         fn sigs(self: Pin<&mut CPathInfo>) -> UniquePtr<CxxVector<CxxString>>;
+
+        /// Returns the content address of the store path, or an empty string if it is
+        /// input addressed.
+        fn ca(self: Pin<&mut CPathInfo>) -> String;
+
+        /// Returns the derivation that produced the store path, or an empty string if it is
+        /// unknown.
+        fn deriver(self: Pin<&mut CPathInfo>) -> String;
+
+        /// Returns whether the store path was built locally rather than substituted.
+        fn ultimate(self: Pin<&mut CPathInfo>) -> bool;
     }
 }
@@ -61,6 +61,26 @@ uint64_t CPathInfo::nar_size() {
 	return this->pi->narSize;
 }
 
+RHashSlice CPathInfo::nar_hash() {
+	auto &hash = this->pi->narHash;
+	return RHashSlice((const unsigned char *)hash.hash, hash.hashSize);
+}
+
+RString CPathInfo::ca() {
+	return nix::renderContentAddress(this->pi->ca);
+}
+
+RString CPathInfo::deriver() {
+	if (!this->pi->deriver) {
+		return RString();
+	}
+	return RString(std::string(this->pi->deriver->to_string()));
+}
+
+bool CPathInfo::ultimate() {
+	return this->pi->ultimate;
+}
+
 std::unique_ptr<std::vector<std::string>> CPathInfo::sigs() {
 	std::vector<std::string> result;
 	for (auto&& elem : this->pi->sigs) {
@@ -81,7 +101,7 @@ std::unique_ptr<std::vector<std::string>> CPathInfo::references() {
 // CNixStore
 // =========
 
-CNixStore::CNixStore() {
+CNixStore::CNixStore(RStr store_uri) {
 	std::map<std::string, std::string> params;
 	std::lock_guard<std::mutex> lock(g_init_nix_mutex);
 
@@ -90,7 +110,12 @@ CNixStore::CNixStore() {
 		g_init_nix_done = true;
 	}
 
-	this->store = nix::openStore(nix::settings.storeUri.get(), params);
+	// an empty uri means the store nix is configured to use
+	std::string uri(store_uri);
+	if (uri.empty()) {
+		uri = nix::settings.storeUri.get();
+	}
+	this->store = nix::openStore(uri, params);
 }
 
 std::unique_ptr<CPathInfo> CNixStore::query_path_info(RBasePathSlice base_name) {
@@ -123,6 +148,34 @@ void CNixStore::nar_from_path(RVec<unsigned char> base_name, RBox<AsyncWriteSend
 	sink.eof();
 }
 
-std::unique_ptr<CNixStore> open_nix_store() {
-	return std::make_unique<CNixStore>();
+RString CNixStore::derivation_system(RBasePathSlice base_name) {
+	auto drv_path = store_path_from_rust(base_name);
+
+	// the derivation may have been garbage collected or built on another machine
+	if (!this->store->isValidPath(drv_path)) {
+		return RString();
+	}
+	return RString(this->store->readDerivation(drv_path).platform);
+}
+
+std::unique_ptr<std::vector<std::string>> CNixStore::find_roots() {
+	auto & gc_store = nix::require<nix::GcStore>(*this->store);
+
+	std::vector<std::string> result;
+	for (auto&& [path, _links] : gc_store.findRoots(false)) {
+		result.push_back(std::string(path.to_string()));
+	}
+	return std::make_unique<std::vector<std::string>>(result);
+}
+
+std::unique_ptr<std::vector<std::string>> CNixStore::query_all_valid_paths() {
+	std::vector<std::string> result;
+	for (auto&& elem : this->store->queryAllValidPaths()) {
+		result.push_back(std::string(elem.to_string()));
+	}
+	return std::make_unique<std::vector<std::string>>(result);
+}
+
+std::unique_ptr<CNixStore> open_nix_store(RStr store_uri) {
+	return std::make_unique<CNixStore>(store_uri);
 }
@@ -33,6 +33,8 @@ limitations under the License.
 #include <nix/local-store.hh>
 #include <nix/remote-store.hh>
 #include <nix/uds-remote-store.hh>
+#include <nix/gc-store.hh>
+#include <nix/store-cast.hh>
 #include <nix/hash.hh>
 #include <nix/path.hh>
 #include <nix/serialise.hh>
@@ -66,12 +68,16 @@ public:
 	std::unique_ptr<std::vector<std::string>> sigs();
 	std::unique_ptr<std::vector<std::string>> references();
 	uint64_t nar_size();
+	RHashSlice nar_hash();
+	RString ca();
+	RString deriver();
+	bool ultimate();
 };
 
 class CNixStore {
 	std::shared_ptr<nix::Store> store;
 public:
-	CNixStore();
+	CNixStore(RStr store_uri);
 
 	RString store_dir();
 	std::unique_ptr<CPathInfo> query_path_info(RBasePathSlice base_name);
@@ -81,9 +87,12 @@ public:
 		bool include_outputs,
 		bool include_derivers);
 	void nar_from_path(RVec<unsigned char> base_name, RBox<AsyncWriteSender> sender);
+	RString derivation_system(RBasePathSlice base_name);
+	std::unique_ptr<std::vector<std::string>> find_roots();
+	std::unique_ptr<std::vector<std::string>> query_all_valid_paths();
 };
 
-std::unique_ptr<CNixStore> open_nix_store();
+std::unique_ptr<CNixStore> open_nix_store(RStr store_uri);
 
 // Relies on our definitions
 #include "nixcp/src/bindings/mod.rs.h"
@@ -0,0 +1,147 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result, ensure};
+use futures::TryStreamExt;
+use nix_compat::nixbase32;
+use object_store::{ObjectStore, path::Path};
+
+use crate::BloomArgs;
+
+/// where the filter of narinfos in the bucket lives, relative to the root of the cache
+pub const BLOOM_KEY: &str = "nixcp.bloom";
+
+const MAGIC: &[u8; 4] = b"NXBF";
+/// bits per item for about 1% false positives
+const BITS_PER_ITEM: usize = 10;
+const HASHES: u32 = 7;
+
+/// set of narinfo hashes that can say for sure that a hash isn't in it, and is wrong about
+/// one that is about 1% of the time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// an empty filter that stays at about 1% false positives for up to `items` hashes
+    pub fn with_capacity(items: usize) -> Self {
+        let words = (items.max(1) * BITS_PER_ITEM).div_ceil(64);
+        Self {
+            bits: vec![0; words],
+            hashes: HASHES,
+        }
+    }
+
+    /// `digest` is the hash part of a store path, like in `<hash>.narinfo`
+    pub fn insert(&mut self, digest: &[u8]) {
+        for bit in self.bit_indexes(digest) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// false if `digest` was never inserted
+    pub fn contains(&self, digest: &[u8]) -> bool {
+        self.bit_indexes(digest)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + self.bits.len() * 8);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&self.hashes.to_le_bytes());
+        for word in &self.bits {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        ensure!(
+            bytes.len() > 8 && bytes.starts_with(MAGIC) && (bytes.len() - 8) % 8 == 0,
+            "not a bloom filter written by nixcp"
+        );
+        let hashes = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        ensure!(hashes > 0, "bloom filter uses no hashes");
+        let bits = bytes[8..]
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        Ok(Self { bits, hashes })
+    }
+
+    /// double hashing, two independent hashes are enough to derive all of them
+    fn bit_indexes(&self, digest: &[u8]) -> impl Iterator<Item = usize> + use<> {
+        let hash = blake3::hash(digest);
+        let hash = hash.as_bytes();
+        let h1 = u64::from_le_bytes(hash[..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(hash[8..16].try_into().unwrap());
+        let len = self.bits.len() as u64 * 64;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+/// fetch the filter from the cache, `None` if there is none yet
+pub async fn load(s3: &Arc<dyn ObjectStore>) -> Result<Option<BloomFilter>> {
+    let key = Path::from(BLOOM_KEY);
+    let bytes = match s3.get(&key).await {
+        Ok(res) => res.bytes().await.context(format!("fetch {key}"))?,
+        Err(object_store::Error::NotFound { .. }) => return Ok(None),
+        Err(e) => return Err(e).context(format!("fetch {key}")),
+    };
+    BloomFilter::from_bytes(&bytes)
+        .context(format!("parse {key}"))
+        .map(Some)
+}
+
+pub async fn save(s3: &Arc<dyn ObjectStore>, filter: &BloomFilter) -> Result<()> {
+    let key = Path::from(BLOOM_KEY);
+    s3.put(&key, filter.to_bytes().into())
+        .await
+        .context(format!("put {key}"))?;
+    Ok(())
+}
+
+/// rebuild the filter from the narinfos that are in the bucket now
+pub struct Bloom {
+    s3: Arc<dyn ObjectStore>,
+}
+
+impl Bloom {
+    pub fn new(cli: &BloomArgs) -> Result<Self> {
+        Ok(Self {
+            s3: cli.bucket.build()?,
+        })
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        let digests: Vec<Vec<u8>> = self
+            .s3
+            .list(None)
+            .try_filter_map(|meta| async move {
+                // narinfos live at the root of the cache
+                let key = meta.location.to_string();
+                let digest = key
+                    .strip_suffix(".narinfo")
+                    .filter(|hash| !hash.contains('/'))
+                    .and_then(|hash| nixbase32::decode(hash.as_bytes()).ok());
+                Ok(digest)
+            })
+            .try_collect()
+            .await
+            .context("list narinfos in bucket")?;
+
+        // room for pushes to add to it until it is rebuilt again
+        let mut filter = BloomFilter::with_capacity(digests.len() * 2);
+        for digest in &digests {
+            filter.insert(digest);
+        }
+        save(&self.s3, &filter).await?;
+        println!(
+            "wrote {BLOOM_KEY} with {} narinfos ({} bytes)",
+            digests.len(),
+            filter.to_bytes().len()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+//! Push target that talks to the Cachix API instead of an s3 bucket
+
+use std::{sync::Arc, time::Instant};
+
+use anyhow::{Context, Result, anyhow};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use md5::{Digest, Md5};
+use nix_compat::{
+    narinfo::{NarInfo, SigningKey},
+    nixbase32,
+};
+use reqwest::{Method, RequestBuilder, Response, header};
+use serde::{Deserialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use tokio::io::AsyncReadExt;
+use tracing::{debug, trace};
+use url::Url;
+
+use crate::{
+    http::UpstreamClient, make_nar::MakeNar, manifest::ManifestEntry, path_info::PathInfo,
+    store::Store,
+};
+
+const DEFAULT_API: &str = "https://app.cachix.org/api/v1/";
+/// parts must be at least 5 MiB except for the last one
+const PART_SIZE: u64 = 1024 * 1024 * 8;
+
+pub struct Cachix {
+    name: String,
+    api: Url,
+    token: String,
+    /// the client of upstream checks, so that --header applies here too
+    client: Arc<UpstreamClient>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateMultipartUpload {
+    nar_id: String,
+    upload_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadPart {
+    upload_url: String,
+}
+
+impl Cachix {
+    /// `CACHIX_AUTH_TOKEN` must be set to a token with write access to the cache
+    pub fn new(name: &str, client: Arc<UpstreamClient>) -> Result<Self> {
+        let token = std::env::var("CACHIX_AUTH_TOKEN")
+            .context("CACHIX_AUTH_TOKEN must be set to push to cachix")?;
+        Ok(Self {
+            name: name.to_string(),
+            api: Url::parse(DEFAULT_API).expect("default cachix api must be a valid url"),
+            token,
+            client,
+        })
+    }
+
+    /// url of `rest` under this cache in the api
+    fn cache_url(&self, rest: &str) -> Url {
+        self.api
+            .join(&format!("cache/{}{rest}", self.name))
+            .expect("cache name should make a valid url")
+    }
+
+    async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        Ok(request
+            .bearer_auth(&self.token)
+            .send()
+            .await?
+            .error_for_status()?)
+    }
+
+    async fn send_json<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T> {
+        let res = self.send(request).await?;
+        Ok(serde_json::from_slice(&res.bytes().await?)?)
+    }
+
+    fn post_json(&self, url: Url, body: Value) -> RequestBuilder {
+        self.client
+            .request_unlimited(Method::POST, &url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+    }
+
+    /// make sure the cache exists and our token can see it
+    pub async fn preflight(&self) -> Result<()> {
+        self.send(
+            self.client
+                .request_unlimited(Method::GET, &self.cache_url("")),
+        )
+        .await
+        .context(format!("get cachix cache {}", self.name))?;
+        Ok(())
+    }
+
+    pub async fn check_if_already_exists(&self, path: &PathInfo) -> Result<bool> {
+        let hash = nixbase32::encode(path.path.digest());
+        let missing: Vec<String> = self
+            .send_json(self.post_json(self.cache_url("/narinfo"), json!([hash])))
+            .await
+            .context("query missing narinfos from cachix")?;
+        Ok(missing.is_empty())
+    }
+
+    /// compress and upload the nar in parts, then create the narinfo when completing the
+    /// upload. if `signing_key` is `None` cachix must be signing for us.
+    pub async fn upload(
+        &self,
+        path: &PathInfo,
+        store: Arc<Store>,
+        signing_key: Option<&SigningKey<ed25519_dalek::SigningKey>>,
+    ) -> Result<ManifestEntry> {
+        let start = Instant::now();
+        let mut nar = MakeNar::new(path, store)?;
+        let mut file_reader = nar.compress_and_hash()?;
+
+        let mut create_url = self.cache_url("/multipart-nar");
+        create_url.set_query(Some("compression=zstd"));
+        let upload: CreateMultipartUpload = self
+            .send_json(self.client.request_unlimited(Method::POST, &create_url))
+            .await
+            .context("create cachix multipart upload")?;
+        debug!(
+            "cachix nar id for {}: {}",
+            path.absolute_path(),
+            upload.nar_id
+        );
+
+        let mut parts = Vec::new();
+        loop {
+            let mut buf = Vec::new();
+            (&mut file_reader)
+                .take(PART_SIZE)
+                .read_to_end(&mut buf)
+                .await?;
+            if buf.is_empty() && !parts.is_empty() {
+                break;
+            }
+            let part_number = parts.len() + 1;
+            let content_md5 = BASE64_STANDARD.encode(Md5::digest(&buf));
+
+            let mut part_url = self.cache_url(&format!("/multipart-nar/{}", upload.nar_id));
+            part_url
+                .query_pairs_mut()
+                .append_pair("uploadId", &upload.upload_id)
+                .append_pair("partNumber", &part_number.to_string());
+            let part: UploadPart = self
+                .send_json(self.post_json(part_url, json!({ "contentMD5": content_md5 })))
+                .await
+                .context("get cachix upload url for part")?;
+
+            let last = (buf.len() as u64) < PART_SIZE;
+            trace!("uploading part {part_number} of {}", path.absolute_path());
+            let upload_url = Url::parse(&part.upload_url).context("parse cachix upload url")?;
+            let res = self
+                .client
+                .request_unlimited(Method::PUT, &upload_url)
+                .header("Content-MD5", content_md5)
+                .body(buf)
+                .send()
+                .await?
+                .error_for_status()?;
+            let etag = res
+                .headers()
+                .get(header::ETAG)
+                .ok_or_else(|| anyhow!("no etag in response for part {part_number}"))?
+                .to_str()?
+                .to_string();
+            parts.push(json!({ "partNumber": part_number, "eTag": etag }));
+            if last {
+                break;
+            }
+        }
+        drop(file_reader);
+
+        let compress_time = nar.compress_time;
+        // the same narinfo the s3 uploader publishes, so --no-deriver and the signature flags
+        // apply here too
+        let mut nar_info = nar.get_narinfo()?;
+        let sig = match signing_key {
+            Some(signing_key) => {
+                nar_info.add_signature(signing_key);
+                // cachix wants just the base64 signature without the key name
+                let sig = nar_info
+                    .signatures
+                    .last()
+                    .expect("signature was just added")
+                    .to_string();
+                Some(sig.split_once(':').map(|x| x.1.to_string()).unwrap_or(sig))
+            }
+            None => None,
+        };
+        let nar_info_create = narinfo_create(&nar_info, sig)?;
+
+        let mut complete_url =
+            self.cache_url(&format!("/multipart-nar/{}/complete", upload.nar_id));
+        complete_url
+            .query_pairs_mut()
+            .append_pair("uploadId", &upload.upload_id);
+        self.send(self.post_json(
+            complete_url,
+            json!({ "parts": parts, "narInfoCreate": nar_info_create }),
+        ))
+        .await
+        .context("complete cachix multipart upload")?;
+
+        Ok(ManifestEntry {
+            store_path: path.absolute_path(),
+            narinfo_url: path.narinfo_path().to_string(),
+            nar_url: None,
+            file_hash: nar_info.file_hash.as_ref().map(ManifestEntry::format_hash),
+            file_size: nar_info.file_size,
+            nar_size: nar_info.nar_size,
+            compress_secs: Some(compress_time.as_secs_f64()),
+            elapsed_secs: Some(start.elapsed().as_secs_f64()),
+        })
+    }
+}
+
+/// the narinfo as cachix wants it when completing an upload. it takes a single signature,
+/// ours, rather than all of them
+fn narinfo_create(nar_info: &NarInfo, sig: Option<String>) -> Result<Value> {
+    let file_hash: String = nar_info
+        .file_hash
+        .context("file hash must be known at this point")?
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    if nar_info.ca.is_some() {
+        // cachix has no field for it, the path is still valid without it
+        debug!(
+            "cachix doesn't store the content address of {}",
+            nar_info.store_path
+        );
+    }
+    Ok(json!({
+        "cStoreHash": nixbase32::encode(nar_info.store_path.digest()),
+        "cStoreSuffix": nar_info.store_path.name().to_string(),
+        "cNarHash": format!("sha256:{}", nixbase32::encode(&nar_info.nar_hash)),
+        "cNarSize": nar_info.nar_size,
+        "cFileHash": file_hash,
+        "cFileSize": nar_info.file_size,
+        "cReferences": nar_info.references.iter().map(|x| x.to_string()).collect::<Vec<_>>(),
+        // what cachix itself sends for paths without a deriver
+        "cDeriver": nar_info
+            .deriver
+            .as_ref()
+            .map_or("unknown-deriver".to_string(), |deriver| deriver.to_string()),
+        "cSig": sig,
+    }))
+}
@@ -0,0 +1,96 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use humansize::{DECIMAL, format_size};
+use nix_compat::{narinfo::NarInfo, nixbase32, store_path::StorePath};
+use object_store::{ObjectStore, path::Path};
+use url::Url;
+
+use crate::{
+    CheckArgs,
+    fetch::{CacheUri, parse_store_path},
+};
+
+/// report which caches can serve a path and how, without downloading any nar
+pub struct Check {
+    /// display name and cache
+    caches: Vec<(String, Arc<dyn ObjectStore>)>,
+    store_path: StorePath<String>,
+}
+
+impl Check {
+    pub fn new(cli: &CheckArgs) -> Result<Self> {
+        let mut uris = Vec::new();
+        if let Some(to) = &cli.to {
+            uris.push(CacheUri::S3(to.clone()));
+        }
+        if !cli.no_default_upstream {
+            uris.push(CacheUri::Http(
+                Url::parse("https://cache.nixos.org")
+                    .expect("default upstream must be a valid url"),
+            ));
+        }
+        uris.extend(cli.upstreams.iter().cloned());
+
+        let caches = uris
+            .iter()
+            .map(|uri| {
+                let name = match uri {
+                    CacheUri::S3(s3_uri) => format!("s3://{}", s3_uri.bucket),
+                    CacheUri::Http(url) => url.to_string(),
+                };
+                Ok((name, uri.build(cli.prefix.as_deref())?))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            caches,
+            store_path: parse_store_path(&cli.path)?,
+        })
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        println!("{}", self.store_path.to_absolute_path());
+        for (name, cache) in &self.caches {
+            // one unreachable cache shouldn't hide what the others have
+            match self.check(cache).await {
+                Ok(report) => println!("  {name}: {report}"),
+                Err(e) => println!("  {name}: error: {e:#}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// one line about the narinfo `cache` has for the path
+    async fn check(&self, cache: &Arc<dyn ObjectStore>) -> Result<String> {
+        let key = Path::from(format!(
+            "{}.narinfo",
+            nixbase32::encode(self.store_path.digest())
+        ));
+        let narinfo = match cache.get(&key).await {
+            Ok(res) => res.bytes().await.context(format!("fetch {key}"))?,
+            Err(object_store::Error::NotFound { .. }) => return Ok("missing".to_string()),
+            Err(e) => return Err(anyhow::Error::new(e).context(format!("fetch {key}"))),
+        };
+        let narinfo = std::str::from_utf8(&narinfo).context("narinfo is not utf-8")?;
+        let narinfo = NarInfo::parse(narinfo).context("parse narinfo")?;
+
+        let signers: Vec<_> = narinfo
+            .signatures
+            .iter()
+            .map(|signature| signature.name().to_string())
+            .collect();
+        Ok(format!(
+            "present, url {}, compression {}, file size {}, signed by {}",
+            narinfo.url,
+            narinfo.compression.unwrap_or("none"),
+            narinfo
+                .file_size
+                .map_or("unknown".to_string(), |size| format_size(size, DECIMAL)),
+            if signers.is_empty() {
+                "nobody".to_string()
+            } else {
+                signers.join(", ")
+            }
+        ))
+    }
+}
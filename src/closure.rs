@@ -0,0 +1,117 @@
+use std::collections::{HashMap, VecDeque};
+
+use anyhow::Result;
+use nix_compat::store_path::StorePath;
+
+use crate::{path_info::PathInfo, store::Store};
+
+/// a closure as a graph of store paths and their references, for scheduling pushes or
+/// visualizing what a path pulls in
+#[derive(Debug, Clone)]
+pub struct ClosureGraph {
+    nodes: HashMap<StorePath<String>, PathInfo>,
+    /// path -> paths in the graph that reference it
+    referrers: HashMap<StorePath<String>, Vec<StorePath<String>>>,
+}
+
+impl ClosureGraph {
+    /// the closure of `root` as known to the local store
+    pub async fn of(root: &PathInfo, store: &Store) -> Result<Self> {
+        Ok(Self::new(root.get_closure(store).await?))
+    }
+
+    /// build a graph from path infos. references to paths that are not among them and
+    /// self references are left out
+    pub fn new(paths: impl IntoIterator<Item = PathInfo>) -> Self {
+        let nodes: HashMap<_, _> = paths
+            .into_iter()
+            .map(|path| (path.path.clone(), path))
+            .collect();
+        let mut referrers: HashMap<_, Vec<_>> = HashMap::new();
+        for (path, path_info) in &nodes {
+            for reference in path_info.references.iter() {
+                if reference != path && nodes.contains_key(reference) {
+                    referrers
+                        .entry(reference.clone())
+                        .or_default()
+                        .push(path.clone());
+                }
+            }
+        }
+        Self { nodes, referrers }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn get(&self, path: &StorePath<String>) -> Option<&PathInfo> {
+        self.nodes.get(path)
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &PathInfo> {
+        self.nodes.values()
+    }
+
+    /// paths in the graph that `path` references
+    pub fn references<'a>(
+        &'a self,
+        path: &'a StorePath<String>,
+    ) -> impl Iterator<Item = &'a PathInfo> {
+        self.nodes
+            .get(path)
+            .into_iter()
+            .flat_map(|path_info| path_info.references.iter())
+            .filter(move |reference| *reference != path)
+            .filter_map(|reference| self.nodes.get(reference))
+    }
+
+    /// paths in the graph that reference `path`
+    pub fn referrers<'a>(
+        &'a self,
+        path: &StorePath<String>,
+    ) -> impl Iterator<Item = &'a PathInfo> + use<'a> {
+        self.referrers
+            .get(path)
+            .into_iter()
+            .flatten()
+            .filter_map(|referrer| self.nodes.get(referrer))
+    }
+
+    /// every path after everything it references, so a cache that is filled in this order
+    /// never has a path whose references are missing
+    pub fn topological(&self) -> Vec<&PathInfo> {
+        let mut pending_references: HashMap<&StorePath<String>, usize> = self
+            .nodes
+            .keys()
+            .map(|path| (path, self.references(path).count()))
+            .collect();
+        let mut ready: VecDeque<_> = pending_references
+            .iter()
+            .filter(|(_, count)| **count == 0)
+            .map(|(path, _)| *path)
+            .collect();
+
+        let mut order = Vec::with_capacity(self.nodes.len());
+        while let Some(path) = ready.pop_front() {
+            order.push(&self.nodes[path]);
+            for referrer in self.referrers.get(path).into_iter().flatten() {
+                let count = pending_references
+                    .get_mut(referrer)
+                    .expect("referrers are in the graph");
+                *count -= 1;
+                if *count == 0 {
+                    ready.push_back(referrer);
+                }
+            }
+        }
+        // store paths can't reference each other in a cycle, except for themselves which we
+        // skip
+        debug_assert_eq!(order.len(), self.nodes.len());
+        order
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+use zstd::stream::write::Encoder;
+
+/// a codec nars are compressed with before they are uploaded. implement it to upload with one
+/// nixcp doesn't ship, e.g. zstd with a trained dictionary, and hand it to
+/// `MakeNar::with_compression`
+pub trait Compression: fmt::Debug + Send + Sync {
+    /// what the Compression field of narinfos calls it, e.g. `zstd`. clients must know it to
+    /// decompress the nar
+    fn name(&self) -> &str;
+
+    /// appended to the file hash in nar urls, e.g. `.zst`
+    fn extension(&self) -> &str;
+
+    /// read the whole nar from `nar` and write it compressed to `out`. runs on a blocking
+    /// thread
+    fn compress(&self, nar: &mut dyn Read, out: &mut dyn Write) -> io::Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Zstd {
+    /// window log for long distance matching, disabled if `None`
+    pub window_log: Option<u32>,
+}
+
+impl Compression for Zstd {
+    fn name(&self) -> &str {
+        "zstd"
+    }
+
+    fn extension(&self) -> &str {
+        ".zst"
+    }
+
+    fn compress(&self, nar: &mut dyn Read, out: &mut dyn Write) -> io::Result<()> {
+        let mut encoder = Encoder::new(out, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+        if let Some(window_log) = self.window_log {
+            encoder.long_distance_matching(true)?;
+            encoder.window_log(window_log)?;
+        }
+        io::copy(nar, &mut encoder)?;
+        encoder.finish()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Xz {
+    /// 0 to 9
+    pub level: u32,
+}
+
+impl Default for Xz {
+    fn default() -> Self {
+        // what nix uses
+        Self { level: 6 }
+    }
+}
+
+impl Compression for Xz {
+    fn name(&self) -> &str {
+        "xz"
+    }
+
+    fn extension(&self) -> &str {
+        ".xz"
+    }
+
+    fn compress(&self, nar: &mut dyn Read, out: &mut dyn Write) -> io::Result<()> {
+        let mut encoder = liblzma::write::XzEncoder::new(out, self.level);
+        io::copy(nar, &mut encoder)?;
+        encoder.finish()?;
+        Ok(())
+    }
+}
+
+/// upload nars as they are
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoCompression;
+
+impl Compression for NoCompression {
+    fn name(&self) -> &str {
+        "none"
+    }
+
+    fn extension(&self) -> &str {
+        ""
+    }
+
+    fn compress(&self, nar: &mut dyn Read, out: &mut dyn Write) -> io::Result<()> {
+        io::copy(nar, out)?;
+        Ok(())
+    }
+}
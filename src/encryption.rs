@@ -0,0 +1,260 @@
+use std::{
+    fmt, fs,
+    io::{self, Read, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use aes_gcm::{
+    Aes256Gcm, KeyInit,
+    aead::{
+        OsRng,
+        generic_array::GenericArray,
+        rand_core::RngCore,
+        stream::{DecryptorBE32, EncryptorBE32},
+    },
+};
+use anyhow::{Context, Result, anyhow, ensure};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt};
+use tokio_util::io::StreamReader;
+
+use crate::compression::Compression;
+
+/// narinfo field that marks an encrypted nar and names the key, nix skips fields it doesn't
+/// know
+pub const NARINFO_FIELD: &str = "Encryption";
+const ALGORITHM: &str = "aes-256-gcm";
+
+const MAGIC: &[u8; 8] = b"NXCPENC1";
+/// the stream construction takes 5 bytes of the 12 byte nonce for the chunk counter
+const NONCE_PREFIX_LEN: usize = 7;
+/// plaintext per chunk, each is followed by its tag
+const CHUNK_LEN: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+
+/// a key nars are encrypted with before they leave this machine
+#[derive(Clone)]
+pub struct EncryptionKey {
+    key: [u8; 32],
+    /// recorded in narinfos to tell which key a nar needs without revealing it
+    id: String,
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EncryptionKey {
+    /// read a file holding 32 random bytes in base64, like `head -c 32 /dev/urandom | base64`
+    /// writes
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path).context(format!("read key from {path:?}"))?;
+        Self::parse(&contents).context(format!("parse key in {path:?}"))
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let key: [u8; 32] = STANDARD
+            .decode(s.trim())
+            .context("key is not base64")?
+            .try_into()
+            .map_err(|key: Vec<u8>| anyhow!("key is {} bytes, expected 32", key.len()))?;
+        let id = Sha256::digest(key)[..8]
+            .iter()
+            .map(|x| format!("{x:02x}"))
+            .collect();
+        Ok(Self { key, id })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// value of `NARINFO_FIELD` for nars encrypted with this key
+    pub fn narinfo_value(&self) -> String {
+        format!("{ALGORITHM}:{}", self.id)
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(GenericArray::from_slice(&self.key))
+    }
+}
+
+/// value of `NARINFO_FIELD` in `narinfo`, `None` for nars that aren't encrypted
+pub fn narinfo_field(narinfo: &str) -> Option<&str> {
+    narinfo.lines().find_map(|line| {
+        line.strip_prefix(NARINFO_FIELD)?
+            .strip_prefix(':')
+            .map(str::trim)
+    })
+}
+
+/// append `NARINFO_FIELD` to a narinfo rendered by nix-compat, which drops fields it doesn't
+/// know when it parses one
+pub fn with_narinfo_field(mut narinfo: String, value: Option<&str>) -> String {
+    if let Some(value) = value {
+        narinfo.push_str(&format!("{NARINFO_FIELD}: {value}\n"));
+    }
+    narinfo
+}
+
+/// compress with `inner`, then encrypt with aes-256-gcm in chunks so that neither side has to
+/// hold the whole nar. the narinfo keeps the compression of `inner`, clients decrypt first
+#[derive(Debug)]
+pub struct Encrypted {
+    inner: Arc<dyn Compression>,
+    key: EncryptionKey,
+    /// picked once so that every pass over the same nar, e.g. of --finalize-strategy
+    /// prehash, gives the same file hash. only the last pass leaves this machine
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    extension: String,
+}
+
+impl Encrypted {
+    pub fn new(inner: Arc<dyn Compression>, key: EncryptionKey) -> Self {
+        let mut nonce_prefix = [0; NONCE_PREFIX_LEN];
+        OsRng.fill_bytes(&mut nonce_prefix);
+        let extension = format!("{}.enc", inner.extension());
+        Self {
+            inner,
+            key,
+            nonce_prefix,
+            extension,
+        }
+    }
+}
+
+impl Compression for Encrypted {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn extension(&self) -> &str {
+        &self.extension
+    }
+
+    fn compress(&self, nar: &mut dyn Read, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&self.nonce_prefix)?;
+        let mut writer = EncryptingWriter {
+            out,
+            encryptor: EncryptorBE32::from_aead(
+                self.key.cipher(),
+                GenericArray::from_slice(&self.nonce_prefix),
+            ),
+            buf: Vec::with_capacity(CHUNK_LEN * 2),
+        };
+        self.inner.compress(nar, &mut writer)?;
+        writer.finish()
+    }
+}
+
+struct EncryptingWriter<'a> {
+    out: &'a mut dyn Write,
+    encryptor: EncryptorBE32<Aes256Gcm>,
+    buf: Vec<u8>,
+}
+
+impl EncryptingWriter<'_> {
+    /// the last chunk is encrypted differently so that a truncated nar doesn't decrypt
+    fn finish(self) -> io::Result<()> {
+        let chunk = self
+            .encryptor
+            .encrypt_last(self.buf.as_slice())
+            .map_err(|_| io::Error::other("encrypt nar"))?;
+        self.out.write_all(&chunk)?;
+        self.out.flush()
+    }
+}
+
+impl Write for EncryptingWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        // keep a full chunk around until we know it isn't the last one
+        while self.buf.len() > CHUNK_LEN {
+            let chunk = self
+                .encryptor
+                .encrypt_next(&self.buf[..CHUNK_LEN])
+                .map_err(|_| io::Error::other("encrypt nar"))?;
+            self.out.write_all(&chunk)?;
+            self.buf.drain(..CHUNK_LEN);
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+struct Decrypting<R> {
+    reader: R,
+    /// `None` once the last chunk was decrypted
+    decryptor: Option<DecryptorBE32<Aes256Gcm>>,
+    buf: Vec<u8>,
+}
+
+/// decrypt a nar `Encrypted` wrote, `field` is the `NARINFO_FIELD` of its narinfo
+pub async fn decrypted<R>(
+    mut reader: R,
+    field: &str,
+    key: &EncryptionKey,
+) -> Result<impl AsyncBufRead + Send + use<R>>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    ensure!(
+        field == key.narinfo_value(),
+        "nar is encrypted with {field}, but the key is {}",
+        key.narinfo_value()
+    );
+    let mut header = [0; MAGIC.len() + NONCE_PREFIX_LEN];
+    reader
+        .read_exact(&mut header)
+        .await
+        .context("read header of encrypted nar")?;
+    let (magic, nonce_prefix) = header.split_at(MAGIC.len());
+    ensure!(magic == MAGIC, "not a nar encrypted by nixcp");
+
+    let state = Decrypting {
+        reader,
+        decryptor: Some(DecryptorBE32::from_aead(
+            key.cipher(),
+            GenericArray::from_slice(nonce_prefix),
+        )),
+        buf: Vec::with_capacity(CHUNK_LEN + TAG_LEN + 1),
+    };
+    let chunks = futures::stream::try_unfold(state, |mut state| async move {
+        let Some(mut decryptor) = state.decryptor.take() else {
+            return Ok(None);
+        };
+        // a byte past a full chunk tells us that it isn't the last one
+        let want = CHUNK_LEN + TAG_LEN + 1 - state.buf.len();
+        (&mut state.reader)
+            .take(want as u64)
+            .read_to_end(&mut state.buf)
+            .await?;
+        let decrypt_failed = |_| io::Error::other("decrypt nar, wrong key or corrupt");
+        let chunk = if state.buf.len() > CHUNK_LEN + TAG_LEN {
+            let rest = state.buf.split_off(CHUNK_LEN + TAG_LEN);
+            let chunk = decryptor
+                .decrypt_next(state.buf.as_slice())
+                .map_err(decrypt_failed)?;
+            state.buf = rest;
+            state.decryptor = Some(decryptor);
+            chunk
+        } else {
+            decryptor
+                .decrypt_last(state.buf.as_slice())
+                .map_err(decrypt_failed)?
+        };
+        io::Result::Ok(Some((Bytes::from(chunk), state)))
+    });
+    Ok(StreamReader::new(Box::pin(chunks)))
+}
@@ -0,0 +1,104 @@
+use std::{
+    ffi::OsStr,
+    fs::{self, File, Permissions},
+    io::{self, BufReader},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{PermissionsExt, symlink},
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result, bail};
+use nix_compat::{
+    nar::reader::{self, Node},
+    narinfo::NarInfo,
+    store_path::StorePath,
+};
+use object_store::ObjectStore;
+use tokio::task;
+use tokio_util::io::SyncIoBridge;
+
+use crate::{
+    ExtractArgs,
+    encryption::{self, EncryptionKey},
+    fetch::{find_narinfo, nar_reader, parse_store_path},
+};
+
+pub struct Extract {
+    caches: Vec<Arc<dyn ObjectStore>>,
+    encryption_key: Option<EncryptionKey>,
+    store_path: StorePath<String>,
+    output: PathBuf,
+}
+
+impl Extract {
+    pub fn new(cli: &ExtractArgs) -> Result<Self> {
+        Ok(Self {
+            caches: cli.caches.build()?,
+            encryption_key: cli.caches.encryption_key()?,
+            store_path: parse_store_path(&cli.path)?,
+            output: cli.output.clone(),
+        })
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        if self.output.symlink_metadata().is_ok() {
+            bail!("{} already exists", self.output.display());
+        }
+        let (cache, narinfo) = find_narinfo(&self.caches, &self.store_path).await?;
+        let encryption = encryption::narinfo_field(&narinfo);
+        if let Some(field) = encryption
+            && self.encryption_key.is_none()
+        {
+            bail!("the nar is encrypted with {field}, pass --encryption-key to extract it");
+        }
+        let narinfo = NarInfo::parse(&narinfo).context("parse narinfo")?;
+        let decryption = encryption.zip(self.encryption_key.as_ref());
+        let nar = SyncIoBridge::new(nar_reader(cache, &narinfo, decryption, true).await?);
+
+        let output = self.output.clone();
+        task::spawn_blocking(move || {
+            let mut nar = BufReader::new(nar);
+            let node = reader::open(&mut nar).context("read nar")?;
+            unpack(node, &output)
+        })
+        .await
+        .unwrap()?;
+        println!(
+            "extracted {} to {}",
+            self.store_path.to_absolute_path(),
+            self.output.display()
+        );
+        Ok(())
+    }
+}
+
+/// write `node` and everything below it to `path`
+fn unpack(node: Node<'_, '_>, path: &Path) -> Result<()> {
+    match node {
+        Node::Symlink { target } => {
+            symlink(OsStr::from_bytes(&target), path)
+                .context(format!("create symlink {}", path.display()))?;
+        }
+        Node::File {
+            executable,
+            mut reader,
+        } => {
+            let mut file = File::create(path).context(format!("create {}", path.display()))?;
+            io::copy(&mut reader, &mut file).context(format!("write {}", path.display()))?;
+            if executable {
+                fs::set_permissions(path, Permissions::from_mode(0o755))?;
+            }
+        }
+        Node::Directory(mut dir) => {
+            fs::create_dir(path).context(format!("create directory {}", path.display()))?;
+            // the nar reader rejects names that could escape the directory
+            while let Some(entry) = dir.next().context("read nar")? {
+                unpack(entry.node, &path.join(OsStr::from_bytes(entry.name)))?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,217 @@
+use std::{fs, io, path::PathBuf, pin::Pin, str::FromStr, sync::Arc};
+
+use anyhow::{Context, Result, bail};
+use async_compression::tokio::bufread::{XzDecoder, ZstdDecoder};
+use futures::TryStreamExt;
+use nix_compat::{narinfo::NarInfo, nixbase32, store_path::StorePath};
+use object_store::{ClientOptions, ObjectStore, http::HttpBuilder, path::Path};
+use reqwest::header::HeaderValue;
+use tokio::io::{AsyncBufRead, AsyncRead, BufReader};
+use tokio_util::io::StreamReader;
+use tracing::debug;
+use url::Url;
+
+use crate::{
+    CacheArgs, FetchArgs,
+    encryption::{self, EncryptionKey},
+    http::USER_AGENT,
+    s3_uri::S3Uri,
+};
+
+/// a binary cache to read from, either a nix style s3 store uri or a http(s) cache url
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheUri {
+    S3(S3Uri),
+    Http(Url),
+}
+
+impl FromStr for CacheUri {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.starts_with("s3://") {
+            return Ok(Self::S3(s.parse()?));
+        }
+        let url = Url::parse(s).context(format!("failed to parse {s} as url"))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            bail!("expected an s3:// or http(s):// uri, got {s}");
+        }
+        Ok(Self::Http(url))
+    }
+}
+
+impl CacheUri {
+    /// `prefix` only applies to s3, a http cache carries it in its url
+    pub fn build(&self, prefix: Option<&str>) -> Result<Arc<dyn ObjectStore>> {
+        match self {
+            Self::S3(s3_uri) => s3_uri.build(prefix),
+            Self::Http(url) => Ok(Arc::new(
+                HttpBuilder::new()
+                    .with_url(url.as_str())
+                    .with_client_options(
+                        ClientOptions::new().with_user_agent(HeaderValue::from_static(USER_AGENT)),
+                    )
+                    .build()?,
+            )),
+        }
+    }
+}
+
+pub struct Fetch {
+    caches: Vec<Arc<dyn ObjectStore>>,
+    encryption_key: Option<EncryptionKey>,
+    store_path: StorePath<String>,
+    output: PathBuf,
+    // write the narinfo instead of the nar
+    narinfo: bool,
+    decompress: bool,
+}
+
+impl Fetch {
+    pub fn new(cli: &FetchArgs) -> Result<Self> {
+        Ok(Self {
+            caches: cli.caches.build()?,
+            encryption_key: cli.caches.encryption_key()?,
+            store_path: parse_store_path(&cli.path)?,
+            output: cli.output.clone(),
+            narinfo: cli.narinfo,
+            decompress: cli.decompress,
+        })
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        let (cache, narinfo) = find_narinfo(&self.caches, &self.store_path).await?;
+        if self.narinfo {
+            tokio::fs::write(&self.output, &narinfo)
+                .await
+                .context(format!("write {:?}", self.output))?;
+            return Ok(());
+        }
+
+        let encryption = encryption::narinfo_field(&narinfo);
+        if let Some(field) = encryption
+            && self.decompress
+            && self.encryption_key.is_none()
+        {
+            bail!("the nar is encrypted with {field}, pass --encryption-key to decompress it");
+        }
+        let narinfo = NarInfo::parse(&narinfo).context("parse narinfo")?;
+        let decryption = encryption.zip(self.encryption_key.as_ref());
+        let mut nar = nar_reader(cache, &narinfo, decryption, self.decompress).await?;
+        let mut file = tokio::fs::File::create(&self.output)
+            .await
+            .context(format!("create {:?}", self.output))?;
+        let written = tokio::io::copy(&mut nar, &mut file)
+            .await
+            .context(format!("fetch {}", narinfo.url))?;
+        println!("wrote {written} bytes to {}", self.output.display());
+        Ok(())
+    }
+}
+
+impl CacheArgs {
+    pub fn build(&self) -> Result<Vec<Arc<dyn ObjectStore>>> {
+        self.caches
+            .iter()
+            .map(|cache| cache.build(self.prefix.as_deref()))
+            .collect()
+    }
+
+    pub fn encryption_key(&self) -> Result<Option<EncryptionKey>> {
+        self.encryption_key
+            .as_deref()
+            .map(EncryptionKey::read)
+            .transpose()
+    }
+}
+
+/// parse an absolute store path, resolving symlinks like ./result if they exist locally
+pub(crate) fn parse_store_path(path: &str) -> Result<StorePath<String>> {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+    StorePath::from_absolute_path(path.as_os_str().as_encoded_bytes())
+        .context(format!("{path:?} is not a store path"))
+}
+
+/// the narinfo for `store_path` from the first cache that has it
+pub(crate) async fn find_narinfo<'a>(
+    caches: &'a [Arc<dyn ObjectStore>],
+    store_path: &StorePath<String>,
+) -> Result<(&'a Arc<dyn ObjectStore>, String)> {
+    let key = Path::from(format!(
+        "{}.narinfo",
+        nixbase32::encode(store_path.digest())
+    ));
+    for cache in caches {
+        debug!("querying {cache} for {key}");
+        match cache.get(&key).await {
+            Ok(res) => {
+                let narinfo = res.bytes().await.context(format!("fetch {key}"))?;
+                let narinfo =
+                    String::from_utf8(narinfo.to_vec()).context("narinfo is not utf-8")?;
+                return Ok((cache, narinfo));
+            }
+            Err(object_store::Error::NotFound { .. }) => continue,
+            Err(e) => return Err(anyhow::Error::new(e).context(format!("fetch {key}"))),
+        }
+    }
+    bail!("{} is not in any cache", store_path.to_absolute_path())
+}
+
+/// stream the nar of `narinfo` from `cache`, decrypting it if `decryption` holds the
+/// encryption field of the narinfo and a key
+pub(crate) async fn nar_reader(
+    cache: &Arc<dyn ObjectStore>,
+    narinfo: &NarInfo<'_>,
+    decryption: Option<(&str, &EncryptionKey)>,
+    decompress: bool,
+) -> Result<Pin<Box<dyn AsyncRead + Send>>> {
+    let stream = cache
+        .get(&Path::from(narinfo.url))
+        .await
+        .context(format!("fetch {}", narinfo.url))?
+        .into_stream()
+        .map_err(io::Error::other);
+    decoded(
+        BufReader::new(StreamReader::new(stream)),
+        narinfo,
+        decryption,
+        decompress,
+    )
+    .await
+}
+
+/// decrypt and decompress a nar as it was read from a cache, like `nar_reader` does
+pub(crate) async fn decoded<'a>(
+    reader: impl AsyncBufRead + Unpin + Send + 'a,
+    narinfo: &NarInfo<'_>,
+    decryption: Option<(&str, &EncryptionKey)>,
+    decompress: bool,
+) -> Result<Pin<Box<dyn AsyncRead + Send + 'a>>> {
+    let reader: Pin<Box<dyn AsyncBufRead + Send + 'a>> = match decryption {
+        Some((field, key)) => Box::pin(
+            encryption::decrypted(reader, field, key)
+                .await
+                .context(format!("decrypt {}", narinfo.url))?,
+        ),
+        None => Box::pin(reader),
+    };
+    if decompress {
+        decompressed(reader, narinfo.compression)
+    } else {
+        Ok(Box::pin(reader))
+    }
+}
+
+/// decompress a nar with the compression named in its narinfo
+pub(crate) fn decompressed<'a>(
+    reader: impl AsyncBufRead + Send + 'a,
+    compression: Option<&str>,
+) -> Result<Pin<Box<dyn AsyncRead + Send + 'a>>> {
+    let reader: Pin<Box<dyn AsyncRead + Send + 'a>> = match compression {
+        Some("zstd") => Box::pin(ZstdDecoder::new(reader)),
+        Some("xz") => Box::pin(XzDecoder::new(reader)),
+        Some("none") | None => Box::pin(reader),
+        Some(compression) => bail!("unsupported compression {compression}"),
+    };
+    Ok(reader)
+}
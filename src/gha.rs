@@ -0,0 +1,47 @@
+//! GitHub Actions workflow commands
+//! https://docs.github.com/en/actions/writing-workflows/choosing-what-your-workflow-does/workflow-commands-for-github-actions
+
+use std::{env, fs::OpenOptions, io::Write};
+
+use anyhow::{Context, Result};
+
+/// start a collapsible group in the log
+pub fn group(title: &str) {
+    println!("::group::{title}");
+}
+
+pub fn end_group() {
+    println!("::endgroup::");
+}
+
+pub fn notice(message: &str) {
+    println!("::notice::{message}");
+}
+
+pub fn error(message: &str) {
+    // annotations end at the first newline unless it's escaped
+    println!("::error::{}", message.replace('\n', "%0A"));
+}
+
+/// append markdown to the summary shown on the run page
+pub fn append_step_summary(markdown: &str) -> Result<()> {
+    append_to_env_file("GITHUB_STEP_SUMMARY", markdown)
+}
+
+/// set a step output that later steps can use as `steps.<id>.outputs.<name>`
+pub fn set_output(name: &str, value: &str) -> Result<()> {
+    append_to_env_file("GITHUB_OUTPUT", &format!("{name}={value}\n"))
+}
+
+/// does nothing when not running in actions
+fn append_to_env_file(var: &str, contents: &str) -> Result<()> {
+    let Some(path) = env::var_os(var) else {
+        return Ok(());
+    };
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .context(format!("write to {var}"))
+}
@@ -0,0 +1,158 @@
+use std::{collections::HashMap, str::FromStr, time::Duration};
+
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, Utc};
+use reqwest::{
+    Client, Method, RequestBuilder, Response, StatusCode,
+    header::{self, HeaderName, HeaderValue},
+};
+use tracing::warn;
+use url::Url;
+
+use crate::{rate_limit::RateLimiter, upstream_stats::UpstreamStats};
+
+/// sent with every request so that cache operators can tell who is talking to them
+pub const USER_AGENT: &str = concat!("nixcp/", env!("CARGO_PKG_VERSION"));
+
+/// How many times a request an upstream throttled is sent
+const THROTTLE_ATTEMPTS: u32 = 5;
+
+/// Longest we wait for a throttled request, whatever Retry-After says
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// an extra header to send to one upstream host, or to all of them if `host` is unset.
+/// parsed from `[host=]Name: value`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamHeader {
+    pub host: Option<String>,
+    pub name: HeaderName,
+    pub value: HeaderValue,
+}
+
+impl FromStr for UpstreamHeader {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, value) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("expected a header like 'X-Auth: value', got {s}"))?;
+        // header names can't contain '=' so anything before it is the host
+        let (host, name) = match name.split_once('=') {
+            Some((host, name)) => (Some(host.trim().to_string()), name),
+            None => (None, name),
+        };
+        Ok(Self {
+            host,
+            name: HeaderName::from_str(name.trim())
+                .context(format!("invalid header name in {s}"))?,
+            value: HeaderValue::from_str(value.trim())
+                .context(format!("invalid header value in {s}"))?,
+        })
+    }
+}
+
+/// http client for upstream caches that applies rate limits and extra headers
+pub struct UpstreamClient {
+    client: Client,
+    rate_limiter: RateLimiter,
+    headers: Vec<UpstreamHeader>,
+    // host -> how long requests to it may take
+    timeouts: HashMap<String, Duration>,
+    stats: UpstreamStats,
+}
+
+impl UpstreamClient {
+    pub fn new(rate_limiter: RateLimiter, headers: Vec<UpstreamHeader>) -> Result<Self> {
+        Ok(Self {
+            client: Client::builder()
+                .user_agent(USER_AGENT)
+                .build()
+                .context("build http client")?,
+            rate_limiter,
+            headers,
+            timeouts: HashMap::new(),
+            stats: UpstreamStats::default(),
+        })
+    }
+
+    /// give up on requests to a host after its timeout
+    pub fn with_timeouts(mut self, timeouts: impl IntoIterator<Item = (String, Duration)>) -> Self {
+        self.timeouts.extend(timeouts);
+        self
+    }
+
+    /// how upstream checks went so far
+    pub fn stats(&self) -> &UpstreamStats {
+        &self.stats
+    }
+
+    /// a request to `url` with the headers for its host, once the rate limit allows it
+    pub async fn request(&self, method: Method, url: &Url) -> RequestBuilder {
+        self.rate_limiter.acquire(url).await;
+        self.request_unlimited(method, url)
+    }
+
+    /// a request to `url` with the headers for its host, for requests that aren't upstream
+    /// checks and so don't count against --upstream-rps
+    pub fn request_unlimited(&self, method: Method, url: &Url) -> RequestBuilder {
+        let mut request = self.client.request(method, url.as_str());
+        if let Some(timeout) = url.host_str().and_then(|host| self.timeouts.get(host)) {
+            request = request.timeout(*timeout);
+        }
+        for header in &self.headers {
+            if header.host.is_none() || header.host.as_deref() == url.host_str() {
+                request = request.header(&header.name, &header.value);
+            }
+        }
+        request
+    }
+
+    /// send `request`, waiting and sending it again when the upstream throttles it. a response
+    /// that is still throttled after `THROTTLE_ATTEMPTS` is returned as is
+    pub async fn send(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        let mut attempt = 1;
+        loop {
+            let res = request
+                .try_clone()
+                .expect("requests without a body can be cloned")
+                .send()
+                .await?;
+            let retry_after = res
+                .headers()
+                .get(header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| parse_retry_after(value, Utc::now()));
+            let delay = match (res.status(), retry_after) {
+                (StatusCode::TOO_MANY_REQUESTS, retry_after) => {
+                    retry_after.unwrap_or(Duration::from_secs(1 << attempt))
+                }
+                // unlike a 429 a 503 may just be an outage, only wait if asked to
+                (StatusCode::SERVICE_UNAVAILABLE, Some(retry_after)) => retry_after,
+                _ => return Ok(res),
+            };
+            self.stats.record_throttled();
+            if attempt >= THROTTLE_ATTEMPTS {
+                return Ok(res);
+            }
+            let delay = delay.min(MAX_RETRY_AFTER);
+            warn!("{} throttled us, retrying in {delay:?}", res.url());
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// how long a Retry-After header asks us to wait, given as seconds or as an http date
+pub fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    // a date in the past means right away
+    Some(
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
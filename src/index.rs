@@ -0,0 +1,90 @@
+use std::{
+    collections::HashSet,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use blake3::Hash;
+
+use crate::path_info::PathInfo;
+
+/// local record of what has been pushed where, so that unchanged paths can be skipped without
+/// asking the destination. it is trusted blindly, delete the file if the cache was wiped
+pub struct PushIndex {
+    file: PathBuf,
+    /// identifies the cache, so one index can serve several
+    destination: String,
+    entries: HashSet<Hash>,
+    /// pushed during this run and not written yet
+    pending: Mutex<Vec<Hash>>,
+}
+
+impl PushIndex {
+    /// load the index from `file`, which holds one hex blake3 hash per line
+    pub fn open(file: &Path, destination: String) -> Result<Self> {
+        let contents = match fs::read_to_string(file) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(anyhow::Error::new(e).context(format!("read index {file:?}"))),
+        };
+        // a torn last line from an interrupted write just costs us one lookup
+        let entries = contents
+            .lines()
+            .filter_map(|line| Hash::from_hex(line.trim()).ok())
+            .collect();
+        Ok(Self {
+            file: file.to_path_buf(),
+            destination,
+            entries,
+            pending: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// fingerprint of `path` in this destination. the nar hash makes sure a path whose
+    /// contents changed, e.g. after a store repair, is pushed again
+    pub fn key(&self, path: &PathInfo) -> Hash {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(self.destination.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(path.absolute_path().as_bytes());
+        hasher.update(b"\0");
+        hasher.update(&path.nar_hash);
+        hasher.finalize()
+    }
+
+    pub fn contains(&self, path: &PathInfo) -> bool {
+        self.entries.contains(&self.key(path))
+    }
+
+    /// remember that the path with `key` is in the destination
+    pub fn insert(&self, key: Hash) {
+        self.pending.lock().unwrap().push(key);
+    }
+
+    /// append everything inserted during this run to the file
+    pub fn save(&self) -> Result<()> {
+        let pending = self.pending.lock().unwrap();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        if let Some(parent) = self.file.parent() {
+            fs::create_dir_all(parent).context(format!("create {parent:?}"))?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.file)
+            .context(format!("open index {:?}", self.file))?;
+        let mut lines = String::new();
+        for key in pending.iter().filter(|key| !self.entries.contains(*key)) {
+            lines.push_str(&key.to_hex());
+            lines.push('\n');
+        }
+        file.write_all(lines.as_bytes())
+            .context(format!("write index {:?}", self.file))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,60 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use nix_compat::{nixbase32, store_path::StorePath};
+
+/// digests of paths a push has finished, written as it goes so that a push that crashed can
+/// pick up where it stopped
+pub struct Journal {
+    path: PathBuf,
+    /// finished by an earlier attempt
+    done: HashSet<String>,
+    file: Mutex<File>,
+}
+
+impl Journal {
+    /// open `path` for appending, reading what earlier attempts finished if it exists
+    pub fn open(path: &Path) -> Result<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(anyhow::Error::new(e).context(format!("read journal {path:?}"))),
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context(format!("open journal {path:?}"))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            done: contents
+                .lines()
+                .map(|line| line.trim().to_string())
+                .collect(),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// whether an earlier attempt finished `path`
+    pub fn contains(&self, path: &StorePath<String>) -> bool {
+        self.done.contains(&nixbase32::encode(path.digest()))
+    }
+
+    /// note that `path` is in the cache. written right away so it survives a crash
+    pub fn record(&self, path: &StorePath<String>) -> Result<()> {
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", nixbase32::encode(path.digest()))
+            .context(format!("write journal {:?}", self.path))
+    }
+
+    /// delete the journal once the push it belongs to has finished
+    pub fn remove(&self) -> Result<()> {
+        fs::remove_file(&self.path).context(format!("remove journal {:?}", self.path))
+    }
+}
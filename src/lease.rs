@@ -0,0 +1,153 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use nix_compat::nixbase32;
+use object_store::{ObjectStore, PutMode, PutOptions, UpdateVersion, path::Path};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+use ulid::Ulid;
+
+use crate::path_info::PathInfo;
+
+/// how often a lease held by another runner is checked
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// a small object in the bucket that tells other runners we are uploading a path. it is
+/// rewritten while held so that it doesn't expire during long uploads
+pub struct Lease {
+    s3: Arc<dyn ObjectStore>,
+    path: Path,
+    renewal: JoinHandle<()>,
+}
+
+/// outcome of a single attempt to claim a lease
+pub enum Claim {
+    Claimed(Lease),
+    /// another runner is uploading the path
+    Held,
+    /// the path is already in the bucket
+    Uploaded,
+}
+
+impl Lease {
+    /// claim the lease for `path_info`, waiting while another runner holds it. returns `None`
+    /// if the path showed up in the bucket while we waited
+    pub async fn claim(
+        s3: &Arc<dyn ObjectStore>,
+        path_info: &PathInfo,
+        ttl: Duration,
+    ) -> Result<Option<Self>> {
+        let mut waiting = false;
+        loop {
+            match Self::try_claim(s3, path_info, ttl).await? {
+                Claim::Claimed(lease) => return Ok(Some(lease)),
+                Claim::Uploaded => return Ok(None),
+                Claim::Held => {}
+            }
+            if !waiting {
+                println!(
+                    "waiting for another runner to upload {}",
+                    path_info.absolute_path()
+                );
+                waiting = true;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// claim the lease for `path_info` without waiting. a lease not renewed for `ttl` is assumed
+    /// to belong to a runner that died and is taken over
+    pub async fn try_claim(
+        s3: &Arc<dyn ObjectStore>,
+        path_info: &PathInfo,
+        ttl: Duration,
+    ) -> Result<Claim> {
+        let path = Path::parse(format!(
+            "leases/{}.lease",
+            nixbase32::encode(path_info.path.digest())
+        ))?;
+        let max_age = chrono::Duration::from_std(ttl).context("lease ttl is too large")?;
+        // only succeeds if nobody else holds the lease
+        let mut mode = PutMode::Create;
+        loop {
+            let opts = PutOptions {
+                mode: mode.clone(),
+                ..Default::default()
+            };
+            match s3
+                .put_opts(&path, format!("nixcp {}", Ulid::new()).into(), opts)
+                .await
+            {
+                Ok(_) => {
+                    debug!("claimed {path}");
+                    let renewal = tokio::spawn(renew(s3.clone(), path.clone(), ttl));
+                    return Ok(Claim::Claimed(Self {
+                        s3: s3.clone(),
+                        path,
+                        renewal,
+                    }));
+                }
+                Err(object_store::Error::AlreadyExists { .. })
+                | Err(object_store::Error::Precondition { .. })
+                | Err(object_store::Error::NotFound { .. }) => {}
+                Err(e) => return Err(anyhow::Error::new(e).context(format!("claim {path}"))),
+            }
+            // another runner renewed, took over or released the lease since we looked, just
+            // try again later
+            if matches!(mode, PutMode::Update(_)) {
+                return Ok(Claim::Held);
+            }
+
+            if path_info.check_if_already_exists(s3.as_ref()).await? {
+                return Ok(Claim::Uploaded);
+            }
+            match s3.head(&path).await {
+                // only replace the lease we saw, so that two runners can't both take it over
+                Ok(meta) if Utc::now() - meta.last_modified > max_age => {
+                    warn!(
+                        "lease for {} expired, taking over",
+                        path_info.absolute_path()
+                    );
+                    mode = PutMode::Update(UpdateVersion {
+                        e_tag: meta.e_tag,
+                        version: meta.version,
+                    });
+                }
+                Ok(_) => return Ok(Claim::Held),
+                // released while we looked
+                Err(object_store::Error::NotFound { .. }) => {}
+                Err(e) => return Err(anyhow::Error::new(e).context(format!("check {path}"))),
+            }
+        }
+    }
+
+    /// let other runners know we are done. a lease that can't be deleted expires eventually
+    pub async fn release(self) {
+        self.renewal.abort();
+        if let Err(e) = self.s3.delete(&self.path).await {
+            warn!("failed to release {}: {e}", self.path);
+        }
+    }
+}
+
+impl Drop for Lease {
+    // a lease dropped without being released, e.g. by a failed upload, is left to expire
+    fn drop(&mut self) {
+        self.renewal.abort();
+    }
+}
+
+/// rewrite the lease at `path` well before it is `ttl` old, until aborted
+async fn renew(s3: Arc<dyn ObjectStore>, path: Path, ttl: Duration) {
+    let mut interval = tokio::time::interval((ttl / 3).max(Duration::from_secs(1)));
+    // the first tick completes right away
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match s3.put(&path, format!("nixcp {}", Ulid::new()).into()).await {
+            Ok(_) => debug!("renewed {path}"),
+            Err(e) => warn!("failed to renew {path}: {e}"),
+        }
+    }
+}
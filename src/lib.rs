@@ -1,13 +1,50 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 
+use crate::{
+    fetch::CacheUri,
+    http::UpstreamHeader,
+    path_info::DEFAULT_CLOSURE_CONCURRENCY,
+    push::UpstreamErrorPolicy,
+    s3_uri::S3Uri,
+    uploader::{FinalizeStrategy, NarUrlTemplate},
+    watch::UploadWindow,
+};
+
+pub mod bench;
 mod bindings;
+pub mod bloom;
+mod cachix;
+pub mod check;
+pub mod closure;
+pub mod compression;
+pub mod encryption;
+pub mod extract;
+pub mod fetch;
+mod gha;
+pub mod http;
+pub mod index;
+pub mod journal;
+mod lease;
 pub mod make_nar;
+pub mod manifest;
 pub mod path_info;
+pub mod prune;
 pub mod push;
+pub mod rate_limit;
+pub mod release;
+pub mod replicate;
+pub mod request_stats;
+pub mod s3_uri;
 pub mod store;
 mod uploader;
+pub mod upstream_config;
+pub mod upstream_copy;
+pub mod upstream_stats;
+pub mod verify;
+pub mod versions;
+pub mod watch;
 
 #[derive(Parser, Debug)]
 #[command(version)]
@@ -21,29 +58,106 @@ pub struct Cli {
     /// Whether to enable tokio-console
     #[arg(long)]
     pub tokio_console: bool,
+
+    /// Format of log lines
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line with the timestamp, level, fields and spans, for log
+    /// aggregators
+    Json,
 }
 
 #[derive(Debug, Subcommand)]
 pub enum Commands {
     #[command(arg_required_else_help = true)]
     Push(PushArgs),
+
+    /// Delete paths from the bucket that haven't been used recently
+    #[command(arg_required_else_help = true)]
+    Prune(PruneArgs),
+
+    /// Check that every path in the bucket has an intact NAR
+    #[command(arg_required_else_help = true)]
+    Verify(VerifyArgs),
+
+    /// Write a bloom filter of the narinfos in the bucket for push --bloom
+    #[command(arg_required_else_help = true)]
+    Bloom(BloomArgs),
+
+    /// Download the NAR or narinfo of a store path from a cache
+    #[command(arg_required_else_help = true)]
+    Fetch(FetchArgs),
+
+    /// Unpack a store path from a cache into a directory without needing nix
+    #[command(arg_required_else_help = true)]
+    Extract(ExtractArgs),
+
+    /// Show which caches have a store path, with its compression, size and signatures
+    #[command(arg_required_else_help = true)]
+    Check(CheckArgs),
+
+    /// Copy paths that are missing from one bucket into another
+    #[command(arg_required_else_help = true)]
+    Replicate(ReplicateArgs),
+
+    /// Push every new generation of a profile as it appears
+    #[command(arg_required_else_help = true)]
+    Watch(WatchArgs),
+
+    /// Measure upload throughput and latency to a bucket with throwaway objects
+    #[command(arg_required_else_help = true)]
+    Bench(BenchArgs),
 }
 
-#[derive(Debug, Args)]
+#[derive(Debug, Clone, Args)]
 pub struct PushArgs {
     /// The s3 bucket to upload to
-    #[arg(long, value_name = "bucket name")]
-    bucket: String,
+    #[arg(long, value_name = "bucket name", required_unless_present_any = ["cachix", "to"])]
+    bucket: Option<String>,
+
+    /// Nix style s3 store uri to upload to. --region and --endpoint take precedence over
+    /// its parameters.
+    /// e.g. s3://bucket?region=eu-west-1&endpoint=minio.local&scheme=https&profile=ci
+    #[arg(long, value_name = "s3 uri", conflicts_with = "bucket")]
+    to: Option<S3Uri>,
+
+    /// Push to this Cachix cache instead of an s3 bucket.
+    /// CACHIX_AUTH_TOKEN must be set
+    #[arg(long, value_name = "cache name", conflicts_with_all = ["bucket", "to", "copy_from_upstream"])]
+    cachix: Option<String>,
 
     /// Upstream cache to check against. Can be specified multiple times.
-    /// cache.nixos.org is always included.
+    /// cache.nixos.org is always included. s3:// uris are checked with the bucket's
+    /// credentials and file:// uris on the local filesystem, for caches that aren't
+    /// served over http.
     #[arg(long = "upstream", short, value_name = "nixcache.example.com")]
     upstreams: Vec<String>,
 
+    /// JSON file of upstreams with their own timeout, headers and rate limit. --upstream and
+    /// the other upstream flags add to it
+    #[arg(long, value_name = "FILE")]
+    upstreams_file: Option<PathBuf>,
+
     /// Path to the file containing signing key
     /// e.g. ~/cache-priv-key.pem
+    #[arg(long, required_unless_present = "signing_key_credential")]
+    signing_key: Option<PathBuf>,
+
+    /// Name of a systemd credential (LoadCredential=) containing the signing key, read from
+    /// $CREDENTIALS_DIRECTORY
+    #[arg(long, value_name = "NAME", conflicts_with = "signing_key")]
+    signing_key_credential: Option<String>,
+
+    /// Store all objects under this prefix within the bucket
+    /// e.g. team-a/
     #[arg(long)]
-    signing_key: String,
+    prefix: Option<String>,
 
     /// If unspecified, will get it form AWS_DEFAULT_REGION envar or default to us-east-1
     #[arg(long)]
@@ -54,12 +168,562 @@ pub struct PushArgs {
     #[arg(long)]
     endpoint: Option<String>,
 
+    /// Put the bucket in the request path instead of the host name, as MinIO and Garage
+    /// usually need. Always done for endpoints on localhost or an ip address
+    #[arg(long)]
+    s3_path_style: bool,
+
+    /// Allow an http:// endpoint. Credentials and cache contents are sent unencrypted, so
+    /// only use this for local testing
+    #[arg(long)]
+    allow_http: bool,
+
+    /// STS endpoint to assume AWS_ROLE_ARN at with AWS_WEB_IDENTITY_TOKEN_FILE, for builders
+    /// that can't reach the default one. If unspecified, will get it from AWS_ENDPOINT_URL_STS
+    /// envar or use the regional endpoint of the partition of the region e.g. China
+    #[arg(long, value_name = "URL")]
+    sts_endpoint: Option<String>,
+
     /// Do not include cache.nixos.org as upstream
     #[arg(long)]
     no_default_upstream: bool,
 
-    /// Path to upload
+    /// Skip paths this cache already has, checked after the destination. Unlike --upstream
+    /// it is only asked, never copied from. Can be specified multiple times, they are
+    /// checked in order, e.g. the old bucket when migrating to a new one
+    #[arg(long = "also-check", value_name = "s3 uri")]
+    also_check: Vec<S3Uri>,
+
+    /// What to do when an upstream check fails with a network error
+    #[arg(long, value_enum, default_value_t = UpstreamErrorPolicy::Miss)]
+    upstream_error_policy: UpstreamErrorPolicy,
+
+    /// Limit checks against each upstream to this many requests per second
+    #[arg(long, value_name = "RPS")]
+    upstream_rps: Option<f64>,
+
+    /// Number of requests an upstream may get at once before --upstream-rps kicks in
+    #[arg(long, default_value_t = 10, requires = "upstream_rps")]
+    upstream_burst: u32,
+
+    /// Extra header to send to upstreams, e.g. 'X-Auth: secret'. Prefix it with a host like
+    /// 'nixcache.example.com=X-Auth: secret' to only send it to that upstream.
+    /// Can be specified multiple times.
+    #[arg(long = "header", value_name = "[HOST=]NAME: VALUE")]
+    headers: Vec<UpstreamHeader>,
+
+    /// Number of parts of a single large NAR to upload concurrently
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(usize).range(1..))]
+    part_concurrency: usize,
+
+    /// NARs up to this size are compressed in memory and uploaded with a single request
+    #[arg(long, value_name = "BYTES", default_value_t = 1024 * 1024)]
+    direct_put_threshold: u64,
+
+    /// Larger NARs up to this size are compressed to a file in --spill-dir and uploaded
+    /// straight to their final path, saving the rename or second compression pass of
+    /// --finalize-strategy at the cost of local disk space
+    #[arg(long, value_name = "BYTES")]
+    spill_threshold: Option<u64>,
+
+    /// Where --spill-threshold puts compressed NARs. Defaults to $TMPDIR or /tmp
+    #[arg(long, value_name = "DIR", requires = "spill_threshold")]
+    spill_dir: Option<PathBuf>,
+
+    /// Enable zstd long distance matching with this window log (2^N byte window).
+    /// Improves compression of huge outputs at the cost of memory while compressing.
+    /// Capped at 27 so that clients can decompress without raising their window limit.
+    #[arg(long, value_name = "WINDOW_LOG", value_parser = clap::value_parser!(u32).range(10..=27))]
+    zstd_long: Option<u32>,
+
+    /// Upload NARs uncompressed when compressing their first 4 MiB only shrinks it to at
+    /// least this fraction of the original size e.g. 0.95
+    #[arg(long, value_name = "RATIO")]
+    incompressible_threshold: Option<f64>,
+
+    /// Encrypt NARs after compressing them with the key in this file, 32 random bytes in
+    /// base64. Nix can't substitute encrypted NARs, fetch and extract them with nixcp
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["cachix", "copy_from_upstream"])]
+    encryption_key: Option<PathBuf>,
+
+    /// How NARs are moved to their final path once their hash is known.
+    /// auto picks prehash for endpoints without a native move e.g. R2 and GCS
+    #[arg(long, value_enum, default_value_t = FinalizeStrategy::Auto)]
+    finalize_strategy: FinalizeStrategy,
+
+    /// Where NARs are uploaded, to match the layout of a cache another tool created.
+    /// {filehash} is the nixbase32 hash of the compressed NAR and {ext} is .zst or empty for
+    /// uncompressed NARs
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        default_value = "nar/{filehash}.nar{ext}"
+    )]
+    nar_url_template: NarUrlTemplate,
+
+    /// Stop queueing new uploads once this many bytes (uncompressed NAR size) are queued
+    #[arg(long, value_name = "BYTES")]
+    max_upload_bytes: Option<u64>,
+
+    /// Stop queueing new uploads once this many paths are queued
+    #[arg(long, value_name = "COUNT")]
+    max_upload_count: Option<usize>,
+
+    /// Fail requests to the bucket once this many were sent, as a safety valve against a
+    /// push that would run up the request bill
+    #[arg(
+        long,
+        value_name = "COUNT",
+        conflicts_with = "cachix",
+        value_parser = clap::value_parser!(usize).range(1..)
+    )]
+    max_requests: Option<usize>,
+
+    /// Don't start uploads that would make all active uploads use more than this much memory
+    /// for buffers and compression e.g. 512MiB. A single upload may exceed it on its own.
+    #[arg(long, value_name = "SIZE", value_parser = push::parse_size)]
+    max_memory: Option<u64>,
+
+    /// Don't start uploads that would make all active uploads hold more than this many file
+    /// descriptors. Defaults to the open files limit minus some for upstream checks and the
+    /// store connection
+    #[arg(long, value_name = "COUNT")]
+    max_open_files: Option<u32>,
+
+    /// Paths that passed the upstream checks and may wait for an upload to start. Once that
+    /// many wait, checking pauses, so raise it to check further ahead while uploads are slow
+    #[arg(long, value_name = "COUNT", default_value_t = 10)]
+    queue_depth: usize,
+
+    /// Number of path info queries to run at once while computing closures
+    #[arg(
+        long,
+        value_name = "COUNT",
+        default_value_t = DEFAULT_CLOSURE_CONCURRENCY,
+        value_parser = clap::value_parser!(usize).range(1..)
+    )]
+    closure_concurrency: usize,
+
+    /// Copy store paths that are missing locally from upstreams into the bucket
+    #[arg(long)]
+    copy_from_upstream: bool,
+
+    /// Add our signature to paths copied from upstreams, once their signature is valid for a
+    /// --trusted-public-key and the NAR matches their FileHash and FileSize
+    #[arg(long, requires = "copy_from_upstream")]
+    resign: bool,
+
+    /// Write a manifest of every path pushed to this file as newline delimited json,
+    /// including how long each upload took and how much of that was compression
+    #[arg(long, value_name = "FILE")]
+    manifest_out: Option<PathBuf>,
+
+    /// Emit GitHub Actions log groups and annotations, write a job summary and set the
+    /// uploaded and uploaded-bytes step outputs
+    #[arg(long)]
+    gha: bool,
+
+    /// Show how many paths and bytes would be uploaded after checking upstreams and the
+    /// bucket, and ask before uploading them
+    #[arg(long)]
+    confirm: bool,
+
+    /// Print how many paths and bytes would be uploaded after checking upstreams and the
+    /// bucket, with an estimate of the compressed size, and exit without uploading
+    #[arg(long, conflicts_with = "confirm")]
+    estimate: bool,
+
+    /// Print the --estimate, or the summary at the end of a push, as a json object with the
+    /// requests sent to the bucket
+    #[arg(long)]
+    json: bool,
+
+    /// Break the --estimate down by package name, largest first, to see which
+    /// dependencies make up most of a push
+    #[arg(long, requires = "estimate")]
+    breakdown: bool,
+
+    /// Compressed size as a fraction of the NAR size, used to estimate the upload size.
+    /// Look at a past --manifest-out to find the ratio for your paths
+    #[arg(long, value_name = "RATIO", default_value_t = 0.4)]
+    compression_ratio: f64,
+
+    /// Only push paths that were built on this machine, skipping ones substituted from
+    /// caches
+    #[arg(long, conflicts_with = "copy_from_upstream")]
+    only_built_here: bool,
+
+    /// Refuse to upload paths that weren't built on this machine unless one of their
+    /// signatures is valid for our key or a --trusted-public-key
+    #[arg(long)]
+    check_sigs: bool,
+
+    /// Key whose signatures --check-sigs and --resign accept, like nix's trusted-public-keys
+    /// e.g. cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=.
+    /// Can be specified multiple times
+    #[arg(long = "trusted-public-key", value_name = "KEY")]
+    trusted_public_keys: Vec<String>,
+
+    /// Upload NARs to this prefix before they are renamed to their final path. Add a
+    /// lifecycle rule expiring objects under it to clean up after interrupted pushes even if
+    /// nixcp doesn't run again. An empty prefix uploads to the root of the bucket
+    #[arg(long, value_name = "PREFIX", default_value = "tmp")]
+    temp_prefix: String,
+
+    /// Delete temp objects left behind by interrupted pushes once they are this old.
+    /// Units: s, m, h, d, w
+    #[arg(long, value_name = "AGE", default_value = "1d", value_parser = prune::parse_age)]
+    clean_temp_older_than: Duration,
+
+    /// Do not look for temp objects left behind by interrupted pushes
+    #[arg(long)]
+    no_clean_temp: bool,
+
+    /// Leave the Deriver and System fields out of uploaded narinfos, e.g. to not reveal
+    /// derivation names
+    #[arg(long)]
+    no_deriver: bool,
+
+    /// Only publish our own signature instead of also copying signatures the store has from
+    /// other caches
+    #[arg(long)]
+    strip_signatures: bool,
+
+    /// Only copy the store's signatures by this key name into uploaded narinfos.
+    /// Can be specified multiple times.
+    #[arg(
+        long = "keep-signatures",
+        value_name = "KEY_NAME",
+        // cachix only takes our signature
+        conflicts_with_all = ["strip_signatures", "cachix"]
+    )]
+    keep_signatures: Vec<String>,
+
+    /// Do not send a SHA-256 checksum with every upload for S3 to verify, for endpoints
+    /// that reject x-amz-checksum-sha256
+    #[arg(long)]
+    no_checksum: bool,
+
+    /// Remember what was pushed in this file and skip paths it lists without asking
+    /// upstreams or the destination. Delete it if the cache was wiped or pruned
+    #[arg(long, value_name = "FILE")]
+    index: Option<PathBuf>,
+
+    /// Record finished paths in this file as the push goes, and skip the paths it lists.
+    /// Re-run with the same file to resume a push that crashed. Removed once the push
+    /// succeeds
+    #[arg(long, value_name = "FILE")]
+    journal: Option<PathBuf>,
+
+    /// Only push paths that aren't in the closure of this store path, e.g. the previous
+    /// generation of a system, or in this --manifest-out of an earlier push
+    #[arg(long, value_name = "PATH OR MANIFEST")]
+    since: Option<PathBuf>,
+
+    /// Only check whether a path is in the bucket if the bloom filter `nixcp bloom` wrote
+    /// says it may be, saving most requests for large pushes. Paths pushed without this
+    /// since the filter was written may be uploaded again
+    #[arg(long, conflicts_with = "cachix")]
+    bloom: bool,
+
+    /// List the skipped paths grouped by why they were skipped after the summary, or
+    /// write the list to FILE with --show-skipped=FILE
+    #[arg(long, value_name = "FILE", num_args = 0..=1, require_equals = true)]
+    show_skipped: Option<Option<PathBuf>>,
+
+    /// Claim each path with a lease object under leases/ in the bucket before uploading it,
+    /// so that runners pushing overlapping closures don't upload the same path at once
+    #[arg(long, conflicts_with = "cachix")]
+    coordinate: bool,
+
+    /// Leases that weren't renewed for this long are assumed to belong to a runner that died
+    /// e.g. 1h. Units: s, m, h, d, w
+    #[arg(long, value_name = "AGE", default_value = "1h", value_parser = prune::parse_age)]
+    lease_ttl: Duration,
+
+    /// After a successful push, write the pushed paths and the manifest to
+    /// releases/NAME/REV.json and releases/NAME/latest.json in the bucket, so that deployment
+    /// tooling can find the latest pushed system
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["cachix", "all", "all_roots"])]
+    release: Option<String>,
+
+    /// Revision to publish the release as, e.g. the git commit. Defaults to the time of the
+    /// push like 20250102T030405Z
+    #[arg(long, value_name = "REV", requires = "release")]
+    release_rev: Option<String>,
+
+    /// Do not wait for other pushes on this machine to finish before starting
+    #[arg(long)]
+    pub no_lock: bool,
+
+    /// Do not check that the bucket is writable before uploading
+    #[arg(long)]
+    pub skip_preflight: bool,
+
+    /// Print the settings that would be used, with secrets redacted, and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Push the closures of everything reachable from gc roots instead of PATH, e.g. to seed a
+    /// new cache from a long-lived builder
+    #[arg(long, conflicts_with_all = ["paths", "all"])]
+    pub all_roots: bool,
+
+    /// Push every valid path in the store instead of PATH
+    #[arg(long, conflicts_with = "paths")]
+    pub all: bool,
+
+    /// Read paths from a chroot store whose /nix/store lives under this directory instead of
+    /// the local store, e.g. one an image build produced
+    #[arg(long, value_name = "DIR")]
+    pub store_root: Option<PathBuf>,
+
+    /// Compute closures and stream NARs on this host over SSH (ssh-ng), while signing and
+    /// uploading happen here. The host needs nix but no cache credentials
+    #[arg(long, value_name = "HOST", conflicts_with = "store_root")]
+    pub on: Option<String>,
+
+    /// Path to upload. Builds and uploads .#default if none is given
     /// e.g. ./result or /nix/store/y4qpcibkj767szhjb58i2sidmz8m24hb-hello-2.12.1
     #[arg(value_name = "PATH")]
     pub paths: Vec<PathBuf>,
 }
+
+/// where the cache lives, for commands that work on an existing bucket
+#[derive(Debug, Args)]
+pub struct BucketArgs {
+    /// The s3 bucket of the cache
+    #[arg(long, value_name = "bucket name", required_unless_present = "to")]
+    bucket: Option<String>,
+
+    /// Nix style s3 store uri of the cache. --region and --endpoint take precedence over its
+    /// parameters.
+    #[arg(long, value_name = "s3 uri", conflicts_with = "bucket")]
+    to: Option<S3Uri>,
+
+    /// Only look at objects under this prefix within the bucket
+    #[arg(long)]
+    prefix: Option<String>,
+
+    /// If unspecified, will get it form AWS_DEFAULT_REGION envar or default to us-east-1
+    #[arg(long)]
+    region: Option<String>,
+
+    /// If unspecifed, will get it from AWS_ENDPOINT envar
+    #[arg(long)]
+    endpoint: Option<String>,
+
+    /// Put the bucket in the request path instead of the host name, as MinIO and Garage
+    /// usually need. Always done for endpoints on localhost or an ip address
+    #[arg(long)]
+    s3_path_style: bool,
+
+    /// Allow an http:// endpoint. Credentials and cache contents are sent unencrypted, so
+    /// only use this for local testing
+    #[arg(long)]
+    allow_http: bool,
+
+    /// STS endpoint to assume AWS_ROLE_ARN at with AWS_WEB_IDENTITY_TOKEN_FILE, for builders
+    /// that can't reach the default one. If unspecified, will get it from AWS_ENDPOINT_URL_STS
+    /// envar or use the regional endpoint of the partition of the region e.g. China
+    #[arg(long, value_name = "URL")]
+    sts_endpoint: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct PruneArgs {
+    #[command(flatten)]
+    bucket: BucketArgs,
+
+    /// Delete paths whose narinfo and NAR haven't been modified (or read, according to
+    /// --access-log) for this long e.g. 90d, unless a newer path references them.
+    /// Units: s, m, h, d, w
+    #[arg(long, value_name = "AGE", value_parser = prune::parse_age)]
+    older_than: Duration,
+
+    /// Never delete anything in the closure of this store path. Can be specified multiple
+    /// times. e.g. ./result or /nix/store/y4qpcibkj767szhjb58i2sidmz8m24hb-hello-2.12.1
+    #[arg(long, value_name = "PATH")]
+    keep_closure: Vec<PathBuf>,
+
+    /// S3 server access log file. Reads recorded in it count as uses. Can be specified
+    /// multiple times.
+    #[arg(long = "access-log", value_name = "FILE")]
+    access_logs: Vec<PathBuf>,
+
+    /// Also delete noncurrent versions and delete markers of narinfos and NARs, which
+    /// buckets with versioning enabled keep around and bill for after a delete
+    #[arg(long)]
+    purge_noncurrent: bool,
+
+    /// Only print what would be deleted
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct BloomArgs {
+    #[command(flatten)]
+    bucket: BucketArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct VerifyArgs {
+    #[command(flatten)]
+    bucket: BucketArgs,
+
+    /// Download and decompress every NAR to recompute its hash and size instead of only
+    /// checking that it exists with the right file size
+    #[arg(long)]
+    deep: bool,
+
+    /// File with the key NARs were pushed with --encryption-key, so that --deep can check
+    /// encrypted NARs too
+    #[arg(long, value_name = "FILE", requires = "deep")]
+    encryption_key: Option<PathBuf>,
+}
+
+/// caches to read from, for commands that fetch paths
+#[derive(Debug, Args)]
+pub struct CacheArgs {
+    /// Cache to fetch from, either a nix style s3 store uri or a http binary cache url.
+    /// Can be specified multiple times, the first cache that has the path is used.
+    /// e.g. s3://nixcache?endpoint=s3.example.com or https://cache.nixos.org
+    #[arg(long = "from", value_name = "cache uri", required = true)]
+    caches: Vec<CacheUri>,
+
+    /// Objects are under this prefix within s3 buckets
+    #[arg(long)]
+    prefix: Option<String>,
+
+    /// File with the key NARs were pushed with --encryption-key, to decrypt them
+    #[arg(long, value_name = "FILE")]
+    encryption_key: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct FetchArgs {
+    #[command(flatten)]
+    caches: CacheArgs,
+
+    /// File to write to
+    #[arg(long, short, value_name = "FILE")]
+    output: PathBuf,
+
+    /// Fetch the narinfo instead of the NAR
+    #[arg(long, conflicts_with = "decompress")]
+    narinfo: bool,
+
+    /// Decompress the NAR so that it can be fed to nix-store --restore
+    #[arg(long)]
+    decompress: bool,
+
+    /// Store path to fetch
+    /// e.g. /nix/store/y4qpcibkj767szhjb58i2sidmz8m24hb-hello-2.12.1
+    #[arg(value_name = "STORE PATH")]
+    path: String,
+}
+
+#[derive(Debug, Args)]
+pub struct ExtractArgs {
+    #[command(flatten)]
+    caches: CacheArgs,
+
+    /// Directory to unpack the path into. Must not exist yet
+    #[arg(long, short, value_name = "DIR")]
+    output: PathBuf,
+
+    /// Store path to extract
+    /// e.g. /nix/store/y4qpcibkj767szhjb58i2sidmz8m24hb-hello-2.12.1
+    #[arg(value_name = "STORE PATH")]
+    path: String,
+}
+
+#[derive(Debug, Args)]
+pub struct CheckArgs {
+    /// Upstream cache to check, either a nix style s3 store uri or a http binary cache url.
+    /// Can be specified multiple times. cache.nixos.org is always included.
+    #[arg(long = "upstream", short, value_name = "cache uri")]
+    upstreams: Vec<CacheUri>,
+
+    /// Do not include cache.nixos.org as upstream
+    #[arg(long)]
+    no_default_upstream: bool,
+
+    /// Nix style s3 store uri of your own cache, to check along with the upstreams
+    #[arg(long, value_name = "s3 uri")]
+    to: Option<S3Uri>,
+
+    /// Objects are under this prefix within s3 buckets
+    #[arg(long)]
+    prefix: Option<String>,
+
+    /// Store path to check e.g. ./result or
+    /// /nix/store/y4qpcibkj767szhjb58i2sidmz8m24hb-hello-2.12.1
+    #[arg(value_name = "STORE PATH")]
+    path: String,
+}
+
+#[derive(Debug, Args)]
+pub struct ReplicateArgs {
+    /// Nix style s3 store uri to copy from
+    /// e.g. s3://nixcache?endpoint=s3.example.com
+    #[arg(long, value_name = "s3 uri")]
+    from: S3Uri,
+
+    /// Nix style s3 store uri to copy to
+    #[arg(long, value_name = "s3 uri")]
+    to: S3Uri,
+
+    /// Keep running and look for new paths this often e.g. 5m. Replicates once if
+    /// unspecified. Units: s, m, h, d, w
+    #[arg(long, value_name = "INTERVAL", value_parser = prune::parse_age)]
+    interval: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct WatchArgs {
+    /// Profile to watch e.g. /nix/var/nix/profiles/system
+    #[arg(long, value_name = "PROFILE")]
+    profile: PathBuf,
+
+    /// How often to check the profile for a new generation. Units: s, m, h, d, w
+    #[arg(long, value_name = "INTERVAL", default_value = "10s", value_parser = prune::parse_age)]
+    poll_interval: Duration,
+
+    /// Only push new generations during this time of day, local time e.g. 22:00-06:00.
+    /// Generations that appear outside of it are pushed once it opens
+    #[arg(long, value_name = "START-END")]
+    upload_window: Option<UploadWindow>,
+
+    // any PATH given is pushed along with every generation
+    #[command(flatten)]
+    push: PushArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct BenchArgs {
+    #[command(flatten)]
+    bucket: BucketArgs,
+
+    /// Size of the objects to upload e.g. 64MiB. Can be specified multiple times
+    #[arg(
+        long = "size",
+        value_name = "SIZE",
+        default_value = "1MiB",
+        value_parser = push::parse_size
+    )]
+    sizes: Vec<u64>,
+
+    /// Number of objects to upload of each size
+    #[arg(long, default_value_t = 16, value_parser = clap::value_parser!(usize).range(1..))]
+    count: usize,
+
+    /// Number of objects to upload concurrently
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(usize).range(1..))]
+    concurrency: usize,
+
+    /// Number of parts of a single large object to upload concurrently
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(usize).range(1..))]
+    part_concurrency: usize,
+}
@@ -1,33 +1,108 @@
+use std::path::PathBuf;
+
 use anyhow::{Context, Result};
 use clap::Parser;
-use tracing_subscriber::{EnvFilter, prelude::*};
+use tracing_subscriber::{EnvFilter, Layer, prelude::*};
 
-use nixcp::push::Push;
+use nixcp::bench::Bench;
+use nixcp::bloom::Bloom;
+use nixcp::check::Check;
+use nixcp::extract::Extract;
+use nixcp::fetch::Fetch;
+use nixcp::prune::Prune;
+use nixcp::push::{self, Push};
+use nixcp::replicate::Replicate;
 use nixcp::store::Store;
-use nixcp::{Cli, Commands};
+use nixcp::verify::Verify;
+use nixcp::watch::Watch;
+use nixcp::{Cli, Commands, LogFormat};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
-    init_logging(cli.tokio_console);
+    init_logging(cli.tokio_console, cli.log_format);
 
     match &cli.command {
         Commands::Push(cli) => {
-            let store = Store::connect()?;
+            if cli.print_config {
+                return push::print_config(cli);
+            }
+            // held until the push is done
+            let _lock = if cli.no_lock {
+                None
+            } else {
+                Some(push::lock()?)
+            };
+            let store_uri = cli.store_uri()?;
+            let store = Store::connect(store_uri.as_deref())?;
             let push = Box::leak(Box::new(Push::new(cli, store).await?));
-            push.add_paths(cli.paths.clone())
-                .await
-                .context("add paths to push")?;
+            if !cli.skip_preflight {
+                push.preflight()
+                    .await
+                    .context("preflight check of bucket permissions")?;
+            }
+            if cli.all || cli.all_roots {
+                push.add_store(cli.all)
+                    .await
+                    .context("add store paths to push")?;
+            } else {
+                let paths = if cli.paths.is_empty() {
+                    push::build(push::DEFAULT_INSTALLABLE, store_uri.as_deref()).await?;
+                    vec![PathBuf::from(push::DEFAULT_INSTALLABLE)]
+                } else {
+                    cli.paths.clone()
+                };
+                push.add_paths(paths).await.context("add paths to push")?;
+            }
             push.run().await.context("nixcp run")?;
         }
+        Commands::Prune(cli) => {
+            let prune = Prune::new(cli)?;
+            prune.run().await.context("nixcp prune")?;
+        }
+        Commands::Verify(cli) => {
+            let verify = Verify::new(cli)?;
+            verify.run().await.context("nixcp verify")?;
+        }
+        Commands::Bloom(cli) => {
+            let bloom = Bloom::new(cli)?;
+            bloom.run().await.context("nixcp bloom")?;
+        }
+        Commands::Fetch(cli) => {
+            let fetch = Fetch::new(cli)?;
+            fetch.run().await.context("nixcp fetch")?;
+        }
+        Commands::Extract(cli) => {
+            let extract = Extract::new(cli)?;
+            extract.run().await.context("nixcp extract")?;
+        }
+        Commands::Check(cli) => {
+            let check = Check::new(cli)?;
+            check.run().await.context("nixcp check")?;
+        }
+        Commands::Replicate(cli) => {
+            let replicate = Replicate::new(cli)?;
+            replicate.run().await.context("nixcp replicate")?;
+        }
+        Commands::Watch(cli) => {
+            Watch::new(cli).run().await.context("nixcp watch")?;
+        }
+        Commands::Bench(cli) => {
+            let bench = Bench::new(cli)?;
+            bench.run().await.context("nixcp bench")?;
+        }
     }
 
     Ok(())
 }
 
-fn init_logging(tokio_console: bool) {
+fn init_logging(tokio_console: bool, log_format: LogFormat) {
     let env_filter = EnvFilter::from_default_env();
-    let fmt_layer = tracing_subscriber::fmt::layer().with_filter(env_filter);
+    let fmt_layer = match log_format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().boxed(),
+    }
+    .with_filter(env_filter);
 
     let console_layer = if tokio_console {
         Some(console_subscriber::spawn())
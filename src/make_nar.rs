@@ -1,17 +1,39 @@
-use anyhow::Result;
-use async_compression::{Level, tokio::bufread::ZstdEncoder};
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use futures::stream;
 use nix_compat::{
     narinfo::{self, NarInfo},
+    nixhash::CAHash,
     store_path::StorePath,
 };
 use sha2::{Digest, Sha256};
-use std::{mem::take, sync::Arc};
-use tokio::io::{AsyncRead, BufReader};
-use tokio_util::io::InspectReader;
+use std::{
+    io::{self, BufReader, Read, Write},
+    mem::take,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::mpsc,
+    task,
+};
+use tokio_util::io::{StreamReader, SyncIoBridge};
+use tracing::{Span, debug_span, field};
 
+use crate::compression::{Compression, NoCompression, Zstd};
+use crate::encryption::{Encrypted, EncryptionKey};
 use crate::path_info::PathInfo;
 use crate::store::Store;
 
+/// how much of the nar is compressed to estimate its compression ratio
+const SAMPLE_SIZE: u64 = 4 * 1024 * 1024;
+/// how many compressed chunks can wait for the reader
+const CHANNEL_DEPTH: usize = 16;
+/// how much of the nar is read from the store at a time
+const READ_BUF_SIZE: usize = 64 * 1024;
+
+#[derive(Clone)]
 pub struct MakeNar<'a> {
     path_info: &'a PathInfo,
     store: Arc<Store>,
@@ -20,6 +42,9 @@ pub struct MakeNar<'a> {
     file_hasher: Sha256,
     pub nar_size: u64,
     file_size: u64,
+    /// time spent compressing and hashing, not counting waits on the store or the reader
+    pub compress_time: Duration,
+    compression: Arc<dyn Compression>,
 }
 
 impl<'a> MakeNar<'a> {
@@ -31,28 +56,110 @@ impl<'a> MakeNar<'a> {
             file_hasher: Sha256::new(),
             nar_size: 0,
             file_size: 0,
+            compress_time: Duration::ZERO,
+            compression: Arc::new(Zstd::default()),
         })
     }
 
+    /// Compress the nar with zstd long distance matching with the given window log
+    pub fn long_distance_matching(self, window_log: Option<u32>) -> Self {
+        self.with_compression(Arc::new(Zstd { window_log }))
+    }
+
+    /// Compress the nar with `compression` instead of zstd
+    pub fn with_compression(mut self, compression: Arc<dyn Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Upload the nar uncompressed
+    pub fn without_compression(self) -> Self {
+        self.with_compression(Arc::new(NoCompression))
+    }
+
+    /// Encrypt the nar with `key` after compressing it
+    pub fn encrypted(self, key: EncryptionKey) -> Self {
+        let compression = Arc::new(Encrypted::new(self.compression.clone(), key));
+        self.with_compression(compression)
+    }
+
+    /// Compresses the first few MiB of the nar and returns the ratio of compressed to
+    /// uncompressed size. A ratio close to 1 means compression is not worth it.
+    pub async fn sample_compression_ratio(&self) -> Result<f64> {
+        let mut sample = Vec::new();
+        self.store
+            .nar_from_path(self.path_info.path.clone())
+            .take(SAMPLE_SIZE)
+            .read_to_end(&mut sample)
+            .await?;
+        if sample.is_empty() {
+            return Ok(1.0);
+        }
+
+        let sample_len = sample.len();
+        let compression = self.compression.clone();
+        let compressed_len = task::spawn_blocking(move || {
+            let mut compressed = Vec::new();
+            compression.compress(&mut sample.as_slice(), &mut compressed)?;
+            io::Result::Ok(compressed.len())
+        })
+        .await??;
+        Ok(compressed_len as f64 / sample_len as f64)
+    }
+
+    /// Compression used for the nar as it would appear in the narinfo
+    pub fn compression(&self) -> &str {
+        self.compression.name()
+    }
+
+    /// Extension of the compressed nar, e.g. `.zst`
+    pub fn extension(&self) -> &str {
+        self.compression.extension()
+    }
+
     /// Returns a compressed nar reader which can be uploaded. File hash will be available when
-    /// everything is read
+    /// everything is read.
+    ///
+    /// Compressing and hashing happen on a blocking thread so they don't hold up the runtime.
+    /// It is connected to the reader by a bounded channel.
     pub fn compress_and_hash(&mut self) -> Result<impl AsyncRead> {
-        let nar_reader = self.store.nar_from_path(self.path_info.path.clone());
-        // reader that hashes as nar is read
-        let nar_reader = InspectReader::new(nar_reader, |x| {
-            self.nar_size += x.len() as u64;
-            self.nar_hasher.update(x);
+        let nar_reader = SyncIoBridge::new(Box::pin(
+            self.store.nar_from_path(self.path_info.path.clone()),
+        ));
+        let (tx, rx) = mpsc::channel(CHANNEL_DEPTH);
+        let compression = self.compression.clone();
+        let span = debug_span!(
+            "compress",
+            path = %self.path_info.absolute_path(),
+            bytes = field::Empty
+        );
+        task::spawn_blocking(move || {
+            let _span = span.enter();
+            if let Err(e) = compress_and_hash(nar_reader, tx.clone(), compression.as_ref()) {
+                // the reader is gone if this fails, then there's nobody to tell anyway
+                let _ = tx.blocking_send(Err(e));
+            }
         });
 
-        let encoder = ZstdEncoder::with_quality(BufReader::new(nar_reader), Level::Default);
-        // reader that updates file_hash as the compressed nar is read
-        Ok(InspectReader::new(encoder, |x| {
-            self.file_size += x.len() as u64;
-            self.file_hasher.update(x);
-        }))
+        let chunks = stream::unfold((rx, self), |(mut rx, this)| async move {
+            match rx.recv().await? {
+                Ok(Compressed::Chunk(chunk)) => Some((Ok(chunk), (rx, this))),
+                Ok(Compressed::Done(hashes)) => {
+                    this.nar_hasher = hashes.nar_hasher;
+                    this.nar_size = hashes.nar_size;
+                    this.file_hasher = hashes.file_hasher;
+                    this.file_size = hashes.file_size;
+                    this.compress_time = hashes.compress_time;
+                    None
+                }
+                Err(e) => Some((Err(e), (rx, this))),
+            }
+        });
+        Ok(StreamReader::new(Box::pin(chunks)))
     }
 
-    /// Returns *unsigned* narinfo. `url` must be updated before uploading
+    /// Returns narinfo that is not signed by us but carries the signatures of the path info.
+    /// `url` must be updated before uploading
     pub fn get_narinfo(&mut self) -> Result<NarInfo> {
         let file_hash = take(&mut self.file_hasher).finalize().into();
         let nar_hash = take(&mut self.nar_hasher).finalize().into();
@@ -68,14 +175,129 @@ impl<'a> MakeNar<'a> {
                 .iter()
                 .map(StorePath::as_ref)
                 .collect(),
-            signatures: Vec::new(),
-            ca: None,
-            system: None,
-            deriver: None,
-            compression: Some("zstd"),
+            // malformed signatures can't be published, skip them
+            signatures: self
+                .path_info
+                .signatures
+                .iter()
+                .filter_map(|signature| narinfo::Signature::parse(signature).ok())
+                .collect(),
+            ca: self
+                .path_info
+                .ca
+                .as_deref()
+                .map(|ca| {
+                    CAHash::from_nix_hex_str(ca)
+                        .ok_or_else(|| anyhow!("invalid content address {ca}"))
+                })
+                .transpose()?,
+            system: self.path_info.system.as_deref(),
+            deriver: self.path_info.deriver.as_ref().map(StorePath::as_ref),
+            compression: Some(self.compression()),
             file_hash: Some(file_hash),
             file_size: Some(self.file_size),
             url: "",
         })
     }
 }
+
+/// what the compressing thread sends to the reader
+enum Compressed {
+    Chunk(Bytes),
+    /// sent after the last chunk
+    Done(Hashes),
+}
+
+struct Hashes {
+    nar_hasher: Sha256,
+    nar_size: u64,
+    file_hasher: Sha256,
+    file_size: u64,
+    compress_time: Duration,
+}
+
+/// sends everything written to it to the reader as chunks and hashes it on the way
+struct ChunkWriter {
+    tx: mpsc::Sender<io::Result<Compressed>>,
+    file_hasher: Sha256,
+    file_size: u64,
+    /// time spent waiting for the reader to make room in the channel
+    send_wait: Duration,
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file_hasher.update(buf);
+        self.file_size += buf.len() as u64;
+        let start = Instant::now();
+        self.tx
+            .blocking_send(Ok(Compressed::Chunk(Bytes::copy_from_slice(buf))))
+            .map_err(|_| io::Error::other("compressed nar reader was dropped"))?;
+        self.send_wait += start.elapsed();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// hashes the nar as the compressor reads it
+struct HashingReader<R> {
+    inner: R,
+    nar_hasher: Sha256,
+    nar_size: u64,
+    /// time spent waiting for the store
+    read_wait: Duration,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = Instant::now();
+        let n = self.inner.read(buf)?;
+        self.read_wait += start.elapsed();
+        self.nar_hasher.update(&buf[..n]);
+        self.nar_size += n as u64;
+        Ok(n)
+    }
+}
+
+/// runs on a blocking thread, reads the whole nar and sends it compressed to `tx`
+fn compress_and_hash(
+    nar_reader: impl Read,
+    tx: mpsc::Sender<io::Result<Compressed>>,
+    compression: &dyn Compression,
+) -> io::Result<()> {
+    let start = Instant::now();
+    let mut reader = BufReader::with_capacity(
+        READ_BUF_SIZE,
+        HashingReader {
+            inner: nar_reader,
+            nar_hasher: Sha256::new(),
+            nar_size: 0,
+            read_wait: Duration::ZERO,
+        },
+    );
+    let mut writer = ChunkWriter {
+        tx: tx.clone(),
+        file_hasher: Sha256::new(),
+        file_size: 0,
+        send_wait: Duration::ZERO,
+    };
+    compression.compress(&mut reader, &mut writer)?;
+
+    let reader = reader.into_inner();
+    let nar_size = reader.nar_size;
+    let hashes = Hashes {
+        nar_hasher: reader.nar_hasher,
+        nar_size,
+        file_hasher: writer.file_hasher,
+        file_size: writer.file_size,
+        compress_time: start
+            .elapsed()
+            .saturating_sub(reader.read_wait + writer.send_wait),
+    };
+    Span::current().record("bytes", nar_size);
+    tx.blocking_send(Ok(Compressed::Done(hashes)))
+        .map_err(|_| io::Error::other("compressed nar reader was dropped"))
+}
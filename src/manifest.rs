@@ -0,0 +1,56 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use nix_compat::nixbase32;
+use serde::{Deserialize, Serialize};
+
+/// what was published for a single store path
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub store_path: String,
+    /// relative to the root of the cache
+    pub narinfo_url: String,
+    /// relative to the root of the cache, `None` if the destination picks it
+    pub nar_url: Option<String>,
+    /// `sha256:<nixbase32>` of the compressed nar
+    pub file_hash: Option<String>,
+    pub file_size: Option<u64>,
+    pub nar_size: u64,
+    /// seconds spent compressing and hashing the nar, not counting waits on the store or
+    /// the network. `None` if we didn't compress it ourselves
+    pub compress_secs: Option<f64>,
+    /// wall clock seconds from starting to compress the nar to publishing the narinfo
+    pub elapsed_secs: Option<f64>,
+}
+
+impl ManifestEntry {
+    pub fn format_hash(hash: &[u8; 32]) -> String {
+        format!("sha256:{}", nixbase32::encode(hash))
+    }
+}
+
+/// read a manifest written by `write_ndjson`
+pub fn read_ndjson(path: &Path) -> Result<Vec<ManifestEntry>> {
+    let manifest = fs::read_to_string(path).context(format!("read manifest {path:?}"))?;
+    manifest
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).context(format!("parse manifest {path:?}")))
+        .collect()
+}
+
+/// write one json object per line
+pub fn write_ndjson(path: &Path, entries: &[ManifestEntry]) -> Result<()> {
+    let file = File::create(path).context(format!("create manifest {path:?}"))?;
+    let mut writer = BufWriter::new(file);
+    for entry in entries {
+        serde_json::to_writer(&mut writer, entry)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
@@ -1,17 +1,69 @@
 use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
 
 use anyhow::{Context, Result, anyhow};
 use futures::future::join_all;
+use nix_compat::narinfo::{self, VerifyingKey};
 use nix_compat::nixbase32;
 use nix_compat::store_path::StorePath;
-use object_store::{ObjectStore, aws::AmazonS3, path::Path as ObjectPath};
+use object_store::{ObjectStore, path::Path as ObjectPath};
 use regex::Regex;
-use std::path::Path;
+use reqwest::{Method, StatusCode, header};
+use std::fmt;
+use std::path::{Path, PathBuf};
 use tokio::process::Command;
+use tokio::sync::Semaphore;
 use tracing::{debug, trace};
 use url::Url;
 
-use crate::store::Store;
+use crate::{
+    http::UpstreamClient,
+    store::Store,
+    upstream_stats::{CheckOutcome, UpstreamStats},
+};
+
+/// a symlink like ./result whose target has been garbage collected
+#[derive(Debug)]
+pub struct DanglingSymlink(pub PathBuf);
+
+impl fmt::Display for DanglingSymlink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "target of {} no longer exists; rebuild with nix build",
+            self.0.display()
+        )
+    }
+}
+
+impl std::error::Error for DanglingSymlink {}
+
+/// path info queries `get_closure` runs at once. each one takes a blocking thread and the
+/// store connection, so running thousands at once only makes them wait on each other
+pub const DEFAULT_CLOSURE_CONCURRENCY: usize = 32;
+
+/// how far computing closures got, so that long ones can report progress
+#[derive(Debug, Default)]
+pub struct ClosureProgress {
+    /// paths found in closures so far
+    discovered: AtomicUsize,
+    /// paths whose path info was queried so far
+    queried: AtomicUsize,
+}
+
+impl ClosureProgress {
+    pub fn discovered(&self) -> usize {
+        self.discovered.load(Ordering::Relaxed)
+    }
+
+    /// path info queries that haven't finished yet
+    pub fn outstanding(&self) -> usize {
+        self.discovered()
+            .saturating_sub(self.queried.load(Ordering::Relaxed))
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct PathInfo {
@@ -19,6 +71,16 @@ pub struct PathInfo {
     pub signatures: Vec<String>,
     pub references: Vec<StorePath<String>>,
     pub nar_size: u64,
+    /// sha256 of the nar
+    pub nar_hash: [u8; 32],
+    /// content address like `fixed:r:sha256:...` for content addressed paths
+    pub ca: Option<String>,
+    /// the derivation that built this path, if the store knows it
+    pub deriver: Option<StorePath<String>>,
+    /// the system `deriver` builds for, only read when uploading the path
+    pub system: Option<String>,
+    /// built on this machine rather than substituted from a cache
+    pub ultimate: bool,
 }
 
 impl PathInfo {
@@ -31,14 +93,20 @@ impl PathInfo {
                 let drv = {
                     // resolve symlink
                     if drv.is_symlink() {
+                        if !drv.exists() {
+                            return Err(DanglingSymlink(drv.to_path_buf()).into());
+                        }
                         &drv.canonicalize()?
                     } else {
                         drv
                     }
                 };
-                &Command::new("nix")
-                    .arg("path-info")
-                    .arg("--derivation")
+                let mut command = Command::new("nix");
+                command.arg("path-info").arg("--derivation");
+                if let Some(uri) = store.uri() {
+                    command.arg("--store").arg(uri);
+                }
+                &command
                     .arg(drv)
                     .output()
                     .await
@@ -69,11 +137,30 @@ impl PathInfo {
 
     // TODO: skip call to query_path_info and return Vec<Path>?
     pub async fn get_closure(&self, store: &Store) -> Result<Vec<Self>> {
-        let futs = store
-            .compute_fs_closure(self.path.clone())
-            .await?
-            .into_iter()
-            .map(|x| store.query_path_info(x));
+        let permits = Semaphore::new(DEFAULT_CLOSURE_CONCURRENCY);
+        self.get_closure_with_progress(store, &ClosureProgress::default(), &permits)
+            .await
+    }
+
+    /// like `get_closure`, counting paths into `progress` as they are found and queried.
+    /// every path info query holds one of `permits`, share them to bound the queries of
+    /// several closures together
+    pub async fn get_closure_with_progress(
+        &self,
+        store: &Store,
+        progress: &ClosureProgress,
+        permits: &Semaphore,
+    ) -> Result<Vec<Self>> {
+        let closure = store.compute_fs_closure(self.path.clone()).await?;
+        progress
+            .discovered
+            .fetch_add(closure.len(), Ordering::Relaxed);
+        let futs = closure.into_iter().map(|x| async move {
+            let _permit = permits.acquire().await.unwrap();
+            let path_info = store.query_path_info(x).await;
+            progress.queried.fetch_add(1, Ordering::Relaxed);
+            path_info
+        });
         join_all(futs).await.into_iter().collect()
     }
 
@@ -95,6 +182,21 @@ impl PathInfo {
         false
     }
 
+    /// whether one of the signatures the store has for this path is valid for one of `keys`
+    pub fn has_valid_signature(&self, keys: &[&VerifyingKey]) -> bool {
+        let references: Vec<_> = self.references.iter().map(StorePath::as_ref).collect();
+        let fingerprint = narinfo::fingerprint(
+            &self.path.as_ref(),
+            &self.nar_hash,
+            self.nar_size,
+            references.iter(),
+        );
+        self.signatures
+            .iter()
+            .filter_map(|signature| narinfo::Signature::parse(signature).ok())
+            .any(|signature| keys.iter().any(|key| key.verify(&fingerprint, &signature)))
+    }
+
     fn signees(&self) -> Vec<&str> {
         let signers: Vec<_> = self
             .signatures
@@ -105,23 +207,119 @@ impl PathInfo {
         signers
     }
 
-    pub async fn check_upstream_hit(&self, upstreams: &[Url]) -> bool {
-        for upstream in upstreams {
-            let upstream = upstream
+    /// checks if any upstream has a narinfo for this path. a network error from an upstream
+    /// is only returned if no other upstream had a hit.
+    pub async fn check_upstream_hit(
+        &self,
+        upstreams: &[Url],
+        client: &UpstreamClient,
+    ) -> Result<bool> {
+        let mut error = None;
+        for cache in upstreams {
+            let upstream = cache
                 .join(self.narinfo_path().as_ref())
                 .expect("adding <hash>.narinfo should make a valid url");
             trace!("querying {}", upstream);
-            let res_status = reqwest::Client::new()
-                .head(upstream.as_str())
-                .send()
-                .await
-                .map(|x| x.status());
+            let start = Instant::now();
+            let request = client.request(Method::HEAD, &upstream).await;
+            let res = match client.send(request).await {
+                // some caches and CDNs reject HEAD even for objects that exist
+                Ok(res)
+                    if matches!(
+                        res.status(),
+                        StatusCode::FORBIDDEN | StatusCode::METHOD_NOT_ALLOWED
+                    ) =>
+                {
+                    trace!("HEAD rejected by {}, retrying with ranged GET", upstream);
+                    let request = client
+                        .request(Method::GET, &upstream)
+                        .await
+                        .header(header::RANGE, "bytes=0-0");
+                    client.send(request).await
+                }
+                res => res,
+            };
+            let outcome = match &res {
+                Ok(res) if res.status().is_success() => CheckOutcome::Hit,
+                Ok(res) if res.status() == StatusCode::TOO_MANY_REQUESTS => CheckOutcome::Error,
+                Ok(_) => CheckOutcome::Miss,
+                Err(_) => CheckOutcome::Error,
+            };
+            client
+                .stats()
+                .record(cache.as_str(), start.elapsed(), outcome);
+            match res {
+                Ok(res) if res.status().is_success() => return Ok(true),
+                // not knowing is different from a miss
+                Ok(res) if res.status() == StatusCode::TOO_MANY_REQUESTS => {
+                    error.get_or_insert(anyhow!("check upstream {upstream}: still throttled"));
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    error.get_or_insert(
+                        anyhow::Error::new(e).context(format!("check upstream {upstream}")),
+                    );
+                }
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(false),
+        }
+    }
 
-            if res_status.map(|code| code.is_success()).unwrap_or_default() {
-                return true;
+    /// like `check_upstream_hit` for upstreams we talk to with object_store, e.g. private
+    /// s3 buckets or local directories
+    pub async fn check_upstream_store_hit(
+        &self,
+        stores: &[Arc<dyn ObjectStore>],
+        stats: &UpstreamStats,
+    ) -> Result<bool> {
+        let mut error = None;
+        for store in stores {
+            trace!("querying {} in {}", self.narinfo_path(), store);
+            let start = Instant::now();
+            let res = store.head(&self.narinfo_path()).await;
+            let outcome = match &res {
+                Ok(_) => CheckOutcome::Hit,
+                Err(object_store::Error::NotFound { .. }) => CheckOutcome::Miss,
+                Err(_) => CheckOutcome::Error,
+            };
+            stats.record(&store.to_string(), start.elapsed(), outcome);
+            match res {
+                Ok(_) => return Ok(true),
+                Err(object_store::Error::NotFound { .. }) => {}
+                Err(e) => {
+                    error.get_or_insert(
+                        anyhow::Error::new(e).context(format!("check upstream {store}")),
+                    );
+                }
             }
         }
-        false
+        match error {
+            Some(e) => Err(e),
+            None => Ok(false),
+        }
+    }
+
+    /// regular files under the path in the local /nix/store. blocks, so call it from a blocking
+    /// task
+    pub fn count_files(&self) -> std::io::Result<usize> {
+        fn walk(path: &Path) -> std::io::Result<usize> {
+            let metadata = std::fs::symlink_metadata(path)?;
+            if metadata.is_file() {
+                return Ok(1);
+            }
+            if !metadata.is_dir() {
+                return Ok(0);
+            }
+            let mut count = 0;
+            for entry in std::fs::read_dir(path)? {
+                count += walk(&entry?.path())?;
+            }
+            Ok(count)
+        }
+        walk(Path::new(&self.absolute_path()))
     }
 
     pub fn absolute_path(&self) -> String {
@@ -133,7 +331,12 @@ impl PathInfo {
             .expect("must parse to a valid object_store path")
     }
 
-    pub async fn check_if_already_exists(&self, s3: &AmazonS3) -> bool {
-        s3.head(&self.narinfo_path()).await.is_ok()
+    pub async fn check_if_already_exists(&self, s3: &dyn ObjectStore) -> Result<bool> {
+        let narinfo_path = self.narinfo_path();
+        match s3.head(&narinfo_path).await {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::NotFound { .. }) => Ok(false),
+            Err(e) => Err(anyhow::Error::new(e).context(format!("check {narinfo_path}"))),
+        }
     }
 }
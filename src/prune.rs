@@ -0,0 +1,385 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt, stream};
+use humansize::{DECIMAL, format_size};
+use nix_compat::{narinfo::NarInfo, nixbase32, store_path::StorePath};
+use object_store::{ObjectMeta, ObjectStore, path::Path};
+use tracing::debug;
+
+use crate::{PruneArgs, versions::BucketVersions};
+
+/// what we need to know about a narinfo in the bucket
+#[derive(Debug, Clone)]
+pub struct CachedPath {
+    pub nar_url: String,
+    /// narinfo keys of the references
+    pub references: Vec<String>,
+}
+
+pub struct Prune {
+    s3: Arc<dyn ObjectStore>,
+    // paths not used since then are deleted
+    cutoff: DateTime<Utc>,
+    // narinfo keys of the roots whose closure is kept
+    roots: Vec<String>,
+    // key -> last time it was read according to the access logs
+    last_access: HashMap<String, DateTime<Utc>>,
+    // purge old versions afterwards, with --purge-noncurrent
+    versions: Option<BucketVersions>,
+    dry_run: bool,
+}
+
+impl Prune {
+    pub fn new(cli: &PruneArgs) -> Result<Self> {
+        let s3 = cli.bucket.build()?;
+
+        let cutoff = Utc::now()
+            - chrono::Duration::from_std(cli.older_than).context("--older-than is too large")?;
+
+        let roots = cli
+            .keep_closure
+            .iter()
+            .map(|root| {
+                // resolve symlinks like ./result
+                let root = fs::canonicalize(root).unwrap_or_else(|_| root.clone());
+                let store_path =
+                    StorePath::<String>::from_absolute_path(root.as_os_str().as_encoded_bytes())
+                        .context(format!("{root:?} is not a store path"))?;
+                Ok(narinfo_key(store_path.digest()))
+            })
+            .collect::<Result<_>>()?;
+
+        let mut last_access = HashMap::new();
+        for access_log in &cli.access_logs {
+            let contents = fs::read_to_string(access_log)
+                .context(format!("read access log {access_log:?}"))?;
+            for (key, time) in parse_access_log(&contents, cli.bucket.prefix.as_deref()) {
+                last_access
+                    .entry(key)
+                    .and_modify(|x: &mut DateTime<Utc>| *x = (*x).max(time))
+                    .or_insert(time);
+            }
+        }
+
+        Ok(Self {
+            s3,
+            cutoff,
+            roots,
+            last_access,
+            versions: cli
+                .purge_noncurrent
+                .then(|| cli.bucket.versions())
+                .transpose()?,
+            dry_run: cli.dry_run,
+        })
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        let objects: HashMap<String, ObjectMeta> = self
+            .s3
+            .list(None)
+            .map_ok(|meta| (meta.location.to_string(), meta))
+            .try_collect()
+            .await
+            .context("list objects in bucket")?;
+
+        // narinfos live at the root of the cache
+        let narinfo_keys: Vec<&String> = objects
+            .keys()
+            .filter(|key| key.ends_with(".narinfo") && !key.contains('/'))
+            .collect();
+        println!("found {} narinfos in the bucket", narinfo_keys.len());
+        let cached_paths: HashMap<&str, CachedPath> = stream::iter(narinfo_keys)
+            .map(|key| async move { anyhow::Ok((key.as_str(), self.fetch_narinfo(key).await?)) })
+            .buffer_unordered(32)
+            .try_collect()
+            .await?;
+
+        let recently_used: HashSet<&str> = cached_paths
+            .iter()
+            .filter(|(key, cached_path)| {
+                let nar_used = objects
+                    .get(&cached_path.nar_url)
+                    .map(|nar| self.last_used(nar));
+                self.last_used(&objects[**key]) >= self.cutoff
+                    || nar_used.is_some_and(|used| used >= self.cutoff)
+            })
+            .map(|(&key, _)| key)
+            .collect();
+        let stale_narinfos = stale_narinfos(&cached_paths, &self.roots, &recently_used)?;
+        let kept_nars: HashSet<&str> = cached_paths
+            .iter()
+            .filter(|(key, _)| !stale_narinfos.contains(*key))
+            .map(|(_, cached_path)| cached_path.nar_url.as_str())
+            .collect();
+        // a nar can be shared by several narinfos so only delete it once nothing uses it
+        let stale_nars: HashSet<&str> = stale_narinfos
+            .iter()
+            .map(|key| cached_paths[key].nar_url.as_str())
+            .filter(|nar_url| !kept_nars.contains(nar_url) && objects.contains_key(*nar_url))
+            .collect();
+        let freed_bytes: u64 = stale_nars
+            .iter()
+            .chain(&stale_narinfos)
+            .map(|key| objects[*key].size)
+            .sum();
+
+        // before anything is deleted, so that what we delete counts too
+        let managed = ManagedKeys::new(
+            cached_paths
+                .values()
+                .map(|cached_path| cached_path.nar_url.as_str()),
+        );
+
+        for key in &stale_narinfos {
+            debug!("prune {key} (nar: {})", cached_paths[key].nar_url);
+        }
+        if self.dry_run {
+            println!(
+                "would prune {} paths ({})",
+                stale_narinfos.len(),
+                format_size(freed_bytes, DECIMAL)
+            );
+            return self.purge_noncurrent(&managed).await;
+        }
+
+        // delete narinfos first so that clients never see one pointing to a missing nar
+        self.delete(stale_narinfos.iter().copied())
+            .await
+            .context("delete narinfos")?;
+        self.delete(stale_nars.into_iter())
+            .await
+            .context("delete nars")?;
+        println!(
+            "pruned {} paths ({})",
+            stale_narinfos.len(),
+            format_size(freed_bytes, DECIMAL)
+        );
+        self.purge_noncurrent(&managed).await
+    }
+
+    /// delete what versioning kept of narinfos and nars, including what we just deleted
+    async fn purge_noncurrent(&self, managed: &ManagedKeys<'_>) -> Result<()> {
+        let Some(versions) = &self.versions else {
+            return Ok(());
+        };
+        let (delete_markers, noncurrent): (Vec<_>, Vec<_>) = versions
+            .noncurrent()
+            .await
+            .context("list object versions")?
+            .into_iter()
+            .filter(|version| managed.contains(&version.key))
+            .partition(|version| version.delete_marker);
+        let purged_bytes: u64 = noncurrent.iter().map(|version| version.size).sum();
+        if self.dry_run {
+            println!(
+                "would purge {} noncurrent versions ({}) and {} delete markers",
+                noncurrent.len(),
+                format_size(purged_bytes, DECIMAL),
+                delete_markers.len()
+            );
+            return Ok(());
+        }
+
+        // delete markers last so that no old version becomes current again in between
+        versions
+            .delete(&noncurrent)
+            .await
+            .context("delete noncurrent versions")?;
+        versions
+            .delete(&delete_markers)
+            .await
+            .context("delete delete markers")?;
+        println!(
+            "purged {} noncurrent versions ({}) and {} delete markers",
+            noncurrent.len(),
+            format_size(purged_bytes, DECIMAL),
+            delete_markers.len()
+        );
+        Ok(())
+    }
+
+    async fn fetch_narinfo(&self, key: &str) -> Result<CachedPath> {
+        let narinfo = self
+            .s3
+            .get(&Path::from(key))
+            .await?
+            .bytes()
+            .await
+            .context(format!("fetch {key}"))?;
+        let narinfo = std::str::from_utf8(&narinfo).context(format!("{key} is not utf-8"))?;
+        let narinfo = NarInfo::parse(narinfo).context(format!("parse {key}"))?;
+        Ok(CachedPath {
+            nar_url: narinfo.url.to_string(),
+            references: narinfo
+                .references
+                .iter()
+                .map(|reference| narinfo_key(reference.digest()))
+                .collect(),
+        })
+    }
+
+    /// last modification or, if the access logs say so, a later read
+    fn last_used(&self, meta: &ObjectMeta) -> DateTime<Utc> {
+        match self.last_access.get(meta.location.as_ref()) {
+            Some(&accessed) => accessed.max(meta.last_modified),
+            None => meta.last_modified,
+        }
+    }
+
+    async fn delete(&self, keys: impl Iterator<Item = &str>) -> Result<()> {
+        let locations: Vec<_> = keys.map(|key| Ok(Path::from(key))).collect();
+        self.s3
+            .delete_stream(stream::iter(locations).boxed())
+            .try_collect::<Vec<_>>()
+            .await?;
+        Ok(())
+    }
+}
+
+/// narinfos that can be deleted: those outside the closures of the roots and of the paths that
+/// were used recently. a path that is old itself but still referenced, like a glibc that every
+/// push finds already there, is kept so that no kept narinfo is left with a missing reference
+pub fn stale_narinfos<'a>(
+    cached_paths: &HashMap<&'a str, CachedPath>,
+    roots: &[String],
+    recently_used: &HashSet<&str>,
+) -> Result<HashSet<&'a str>> {
+    let mut keep = HashSet::new();
+    let mut queue = Vec::new();
+    for root in roots {
+        let Some((&key, _)) = cached_paths.get_key_value(root.as_str()) else {
+            bail!("{root} is not in the bucket");
+        };
+        queue.push(key);
+    }
+    queue.extend(
+        cached_paths
+            .keys()
+            .filter(|key| recently_used.contains(*key))
+            .copied(),
+    );
+    while let Some(key) = queue.pop() {
+        if !keep.insert(key) {
+            continue;
+        }
+        // a missing reference is already broken, nothing we can do about it
+        if let Some(cached_path) = cached_paths.get(key) {
+            queue.extend(
+                cached_path
+                    .references
+                    .iter()
+                    .filter_map(|reference| cached_paths.get_key_value(reference.as_str()))
+                    .map(|(&reference, _)| reference),
+            );
+        }
+    }
+    Ok(cached_paths
+        .keys()
+        .filter(|key| !keep.contains(*key))
+        .copied()
+        .collect())
+}
+
+/// the keys a push writes for every path: narinfos at the root of the cache and the nars they
+/// point to, wherever --nar-url-template put them. nars whose narinfo is already gone are
+/// recognized by sitting next to ones that still have one
+pub struct ManagedKeys<'a> {
+    nar_urls: HashSet<&'a str>,
+    // directories nars were found in, never the root of the cache
+    nar_dirs: HashSet<&'a str>,
+}
+
+impl<'a> ManagedKeys<'a> {
+    pub fn new(nar_urls: impl IntoIterator<Item = &'a str>) -> Self {
+        let nar_urls: HashSet<&str> = nar_urls.into_iter().collect();
+        let nar_dirs = nar_urls
+            .iter()
+            .filter_map(|nar_url| nar_url.rsplit_once('/'))
+            .map(|(dir, _)| dir)
+            .collect();
+        Self { nar_urls, nar_dirs }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        if key.ends_with(".narinfo") && !key.contains('/') {
+            return true;
+        }
+        self.nar_urls.contains(key)
+            || key
+                .rsplit_once('/')
+                .is_some_and(|(dir, _)| self.nar_dirs.contains(dir))
+    }
+}
+
+fn narinfo_key(digest: &[u8; 20]) -> String {
+    format!("{}.narinfo", nixbase32::encode(digest))
+}
+
+/// parse an age like `90d`, `12h` or `2w`
+pub fn parse_age(s: &str) -> Result<Duration> {
+    let split = s
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| anyhow!("{s} has no unit, expected one of s, m, h, d, w"))?;
+    let (count, unit) = s.split_at(split);
+    let count: u64 = count
+        .parse()
+        .context(format!("{s} does not start with a number"))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => bail!("unknown unit {unit} in {s}, expected one of s, m, h, d, w"),
+    };
+    Ok(Duration::from_secs(count * seconds))
+}
+
+/// last read of each key in S3 server access logs, relative to `prefix`
+/// https://docs.aws.amazon.com/AmazonS3/latest/userguide/LogFormat.html
+pub fn parse_access_log(contents: &str, prefix: Option<&str>) -> HashMap<String, DateTime<Utc>> {
+    let prefix = prefix.map(|prefix| format!("{}/", prefix.trim_matches('/')));
+    let mut last_access = HashMap::new();
+    for line in contents.lines() {
+        // bucket_owner bucket [time] remote_ip requester request_id operation key ...
+        let Some((time, rest)) = line
+            .split_once('[')
+            .and_then(|(_, rest)| rest.split_once(']'))
+        else {
+            continue;
+        };
+        let Ok(time) = DateTime::parse_from_str(time, "%d/%b/%Y:%H:%M:%S %z") else {
+            continue;
+        };
+        let mut fields = rest.split_whitespace().skip(3);
+        let (Some(operation), Some(key)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        if !matches!(operation, "REST.GET.OBJECT" | "REST.HEAD.OBJECT") {
+            continue;
+        }
+        // keys are url encoded, ours only ever need the slash decoded
+        let key = key.replace("%2F", "/");
+        let key = match &prefix {
+            Some(prefix) => match key.strip_prefix(prefix.as_str()) {
+                Some(key) => key.to_string(),
+                None => continue,
+            },
+            None => key,
+        };
+        let time = time.with_timezone(&Utc);
+        last_access
+            .entry(key)
+            .and_modify(|x: &mut DateTime<Utc>| *x = (*x).max(time))
+            .or_insert(time);
+    }
+    last_access
+}
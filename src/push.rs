@@ -1,214 +1,2158 @@
 use std::{
-    collections::HashSet,
-    fs,
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, IsTerminal, Write},
     path::PathBuf,
     sync::{
-        Arc,
-        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
     },
+    time::{Duration, Instant},
 };
 
-use anyhow::{Context, Result};
-use futures::future::join_all;
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use chrono::Utc;
+use clap::ValueEnum;
+use futures::{StreamExt, TryStreamExt, future::join_all, stream};
 use humansize::{DECIMAL, format_size};
-use nix_compat::narinfo::{self, SigningKey};
-use object_store::aws::{AmazonS3, AmazonS3Builder};
-use tokio::sync::{RwLock, Semaphore, mpsc};
-use tracing::debug;
+use nix_compat::{
+    narinfo::{self, NarInfo, SigningKey, VerifyingKey},
+    store_path::StorePath,
+};
+use object_store::{ObjectStore, aws::Checksum, local::LocalFileSystem, path::Path};
+use serde::Serialize;
+use tokio::{
+    process::Command,
+    sync::{RwLock, Semaphore, mpsc},
+};
+use tracing::{Instrument, Span, debug, debug_span, info_span, instrument, warn};
+use ulid::Ulid;
 use url::Url;
 
-use crate::{PushArgs, path_info::PathInfo, store::Store, uploader::Uploader};
+use crate::{
+    PushArgs,
+    bench::percentile,
+    bloom::{self, BloomFilter},
+    cachix::Cachix,
+    encryption::{self, EncryptionKey},
+    gha,
+    http::UpstreamClient,
+    index::PushIndex,
+    journal::Journal,
+    lease::{Claim, Lease},
+    manifest::{self, ManifestEntry},
+    path_info::{ClosureProgress, DanglingSymlink, PathInfo},
+    rate_limit::RateLimiter,
+    release::{self, RELEASES_PREFIX, Release},
+    request_stats::{CountingStore, RequestCounts, RequestStats},
+    s3_uri::{S3Uri, xml_text},
+    store::Store,
+    uploader::{NarSizeMismatch, UploadOptions, Uploader},
+    upstream_config::UpstreamsFile,
+    upstream_copy::UpstreamPath,
+};
+
+/// What is pushed when no path is given, like `nix build` without arguments
+pub const DEFAULT_INSTALLABLE: &str = ".#default";
+
+/// How many times an upload that failed because the credentials expired is attempted
+const CREDENTIAL_ATTEMPTS: u32 = 3;
+
+/// Error codes s3 answers with once temporary credentials expired
+const EXPIRED_CREDENTIAL_CODES: [&str; 3] =
+    ["ExpiredToken", "TokenRefreshRequired", "RequestExpired"];
+
+/// Paths with more regular files than this draw more from the file descriptor budget, since
+/// streaming their nar is what runs out of file descriptors
+const MANY_FILES: usize = 1000;
+
+/// Multipart uploads that may run at once, each of them already uploads several parts
+/// concurrently
+const LARGE_UPLOADS: usize = 10;
+
+/// File descriptors left out of the default upload budget for upstream checks, the store
+/// connection and the like
+const RESERVED_FDS: u32 = 128;
+
+/// How many times an upstream check is attempted with `UpstreamErrorPolicy::Retry`
+const UPSTREAM_CHECK_ATTEMPTS: u32 = 3;
+
+/// How often progress is printed while closures are computed
+const CLOSURE_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum UpstreamErrorPolicy {
+    /// Treat the path as missing from upstreams and upload it
+    Miss,
+    /// Abort the push
+    Fail,
+    /// Retry the check a few times before aborting the push
+    Retry,
+}
+
+/// what a push would upload, see `--estimate`
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadEstimate {
+    pub paths: usize,
+    pub nar_size: u64,
+    /// `nar_size` scaled by the compression ratio
+    pub estimated_file_size: u64,
+    /// by package name, largest first. only with `--breakdown`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub breakdown: Option<Vec<PackageEstimate>>,
+    /// sent to the bucket while checking, not for cachix
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requests: Option<RequestCounts>,
+}
+
+/// the final report of a push, see `--json`. counters of checks that weren't enabled are left
+/// out
+#[derive(Debug, Clone, Serialize)]
+pub struct PushSummary {
+    pub uploaded: usize,
+    /// compressed size of the uploaded nars
+    pub uploaded_bytes: u64,
+    pub signature_hits: usize,
+    pub upstream_hits: usize,
+    pub already_exist: usize,
+    pub upstream_errors: usize,
+    /// left for a later push by `--max-upload-bytes` or `--max-upload-count`
+    pub deferred: usize,
+    pub deferred_bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index_hits: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since_hits: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub journal_hits: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bloom_skips: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lost_leases: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub also_check_hits: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_built_here: Option<usize>,
+    /// already in the bucket, only lacking our signature
+    pub resigned: usize,
+    pub copied_from_upstream: usize,
+    pub size_mismatch_paths: Vec<String>,
+    pub untrusted_paths: Vec<String>,
+    /// sent to the bucket, not for cachix
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requests: Option<RequestCounts>,
+}
+
+/// the paths of one package in an `UploadEstimate`, e.g. all outputs of every glibc version
+#[derive(Debug, Clone, Serialize)]
+pub struct PackageEstimate {
+    pub name: String,
+    pub paths: usize,
+    pub nar_size: u64,
+}
+
+/// where paths are pushed to
+enum Destination {
+    S3(Arc<dyn ObjectStore>),
+    Cachix(Arc<Cachix>),
+}
+
+impl Destination {
+    /// the bucket, for features that only work with s3
+    fn s3(&self) -> Result<&Arc<dyn ObjectStore>> {
+        match self {
+            Self::S3(s3) => Ok(s3),
+            Self::Cachix(_) => bail!("not supported when pushing to cachix"),
+        }
+    }
+}
 
 pub struct Push {
     upstream_caches: Vec<Url>,
+    // upstreams that aren't reachable over http
+    upstream_stores: Vec<Arc<dyn ObjectStore>>,
+    // caches that paths are looked up in after the destination, in order
+    also_check: Vec<Arc<dyn ObjectStore>>,
+    upstream_error_policy: UpstreamErrorPolicy,
+    // also sends the requests to cachix
+    upstream_client: Arc<UpstreamClient>,
+    max_upload_bytes: Option<u64>,
+    max_upload_count: Option<usize>,
+    // paths that may wait between checking and uploading
+    queue_depth: usize,
+    // KiB of memory that active uploads draw from, and its size
+    memory_budget: Option<(Arc<Semaphore>, u32)>,
+    // file descriptors that active uploads draw from, and its size
+    fd_budget: (Arc<Semaphore>, u32),
+    // multipart uploads that may run at once
+    large_upload_permits: Arc<Semaphore>,
+    upload_options: UploadOptions,
     store_paths: Arc<RwLock<HashSet<PathInfo>>>,
+    copy_from_upstream: bool,
+    resign: bool,
+    // paths missing locally that will be copied from upstreams
+    upstream_paths: Arc<RwLock<HashSet<UpstreamPath>>>,
     signing_key: SigningKey<ed25519_dalek::SigningKey>,
+    verifying_key: VerifyingKey,
     store: Arc<Store>,
-    s3: Arc<AmazonS3>,
+    destination: Destination,
+    // requests sent to the bucket
+    request_stats: Arc<RequestStats>,
     // paths that we skipped cause of a signature match
     signature_hit_count: AtomicUsize,
+    // paths that we skipped cause the local index says they were pushed
+    index_hit_count: AtomicUsize,
+    // paths that we skipped cause an earlier attempt of this push finished them
+    journal_hit_count: AtomicUsize,
+    // paths that we skipped cause the revision given with --since had them
+    since_hit_count: AtomicUsize,
+    // paths that we skipped cause they were substituted and --only-built-here is set
+    not_built_here_count: AtomicUsize,
     // paths that we skipped cause we found it on an upstream
     upstream_hit_count: AtomicUsize,
     // paths that we skipped cause they are already on our cache
     already_exists_count: AtomicUsize,
+    // paths that we skipped cause another runner uploaded them while we waited for the lease
+    lost_lease_count: AtomicUsize,
+    // paths that we skipped cause an --also-check cache has them
+    also_check_hit_count: AtomicUsize,
+    // existence checks we didn't make cause the bloom filter ruled the path out
+    bloom_skip_count: AtomicUsize,
     // paths that we uploaded
     upload_count: AtomicUsize,
+    // paths that we copied from an upstream
+    copied_count: AtomicUsize,
+    // paths already in the bucket that we only added our signature to
+    resigned_count: AtomicUsize,
+    // narinfos in the bucket that only lack our signature, put with it by the upload stage
+    resign_narinfos: Mutex<Vec<(Path, String)>>,
+    // upstream checks that failed with a network error
+    upstream_error_count: AtomicUsize,
+    // paths found and queried while computing closures
+    closure_progress: ClosureProgress,
+    // path info queries that may run at once while computing closures
+    closure_permits: Semaphore,
+    // paths that we didn't upload cause the upload budget was exhausted
+    deferred_count: AtomicUsize,
+    deferred_bytes: AtomicU64,
+    // paths whose streamed nar didn't match the size the store has on record
+    size_mismatch_paths: Mutex<Vec<String>>,
+    // paths that --check-sigs refused to upload
+    unverified_paths: Mutex<Vec<String>>,
+    // list skipped paths by reason after the summary, to stdout or a file
+    show_skipped: Option<Option<PathBuf>>,
+    // reason -> paths skipped for it, only kept with --show-skipped
+    skipped_paths: Mutex<BTreeMap<&'static str, Vec<String>>>,
+    manifest_out: Option<PathBuf>,
+    // emit github actions annotations, summary and outputs
+    gha: bool,
+    // ask before uploading
+    confirm: bool,
+    // only print what would be uploaded
+    estimate: bool,
+    // check that the bucket is writable before uploading, unless --skip-preflight
+    preflight: bool,
+    json: bool,
+    breakdown: bool,
+    compression_ratio: f64,
+    // only push paths built on this machine
+    only_built_here: bool,
+    // only push paths built on this machine or signed by our key or one of these
+    check_sigs: bool,
+    trusted_keys: Vec<VerifyingKey>,
+    // what was pushed by earlier runs
+    index: Option<PushIndex>,
+    // what earlier attempts of this push finished
+    journal: Option<Journal>,
+    // paths pushed by the revision given with --since
+    since: Option<HashSet<StorePath<String>>>,
+    // narinfos that may be in the bucket, only those are looked up
+    bloom: Option<Mutex<BloomFilter>>,
+    // delete orphaned temp objects older than this
+    clean_temp_older_than: Option<Duration>,
+    // leave Deriver and System out of narinfos
+    no_deriver: bool,
+    // only publish our own signature
+    strip_signatures: bool,
+    // key names whose signatures from the store are published, all of them if empty
+    keep_signatures: Vec<String>,
+    // claim a lease in the bucket before uploading a path, taking over leases older than this
+    lease_ttl: Option<Duration>,
+    // everything we published
+    manifest: Mutex<Vec<ManifestEntry>>,
+    // name and revision to publish a release as
+    release: Option<(String, String)>,
+    // outputs given to add_paths, for the release
+    roots: Mutex<Vec<String>>,
 }
 
 impl Push {
     pub async fn new(cli: &PushArgs, store: Store) -> Result<Self> {
-        let mut upstreams = Vec::with_capacity(cli.upstreams.len() + 1);
-        if !cli.no_default_upstream {
-            upstreams.push(
-                Url::parse("https://cache.nixos.org")
-                    .expect("default upstream must be a valid url"),
-            );
+        let mut upstream_caches = Vec::new();
+        let mut upstream_stores = Vec::new();
+        let upstreams_file = upstreams_file(cli)?;
+        for upstream in upstreams(cli, &upstreams_file)? {
+            match upstream.scheme() {
+                // private buckets are checked with their credentials rather than over http
+                "s3" => upstream_stores.push(upstream.as_str().parse::<S3Uri>()?.build(None)?),
+                // e.g. a cache mounted over nfs
+                "file" => {
+                    let dir = upstream
+                        .to_file_path()
+                        .map_err(|_| anyhow!("{upstream} is not a local path"))?;
+                    upstream_stores.push(Arc::new(
+                        LocalFileSystem::new_with_prefix(&dir)
+                            .context(format!("open upstream {}", dir.display()))?,
+                    ));
+                }
+                _ => upstream_caches.push(upstream),
+            }
         }
-        for upstream in &cli.upstreams {
-            upstreams
-                .push(Url::parse(upstream).context(format!("failed to parse {upstream} as url"))?);
+        if let Some(rps) = cli.upstream_rps {
+            ensure!(rps > 0.0, "--upstream-rps must be positive");
         }
+        ensure!(
+            cli.compression_ratio > 0.0,
+            "--compression-ratio must be positive"
+        );
+        ensure!(cli.queue_depth > 0, "--queue-depth must be at least 1");
+        ensure!(
+            !cli.resign || !cli.trusted_public_keys.is_empty(),
+            "--resign needs a --trusted-public-key to check upstream signatures with"
+        );
+        let release = cli.release.as_ref().map(|name| {
+            let rev = cli
+                .release_rev
+                .clone()
+                .unwrap_or_else(|| Utc::now().format("%Y%m%dT%H%M%SZ").to_string());
+            (name.clone(), rev)
+        });
+        if let Some((name, rev)) = &release {
+            // both end up in a key
+            ensure!(
+                !name.is_empty() && !name.contains('/'),
+                "--release must not be empty or contain /"
+            );
+            ensure!(
+                !rev.is_empty() && !rev.contains('/') && rev != "latest",
+                "--release-rev must not be empty, contain / or be latest"
+            );
+        }
+        let key = read_signing_key(cli)?;
+        let (signing_key, verifying_key) = narinfo::parse_keypair(key.as_str())?;
 
-        let key = fs::read_to_string(&cli.signing_key)?;
-        let signing_key = narinfo::parse_keypair(key.as_str())?.0;
+        let s3_uri = S3Uri::from_args(
+            cli.to.as_ref(),
+            cli.bucket.as_deref(),
+            cli.region.as_deref(),
+            cli.endpoint.as_deref(),
+            cli.s3_path_style,
+            cli.allow_http,
+            cli.sts_endpoint.as_deref(),
+        );
+        let endpoint = s3_uri.as_ref().and_then(|x| x.endpoint.clone());
+        let index = match &cli.index {
+            Some(file) => {
+                let destination = match (&s3_uri, &cli.cachix) {
+                    (_, Some(cachix)) => format!("cachix:{cachix}"),
+                    (Some(s3_uri), None) => format!(
+                        "s3://{}/{}?endpoint={}",
+                        s3_uri.bucket,
+                        cli.prefix.as_deref().unwrap_or_default(),
+                        s3_uri.endpoint.as_deref().unwrap_or_default()
+                    ),
+                    (None, None) => bail!("either a bucket or a cachix cache must be given"),
+                };
+                Some(PushIndex::open(file, destination)?)
+            }
+            None => None,
+        };
 
-        let mut s3_builder = AmazonS3Builder::from_env().with_bucket_name(&cli.bucket);
+        let since = match &cli.since {
+            Some(since) => Some(
+                previous_paths(since, &store)
+                    .await
+                    .context(format!("paths of {}", since.display()))?,
+            ),
+            None => None,
+        };
 
-        if let Some(region) = &cli.region {
-            s3_builder = s3_builder.with_region(region);
-        }
-        if let Some(endpoint) = &cli.endpoint {
-            s3_builder = s3_builder.with_endpoint(endpoint);
-        }
+        let upstream_client = Arc::new(upstream_client(cli, &upstreams_file)?);
+        let request_stats = Arc::new(RequestStats::new(cli.max_requests));
+        let destination = match (s3_uri, &cli.cachix) {
+            (_, Some(cachix)) => {
+                Destination::Cachix(Arc::new(Cachix::new(cachix, upstream_client.clone())?))
+            }
+            (Some(s3_uri), None) => {
+                let s3 = s3_uri.build_with_checksum(
+                    cli.prefix.as_deref(),
+                    (!cli.no_checksum).then_some(Checksum::SHA256),
+                )?;
+                Destination::S3(Arc::new(CountingStore::new(s3, request_stats.clone())))
+            }
+            (None, None) => bail!("either a bucket or a cachix cache must be given"),
+        };
+        let bloom = match &destination {
+            Destination::S3(s3) if cli.bloom => {
+                let bloom = bloom::load(s3).await?;
+                if bloom.is_none() {
+                    warn!("no bloom filter in the bucket yet, run nixcp bloom to write one");
+                }
+                bloom.map(Mutex::new)
+            }
+            _ => None,
+        };
 
         Ok(Self {
-            upstream_caches: upstreams,
+            upstream_caches,
+            upstream_stores,
+            also_check: cli
+                .also_check
+                .iter()
+                .map(|s3_uri| s3_uri.build(None))
+                .collect::<Result<_>>()?,
+            upstream_error_policy: cli.upstream_error_policy,
+            upstream_client,
+            max_upload_bytes: cli.max_upload_bytes,
+            max_upload_count: cli.max_upload_count,
+            queue_depth: cli.queue_depth,
+            memory_budget: cli.max_memory.map(|max| {
+                let kib = (max / 1024).min(u32::MAX as u64) as u32;
+                (Arc::new(Semaphore::new(kib as usize)), kib)
+            }),
+            fd_budget: {
+                let fds = cli.max_open_files.unwrap_or_else(default_fd_budget).max(1);
+                (Arc::new(Semaphore::new(fds as usize)), fds)
+            },
+            large_upload_permits: Arc::new(Semaphore::new(LARGE_UPLOADS)),
+            upload_options: UploadOptions {
+                part_concurrency: cli.part_concurrency,
+                direct_put_threshold: cli.direct_put_threshold,
+                spill_threshold: cli.spill_threshold,
+                spill_dir: cli.spill_dir.clone().unwrap_or_else(std::env::temp_dir),
+                zstd_window_log: cli.zstd_long,
+                incompressible_threshold: cli.incompressible_threshold,
+                encryption_key: cli
+                    .encryption_key
+                    .as_deref()
+                    .map(EncryptionKey::read)
+                    .transpose()?,
+                finalize_strategy: cli.finalize_strategy.resolve(endpoint.as_deref()),
+                nar_url_template: cli.nar_url_template.clone(),
+                temp_prefix: Some(cli.temp_prefix.as_str())
+                    .filter(|prefix| !prefix.is_empty())
+                    .map(Path::parse)
+                    .transpose()
+                    .context("parse --temp-prefix")?,
+            },
             store_paths: Arc::new(RwLock::new(HashSet::new())),
+            copy_from_upstream: cli.copy_from_upstream,
+            resign: cli.resign,
+            upstream_paths: Arc::new(RwLock::new(HashSet::new())),
             signing_key,
+            verifying_key,
             store: Arc::new(store),
-            s3: Arc::new(s3_builder.build()?),
+            destination,
+            request_stats,
             signature_hit_count: AtomicUsize::new(0),
+            index_hit_count: AtomicUsize::new(0),
+            journal_hit_count: AtomicUsize::new(0),
+            since_hit_count: AtomicUsize::new(0),
+            not_built_here_count: AtomicUsize::new(0),
             upstream_hit_count: AtomicUsize::new(0),
             already_exists_count: AtomicUsize::new(0),
+            lost_lease_count: AtomicUsize::new(0),
+            also_check_hit_count: AtomicUsize::new(0),
+            bloom_skip_count: AtomicUsize::new(0),
             upload_count: AtomicUsize::new(0),
+            copied_count: AtomicUsize::new(0),
+            resigned_count: AtomicUsize::new(0),
+            resign_narinfos: Mutex::new(Vec::new()),
+            upstream_error_count: AtomicUsize::new(0),
+            closure_progress: ClosureProgress::default(),
+            closure_permits: Semaphore::new(cli.closure_concurrency),
+            deferred_count: AtomicUsize::new(0),
+            deferred_bytes: AtomicU64::new(0),
+            size_mismatch_paths: Mutex::new(Vec::new()),
+            unverified_paths: Mutex::new(Vec::new()),
+            show_skipped: cli.show_skipped.clone(),
+            skipped_paths: Mutex::new(BTreeMap::new()),
+            manifest_out: cli.manifest_out.clone(),
+            gha: cli.gha,
+            confirm: cli.confirm,
+            estimate: cli.estimate,
+            preflight: !cli.skip_preflight,
+            json: cli.json,
+            breakdown: cli.breakdown,
+            compression_ratio: cli.compression_ratio,
+            only_built_here: cli.only_built_here,
+            check_sigs: cli.check_sigs,
+            trusted_keys: cli
+                .trusted_public_keys
+                .iter()
+                .map(|key| {
+                    VerifyingKey::parse(key).context(format!("invalid trusted public key {key}"))
+                })
+                .collect::<Result<_>>()?,
+            index,
+            journal: cli.journal.as_deref().map(Journal::open).transpose()?,
+            since,
+            bloom,
+            clean_temp_older_than: (!cli.no_clean_temp).then_some(cli.clean_temp_older_than),
+            no_deriver: cli.no_deriver,
+            strip_signatures: cli.strip_signatures,
+            keep_signatures: cli.keep_signatures.clone(),
+            lease_ttl: cli.coordinate.then_some(cli.lease_ttl),
+            manifest: Mutex::new(Vec::new()),
+            release,
+            roots: Mutex::new(Vec::new()),
         })
     }
 
+    /// write and delete a small object to make sure credentials and bucket policy allow
+    /// uploading before doing any expensive work
+    pub async fn preflight(&self) -> Result<()> {
+        let s3 = match &self.destination {
+            Destination::S3(s3) => s3,
+            Destination::Cachix(cachix) => return cachix.preflight().await,
+        };
+        // planning must not write to the bucket, --confirm checks writing once answered
+        if self.estimate || self.confirm {
+            let key = Path::from("nix-cache-info");
+            return match s3.head(&key).await {
+                Ok(_) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+                Err(e) => Err(e).context("read from bucket"),
+            };
+        }
+        self.check_writable(s3).await
+    }
+
+    /// put and delete a sentinel under the temp prefix, where a lifecycle rule can clean up
+    /// what versioning keeps of it
+    async fn check_writable(&self, s3: &Arc<dyn ObjectStore>) -> Result<()> {
+        let name = format!("nixcp-preflight-{}", Ulid::new());
+        let sentinel = match &self.upload_options.temp_prefix {
+            Some(temp_prefix) => temp_prefix.child(name),
+            None => Path::parse(name)?,
+        };
+        debug!("preflight: writing {sentinel}");
+        s3.put(&sentinel, "nixcp preflight check".into())
+            .await
+            .context("write to bucket")?;
+        s3.delete(&sentinel).await.context("delete from bucket")?;
+        Ok(())
+    }
+
     pub async fn add_paths(&'static self, paths: Vec<PathBuf>) -> Result<()> {
+        if self.gha {
+            gha::group("computing closures");
+        }
+        let start = Instant::now();
+        // closures of large inputs can take minutes, show that something is happening
+        let progress = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CLOSURE_PROGRESS_INTERVAL);
+            // the first tick completes right away
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                println!(
+                    "computing closures: {} paths found, {} path info queries outstanding",
+                    self.closure_progress.discovered(),
+                    self.closure_progress.outstanding()
+                );
+            }
+        });
         let mut futs = Vec::with_capacity(paths.len());
         for path in paths {
             let store_paths = self.store_paths.clone();
+            let upstream_paths = self.upstream_paths.clone();
             let store = self.store.clone();
 
-            futs.push(tokio::spawn(async move {
-                let path_info = PathInfo::from_derivation(path.as_path(), &store)
-                    .await
-                    .context("get path info for path")?;
-                debug!("path-info for {path:?}: {path_info:?}");
+            let span = debug_span!("closure", path = %path.display());
+            futs.push(tokio::spawn(
+                async move {
+                    if self.copy_from_upstream
+                        && !path.exists()
+                        && let Ok(store_path) =
+                            StorePath::from_absolute_path(path.as_os_str().as_encoded_bytes())
+                    {
+                        let root = store_path.to_absolute_path();
+                        let closure = UpstreamPath::closure(
+                            store_path,
+                            &self.upstream_caches,
+                            &self.upstream_client,
+                        )
+                        .await
+                        .context("closure from upstream")?;
+                        self.roots.lock().unwrap().push(root);
+                        upstream_paths.write().await.extend(closure);
+                        return Ok(());
+                    }
 
-                store_paths.write().await.extend(
-                    path_info
-                        .get_closure(&store)
+                    let path_info = match PathInfo::from_derivation(path.as_path(), &store).await {
+                        Ok(path_info) => path_info,
+                        // carry on with the other paths
+                        Err(e) if e.is::<DanglingSymlink>() => {
+                            println!("skipping: {e}");
+                            return Ok(());
+                        }
+                        Err(e) => return Err(e.context("get path info for path")),
+                    };
+                    debug!("path-info for {path:?}: {path_info:?}");
+                    let closure = path_info
+                        .get_closure_with_progress(
+                            &store,
+                            &self.closure_progress,
+                            &self.closure_permits,
+                        )
                         .await
-                        .context("closure from path info")?,
+                        .context("closure from path info")?;
+                    if self.release.is_some() {
+                        self.roots
+                            .lock()
+                            .unwrap()
+                            .extend(release_roots(&path, &path_info, &closure));
+                    }
+                    store_paths.write().await.extend(closure);
+                    Ok(())
+                }
+                .instrument(span),
+            ));
+        }
+        let closures = join_all(futs).await;
+        progress.abort();
+        closures.into_iter().flatten().collect::<Result<Vec<_>>>()?;
+        println!(
+            "found {} store paths in {:.1?}",
+            self.store_paths.read().await.len(),
+            start.elapsed()
+        );
+        let upstream_paths = self.upstream_paths.read().await.len();
+        if upstream_paths > 0 {
+            println!("found {upstream_paths} store paths to copy from upstream");
+        }
+        if self.gha {
+            gha::end_group();
+        }
+
+        Ok(())
+    }
+
+    pub async fn run(&'static self) -> Result<()> {
+        if self.estimate {
+            let estimate = self.estimate_uploads(&self.plan_uploads().await?);
+            if self.json {
+                println!("{}", serde_json::to_string(&estimate)?);
+            } else {
+                println!("paths to upload: {}", estimate.paths);
+                println!("nar size: {}", format_size(estimate.nar_size, DECIMAL));
+                println!(
+                    "estimated upload size: {}",
+                    format_size(estimate.estimated_file_size, DECIMAL)
                 );
+                if let Some(requests) = estimate.requests {
+                    println!("s3 requests: {requests}");
+                }
+                for package in estimate.breakdown.iter().flatten() {
+                    println!(
+                        "  {}: {} paths, {}",
+                        package.name,
+                        package.paths,
+                        format_size(package.nar_size, DECIMAL)
+                    );
+                }
+            }
+            return Ok(());
+        }
+        let (tx, rx) = mpsc::channel(self.queue_depth);
+        let filter = if self.confirm {
+            let Some(planned) = self.confirm_uploads().await? else {
+                println!("not uploading anything");
+                return Ok(());
+            };
+            if let (true, Destination::S3(s3)) = (self.preflight, &self.destination) {
+                self.check_writable(s3)
+                    .await
+                    .context("preflight check of bucket permissions")?;
+            }
+            tokio::spawn(async move {
+                for path in planned {
+                    queue(&tx, path).await?;
+                }
+                Ok(())
+            })
+        } else {
+            tokio::spawn(self.filter_from_upstream(tx))
+        };
+        // not before --confirm was answered, declining must leave the bucket as it was
+        let clean_temp = tokio::spawn(self.clean_temp());
+        let upload = tokio::spawn(self.upload(rx));
+
+        if self.gha {
+            gha::group("uploading");
+        }
+        let res = async {
+            let (filter_res, upload_res) = tokio::join!(filter, upload);
+            // the filter stops once uploading did, so the upload error is the one that says why
+            upload_res.context("upload stage panicked")??;
+            filter_res.context("filter stage panicked")??;
+            self.copy_upstream_paths().await
+        }
+        .await;
+        if self.gha {
+            gha::end_group();
+            if let Err(e) = &res {
+                gha::error(&format!("{e:#}"));
+            }
+        }
+
+        // leftovers are harmless, don't fail the push over them
+        match clean_temp.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("failed to clean up orphaned temp objects: {e:#}"),
+            Err(e) => warn!("cleaning up orphaned temp objects panicked: {e}"),
+        }
+
+        // write whatever was published even if the push failed part way
+        if let Some(index) = &self.index {
+            index.save().context("save index")?;
+        }
+        let published =
+            self.upload_count.load(Ordering::Relaxed) + self.copied_count.load(Ordering::Relaxed);
+        if let (Some(bloom), Destination::S3(s3)) = (&self.bloom, &self.destination)
+            && published > 0
+        {
+            // a push running at the same time may lose its additions, which only means its
+            // paths are checked or uploaded again
+            let bloom = bloom.lock().unwrap().clone();
+            bloom::save(s3, &bloom).await.context("save bloom filter")?;
+        }
+        // nothing left to resume
+        if let Some(journal) = &self.journal
+            && res.is_ok()
+        {
+            journal.remove()?;
+        }
+        if let Some(manifest_out) = &self.manifest_out {
+            manifest::write_ndjson(manifest_out, &self.manifest.lock().unwrap())
+                .context("write manifest")?;
+        }
+        if let (Some((name, rev)), Destination::S3(s3)) = (&self.release, &self.destination)
+            && res.is_ok()
+        {
+            let release = Release {
+                name: name.clone(),
+                rev: rev.clone(),
+                pushed_at: Utc::now().to_rfc3339(),
+                roots: self.roots.lock().unwrap().clone(),
+                manifest: self.manifest.lock().unwrap().clone(),
+            };
+            release::publish(s3, &release)
+                .await
+                .context(format!("publish release {name} {rev}"))?;
+            println!("published {RELEASES_PREFIX}/{name}/{rev}.json");
+        }
+        self.print_summary()?;
+        self.print_skipped()?;
+        res
+    }
+
+    /// delete temp objects that interrupted pushes left behind
+    async fn clean_temp(&'static self) -> Result<()> {
+        let (Some(max_age), Destination::S3(s3)) = (self.clean_temp_older_than, &self.destination)
+        else {
+            return Ok(());
+        };
+        let cutoff = Utc::now() - chrono::Duration::from_std(max_age)?;
+        // temp objects are named by a bare ulid, so they are easy to tell from everything else
+        let orphans: Vec<_> = s3
+            .list_with_delimiter(self.upload_options.temp_prefix.as_ref())
+            .await
+            .context("list temp objects")?
+            .objects
+            .into_iter()
+            .filter(|meta| {
+                meta.last_modified < cutoff
+                    && meta
+                        .location
+                        .filename()
+                        .is_some_and(|name| Ulid::from_string(name).is_ok())
+            })
+            .map(|meta| Ok(meta.location))
+            .collect();
+        if orphans.is_empty() {
+            return Ok(());
+        }
+        println!("deleting {} orphaned temp objects", orphans.len());
+        s3.delete_stream(stream::iter(orphans).boxed())
+            .try_collect::<Vec<_>>()
+            .await?;
+        Ok(())
+    }
+
+    /// paths that would be uploaded, without uploading anything
+    async fn plan_uploads(&'static self) -> Result<Vec<PathInfo>> {
+        let store_path_count = self.store_paths.read().await.len();
+        let (tx, mut rx) = mpsc::channel(store_path_count.max(1));
+        self.filter_from_upstream(tx).await?;
+        let mut planned = Vec::new();
+        while let Some(path) = rx.recv().await {
+            planned.push(path);
+        }
+        Ok(planned)
+    }
+
+    fn estimate_uploads(&self, planned: &[PathInfo]) -> UploadEstimate {
+        let nar_size: u64 = planned.iter().map(|x| x.nar_size).sum();
+        UploadEstimate {
+            paths: planned.len(),
+            nar_size,
+            estimated_file_size: (nar_size as f64 * self.compression_ratio) as u64,
+            breakdown: self.breakdown.then(|| breakdown(planned)),
+            requests: self.s3_requests(),
+        }
+    }
+
+    /// filter everything up front and ask whether to upload what's left. `None` if the answer
+    /// is no
+    async fn confirm_uploads(&'static self) -> Result<Option<Vec<PathInfo>>> {
+        if !io::stdin().is_terminal() {
+            bail!("--confirm needs an interactive terminal");
+        }
+        let planned = self.plan_uploads().await?;
+        let estimate = self.estimate_uploads(&planned);
+        let prompt = format!(
+            "upload {} paths ({}, about {} compressed)? [y/N] ",
+            estimate.paths,
+            format_size(estimate.nar_size, DECIMAL),
+            format_size(estimate.estimated_file_size, DECIMAL)
+        );
+        let confirmed = tokio::task::spawn_blocking(move || {
+            eprint!("{prompt}");
+            io::stderr().flush()?;
+            let mut answer = String::new();
+            io::stdin().read_line(&mut answer)?;
+            io::Result::Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+        })
+        .await??;
+        Ok(confirmed.then_some(planned))
+    }
+
+    /// copy paths that are only on upstreams into our bucket
+    async fn copy_upstream_paths(&'static self) -> Result<()> {
+        let upstream_paths = self.upstream_paths.read().await.clone();
+        if upstream_paths.is_empty() {
+            return Ok(());
+        }
+        let s3 = self.destination.s3()?;
+        let permits = Arc::new(Semaphore::new(10));
+
+        let mut handles = Vec::new();
+        for upstream_path in upstream_paths {
+            let permits = permits.clone();
+            handles.push(tokio::spawn(async move {
+                let _permit = permits.acquire().await.unwrap();
+                let path = upstream_path.path.to_absolute_path();
+                let narinfo_path = upstream_path.narinfo_path();
+                match s3.head(&narinfo_path).await {
+                    Ok(_) => {
+                        debug!("skip {} (already exists)", path);
+                        return Ok(());
+                    }
+                    Err(object_store::Error::NotFound { .. }) => {}
+                    Err(e) => {
+                        return Err(anyhow::Error::new(e).context(format!("check {narinfo_path}")));
+                    }
+                }
+                println!("copying from upstream: {path}");
+                let resign = self
+                    .resign
+                    .then_some((&self.signing_key, self.trusted_keys.as_slice()));
+                let entry = upstream_path
+                    .copy(s3.clone(), resign, &self.upstream_client)
+                    .await
+                    .context(format!("copy {path} from upstream"))?;
+                self.copied_count.fetch_add(1, Ordering::Relaxed);
+                if let Some(bloom) = &self.bloom {
+                    bloom.lock().unwrap().insert(upstream_path.path.digest());
+                }
+                self.manifest.lock().unwrap().push(entry);
                 Ok(())
             }));
         }
-        join_all(futs)
+        join_all(handles)
             .await
             .into_iter()
             .flatten()
             .collect::<Result<Vec<_>>>()?;
-        println!("found {} store paths", self.store_paths.read().await.len());
 
         Ok(())
     }
 
-    pub async fn run(&'static self) -> Result<()> {
-        let (tx, rx) = mpsc::channel(1);
-        let filter = tokio::spawn(self.filter_from_upstream(tx));
-        let upload = tokio::spawn(self.upload(rx));
-
-        filter.await?;
-        upload.await??;
+    /// add the closures of everything gc roots point to, or every valid path in the store if
+    /// `all` is set
+    pub async fn add_store(&'static self, all: bool) -> Result<()> {
+        let store_paths = if all {
+            self.store.query_all_valid_paths().await?
+        } else {
+            let mut closure = HashSet::new();
+            for root in self.store.find_roots().await.context("find gc roots")? {
+                // already covered by the closure of another root
+                if closure.contains(&root) {
+                    continue;
+                }
+                closure.extend(self.store.compute_fs_closure(root).await?);
+            }
+            closure.into_iter().collect()
+        };
+        let path_infos = join_all(
+            store_paths
+                .into_iter()
+                .map(|path| self.store.query_path_info(path)),
+        )
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+        self.store_paths.write().await.extend(path_infos);
+        println!("found {} store paths", self.store_paths.read().await.len());
         Ok(())
     }
 
     /// filter paths that are on upstream and send to `tx`
-    async fn filter_from_upstream(&'static self, tx: mpsc::Sender<PathInfo>) {
+    async fn filter_from_upstream(&'static self, tx: mpsc::Sender<PathInfo>) -> Result<()> {
         let mut handles = Vec::new();
         let store_paths = self.store_paths.read().await.clone();
         // limit number of inflight requests
         let inflight_permits = Arc::new(Semaphore::new(32));
 
         for path in store_paths.into_iter() {
+            if self
+                .index
+                .as_ref()
+                .is_some_and(|index| index.contains(&path))
+            {
+                self.skip(&path, "in local index");
+                self.index_hit_count.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            if self
+                .journal
+                .as_ref()
+                .is_some_and(|journal| journal.contains(&path.path))
+            {
+                self.skip(&path, "in journal");
+                self.journal_hit_count.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            if self
+                .since
+                .as_ref()
+                .is_some_and(|since| since.contains(&path.path))
+            {
+                self.skip(&path, "in --since");
+                self.since_hit_count.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            if self.only_built_here && !path.ultimate {
+                self.skip(&path, "not built here");
+                self.not_built_here_count.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
             if path.check_upstream_signature(&self.upstream_caches) {
-                debug!("skip {} (signature match)", path.absolute_path());
+                self.skip(&path, "signature match");
                 self.signature_hit_count.fetch_add(1, Ordering::Relaxed);
                 continue;
             }
             handles.push({
                 let tx = tx.clone();
                 let inflight_permits = inflight_permits.clone();
-                tokio::spawn(async move {
-                    let _permit = inflight_permits.acquire().await.unwrap();
-                    if !path.check_upstream_hit(&self.upstream_caches).await {
-                        if path.check_if_already_exists(&self.s3).await {
-                            debug!("skip {} (already exists)", path.absolute_path());
-                            self.already_exists_count.fetch_add(1, Ordering::Relaxed);
+                let span = path_span(&path);
+                let name = path.absolute_path();
+                let handle = tokio::spawn(
+                    async move {
+                        let _permit = inflight_permits.acquire().await.unwrap();
+                        if !self.check_upstream_hit(&path).await? {
+                            if self.check_if_already_exists(&path).await.context(format!(
+                                "check {} in the destination",
+                                path.absolute_path()
+                            ))? {
+                                self.skip(&path, "already exists");
+                                self.already_exists_count.fetch_add(1, Ordering::Relaxed);
+                                if let Some(index) = &self.index {
+                                    index.insert(index.key(&path));
+                                }
+                                if let Some(journal) = &self.journal {
+                                    journal
+                                        .record(&path.path)
+                                        .context(format!("record {}", path.absolute_path()))?;
+                                }
+                            } else if self.check_also_check(&path).await? {
+                                self.skip(&path, "in an --also-check cache");
+                                self.also_check_hit_count.fetch_add(1, Ordering::Relaxed);
+                            } else if self.check_sigs && !self.trusted(&path) {
+                                warn!(
+                                    "refusing to upload {}: no trusted signature",
+                                    path.absolute_path()
+                                );
+                                self.skip(&path, "no trusted signature");
+                                self.unverified_paths
+                                    .lock()
+                                    .unwrap()
+                                    .push(path.absolute_path());
+                            } else {
+                                queue(&tx, path).await?;
+                            }
                         } else {
-                            tx.send(path).await.unwrap();
+                            self.skip(&path, "upstream hit");
+                            self.upstream_hit_count.fetch_add(1, Ordering::Relaxed);
                         }
-                    } else {
-                        debug!("skip {} (upstream hit)", path.absolute_path());
-                        self.upstream_hit_count.fetch_add(1, Ordering::Relaxed);
+                        Ok(())
                     }
-                })
+                    .instrument(span),
+                );
+                (name, handle)
             });
         }
 
-        join_all(handles)
-            .await
-            .into_iter()
-            .collect::<std::result::Result<(), _>>()
-            .unwrap();
+        join_all(handles.into_iter().map(|(name, handle)| async move {
+            handle.await.context(format!("checking {name} panicked"))?
+        }))
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+        Ok(())
+    }
+
+    /// note that `path` isn't uploaded for `reason`
+    fn skip(&self, path: &PathInfo, reason: &'static str) {
+        debug!("skip {} ({reason})", path.absolute_path());
+        if self.show_skipped.is_some() {
+            self.skipped_paths
+                .lock()
+                .unwrap()
+                .entry(reason)
+                .or_default()
+                .push(path.absolute_path());
+        }
+    }
+
+    /// print the skipped paths by reason, or write them to the file given to --show-skipped
+    fn print_skipped(&self) -> Result<()> {
+        let Some(out) = &self.show_skipped else {
+            return Ok(());
+        };
+        let mut list = String::new();
+        for (reason, paths) in self.skipped_paths.lock().unwrap().iter_mut() {
+            paths.sort();
+            list.push_str(&format!("skipped because {reason} ({}):\n", paths.len()));
+            for path in paths.iter() {
+                list.push_str(&format!("  {path}\n"));
+            }
+        }
+        match out {
+            Some(file) => fs::write(file, list).context(format!("write {file:?}"))?,
+            None => print!("{list}"),
+        }
+        Ok(())
+    }
+
+    /// whether --check-sigs lets `path` be uploaded
+    fn trusted(&self, path: &PathInfo) -> bool {
+        if path.ultimate {
+            return true;
+        }
+        let keys: Vec<_> = std::iter::once(&self.verifying_key)
+            .chain(&self.trusted_keys)
+            .collect();
+        path.has_valid_signature(&keys)
+    }
+
+    /// whether a signature the store has for a path should be published alongside ours
+    fn keep_signature(&self, signature: &str) -> bool {
+        let name = signature.split_once(':').map_or(signature, |x| x.0);
+        // ours is added when signing
+        if self.strip_signatures || name == self.verifying_key.name() {
+            return false;
+        }
+        self.keep_signatures.is_empty() || self.keep_signatures.iter().any(|x| x == name)
+    }
+
+    async fn check_if_already_exists(&self, path: &PathInfo) -> Result<bool> {
+        match &self.destination {
+            Destination::S3(s3) => self.check_if_already_in_bucket(s3, path).await,
+            Destination::Cachix(cachix) => cachix.check_if_already_exists(path).await,
+        }
+    }
+
+    /// whether `path` is already in the bucket. if its narinfo lacks our signature, e.g. because
+    /// the cache switched keys, the signature is added to it by the upload stage rather than
+    /// uploading the nar again
+    async fn check_if_already_in_bucket(
+        &self,
+        s3: &Arc<dyn ObjectStore>,
+        path: &PathInfo,
+    ) -> Result<bool> {
+        if let Some(bloom) = &self.bloom
+            && !bloom.lock().unwrap().contains(path.path.digest())
+        {
+            self.bloom_skip_count.fetch_add(1, Ordering::Relaxed);
+            return Ok(false);
+        }
+        let narinfo_path = path.narinfo_path();
+        let bytes = match s3.get(&narinfo_path).await {
+            Ok(res) => res.bytes().await,
+            Err(object_store::Error::NotFound { .. }) => return Ok(false),
+            Err(e) => Err(e),
+        }
+        .context(format!("fetch {narinfo_path}"))?;
+        let text = std::str::from_utf8(&bytes).ok();
+        let parsed = text.and_then(|narinfo| NarInfo::parse(narinfo).ok());
+        let Some(mut narinfo) = parsed else {
+            warn!("{narinfo_path} in the bucket is invalid, uploading again");
+            return Ok(false);
+        };
+        // signing what is there would vouch for a nar that isn't the one we have
+        if narinfo.nar_hash != path.nar_hash || narinfo.nar_size != path.nar_size {
+            warn!("{narinfo_path} in the bucket is for a different nar, uploading again");
+            return Ok(false);
+        }
+
+        let fingerprint = narinfo.fingerprint();
+        if narinfo
+            .signatures
+            .iter()
+            .any(|signature| self.verifying_key.verify(&fingerprint, signature))
+        {
+            return Ok(true);
+        }
+        debug!("{narinfo_path} lacks our signature");
+        narinfo.add_signature(&self.signing_key);
+        // nix-compat drops the field that marks encrypted nars, put it back
+        let narinfo = encryption::with_narinfo_field(
+            narinfo.to_string(),
+            text.and_then(encryption::narinfo_field),
+        );
+        self.resign_narinfos
+            .lock()
+            .unwrap()
+            .push((narinfo_path, narinfo));
+        Ok(true)
+    }
+
+    /// put the narinfos that only lacked our signature back with it
+    async fn put_resigned(&self) -> Result<()> {
+        let narinfos = std::mem::take(&mut *self.resign_narinfos.lock().unwrap());
+        if narinfos.is_empty() {
+            return Ok(());
+        }
+        let s3 = self.destination.s3()?;
+        stream::iter(narinfos)
+            .map(|(narinfo_path, narinfo)| async move {
+                debug!("adding our signature to {narinfo_path}");
+                s3.put(&narinfo_path, narinfo.into())
+                    .await
+                    .context(format!("put {narinfo_path}"))?;
+                self.resigned_count.fetch_add(1, Ordering::Relaxed);
+                Ok::<_, anyhow::Error>(())
+            })
+            .buffer_unordered(32)
+            .try_collect::<Vec<_>>()
+            .await?;
+        Ok(())
+    }
+
+    /// whether one of the --also-check caches has `path`, asking them in order
+    async fn check_also_check(&self, path: &PathInfo) -> Result<bool> {
+        let narinfo_path = path.narinfo_path();
+        for cache in &self.also_check {
+            match cache.head(&narinfo_path).await {
+                Ok(_) => return Ok(true),
+                Err(object_store::Error::NotFound { .. }) => {}
+                Err(e) => return Err(e).context(format!("check {narinfo_path} in {cache}")),
+            }
+        }
+        Ok(false)
+    }
+
+    /// check upstreams for `path`, handling network errors according to the upstream error policy
+    #[instrument(level = "debug", skip_all, fields(path = %path.absolute_path()))]
+    async fn check_upstream_hit(&self, path: &PathInfo) -> Result<bool> {
+        let mut attempt = 1;
+        loop {
+            let res = match path
+                .check_upstream_store_hit(&self.upstream_stores, self.upstream_client.stats())
+                .await
+            {
+                Ok(true) => Ok(true),
+                store_res => match path
+                    .check_upstream_hit(&self.upstream_caches, &self.upstream_client)
+                    .await
+                {
+                    // an error from a store only matters if no other upstream has the path
+                    Ok(false) => store_res,
+                    http_res => http_res,
+                },
+            };
+            let err = match res {
+                Ok(hit) => return Ok(hit),
+                Err(e) => e,
+            };
+            self.upstream_error_count.fetch_add(1, Ordering::Relaxed);
+            match self.upstream_error_policy {
+                UpstreamErrorPolicy::Miss => {
+                    warn!("{err:#}; treating {} as a miss", path.absolute_path());
+                    return Ok(false);
+                }
+                UpstreamErrorPolicy::Retry if attempt < UPSTREAM_CHECK_ATTEMPTS => {
+                    warn!("{err:#}; retrying (attempt {attempt}/{UPSTREAM_CHECK_ATTEMPTS})");
+                    tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+                    attempt += 1;
+                }
+                UpstreamErrorPolicy::Fail | UpstreamErrorPolicy::Retry => {
+                    return Err(err.context(format!(
+                        "upstream check for {} failed",
+                        path.absolute_path()
+                    )));
+                }
+            }
+        }
     }
 
     async fn upload(&'static self, mut rx: mpsc::Receiver<PathInfo>) -> Result<()> {
         let mut uploads = Vec::new();
-        let permits = Arc::new(Semaphore::new(10));
+        let mut queued_bytes = 0;
+
+        // paths another runner holds the lease for, dispatched once everything else is, so
+        // that waiting for them doesn't hold up the rest
+        let mut contended = Vec::new();
 
         loop {
-            let permits = permits.clone();
+            let (mut path_to_upload, lease) = match rx.recv().await {
+                Some(mut path_to_upload) => {
+                    if self.no_deriver {
+                        path_to_upload.deriver = None;
+                    }
+                    path_to_upload
+                        .signatures
+                        .retain(|signature| self.keep_signature(signature));
+                    if !self.within_budget(
+                        uploads.len() + contended.len(),
+                        queued_bytes,
+                        path_to_upload.nar_size,
+                    ) {
+                        debug!(
+                            "defer {} (upload budget exhausted)",
+                            path_to_upload.absolute_path()
+                        );
+                        self.deferred_count.fetch_add(1, Ordering::Relaxed);
+                        self.deferred_bytes
+                            .fetch_add(path_to_upload.nar_size, Ordering::Relaxed);
+                        continue;
+                    }
+                    queued_bytes += path_to_upload.nar_size;
+                    // claimed before taking permits, so that a contended path doesn't hold them
+                    let lease = match (self.lease_ttl, &self.destination) {
+                        (Some(ttl), Destination::S3(s3)) => {
+                            match Lease::try_claim(s3, &path_to_upload, ttl).await.context(
+                                format!("claim lease for {}", path_to_upload.absolute_path()),
+                            )? {
+                                Claim::Claimed(lease) => Some(lease),
+                                Claim::Held => {
+                                    contended.push(path_to_upload);
+                                    continue;
+                                }
+                                Claim::Uploaded => {
+                                    queued_bytes -= path_to_upload.nar_size;
+                                    self.lost_lease(&path_to_upload);
+                                    continue;
+                                }
+                            }
+                        }
+                        _ => None,
+                    };
+                    (path_to_upload, lease)
+                }
+                None => match (contended.pop(), self.lease_ttl, &self.destination) {
+                    (Some(path_to_upload), Some(ttl), Destination::S3(s3)) => {
+                        match Lease::claim(s3, &path_to_upload, ttl)
+                            .await
+                            .context(format!(
+                                "claim lease for {}",
+                                path_to_upload.absolute_path()
+                            ))? {
+                            Some(lease) => (path_to_upload, Some(lease)),
+                            None => {
+                                self.lost_lease(&path_to_upload);
+                                continue;
+                            }
+                        }
+                    }
+                    _ => {
+                        join_all(uploads)
+                            .await
+                            .into_iter()
+                            .flatten()
+                            .collect::<Result<Vec<_>>>()?;
+                        break;
+                    }
+                },
+            };
 
-            if let Some(path_to_upload) = rx.recv().await {
-                uploads.push(tokio::spawn({
-                    // large uploads will be concurrently uploaded with multipart anyway so don't spawn
-                    // too much of them
-                    let permit = if path_to_upload.nar_size > 15 * 1024 * 1024 {
-                        Some(permits.acquire_owned().await.unwrap())
+            uploads.push(tokio::spawn({
+                let large_upload_permit =
+                    if self.upload_options.is_multipart(path_to_upload.nar_size) {
+                        Some(
+                            self.large_upload_permits
+                                .clone()
+                                .acquire_owned()
+                                .await
+                                .unwrap(),
+                        )
                     } else {
                         None
                     };
-                    println!(
-                        "uploading: {} (size: {})",
-                        path_to_upload.absolute_path(),
-                        format_size(path_to_upload.nar_size, DECIMAL)
-                    );
-                    let uploader = Uploader::new(&self.signing_key, path_to_upload)?;
-                    let s3 = self.s3.clone();
-                    let store = self.store.clone();
-                    async move {
-                        let res = uploader.upload(s3, store).await;
-                        drop(permit);
-                        self.upload_count.fetch_add(1, Ordering::Relaxed);
-                        res
+                let fd_permit = {
+                    let (fd_budget, budget_fds) = &self.fd_budget;
+                    let many_files = self.has_many_files(&path_to_upload).await;
+                    let fds = self
+                        .upload_options
+                        .fd_estimate(path_to_upload.nar_size, many_files)
+                        .min(*budget_fds);
+                    fd_budget.clone().acquire_many_owned(fds).await.unwrap()
+                };
+                let memory_permit = match &self.memory_budget {
+                    Some((memory_budget, budget_kib)) => {
+                        let kib = self
+                            .upload_options
+                            .memory_estimate(path_to_upload.nar_size)
+                            .div_ceil(1024);
+                        // cap at the whole budget so that a huge upload can run on its own
+                        let kib = kib.min(*budget_kib as u64) as u32;
+                        Some(memory_budget.clone().acquire_many_owned(kib).await.unwrap())
                     }
-                }));
-            } else {
-                join_all(uploads)
-                    .await
-                    .into_iter()
-                    .flatten()
-                    .collect::<Result<Vec<_>>>()?;
-
-                println!("uploaded: {}", self.upload_count.load(Ordering::Relaxed));
+                    None => None,
+                };
                 println!(
-                    "skipped because of signature match: {}",
-                    self.signature_hit_count.load(Ordering::Relaxed)
+                    "uploading: {} (size: {})",
+                    path_to_upload.absolute_path(),
+                    format_size(path_to_upload.nar_size, DECIMAL)
                 );
-                println!(
-                    "skipped because of upstream hit: {}",
-                    self.upstream_hit_count.load(Ordering::Relaxed)
-                );
-                println!(
-                    "skipped because already exist: {}",
-                    self.already_exists_count.load(Ordering::Relaxed)
+                let store = self.store.clone();
+                let index_key = self.index.as_ref().map(|index| index.key(&path_to_upload));
+                let store_path = path_to_upload.path.clone();
+                let span = path_span(&path_to_upload);
+                async move {
+                    if let Some(deriver) = &path_to_upload.deriver {
+                        // only nice to have, the narinfo is fine without it
+                        path_to_upload.system = store
+                            .derivation_system(deriver.clone())
+                            .await
+                            .unwrap_or_else(|e| {
+                                debug!("{e:#}");
+                                None
+                            });
+                    }
+                    let res = match &self.destination {
+                        Destination::S3(s3) => {
+                            let mut attempt = 1;
+                            let res = loop {
+                                let res = Uploader::new(
+                                    &self.signing_key,
+                                    &self.verifying_key,
+                                    path_to_upload.clone(),
+                                    &self.upload_options,
+                                )?
+                                .upload(s3.clone(), store.clone())
+                                .await;
+                                match res {
+                                    // give whatever renews the credentials a chance to
+                                    // catch up
+                                    Err(e)
+                                        if attempt < CREDENTIAL_ATTEMPTS
+                                            && is_expired_credentials(&e) =>
+                                    {
+                                        warn!("{e:#}; retrying with fresh credentials");
+                                        tokio::time::sleep(Duration::from_secs(
+                                            30 * attempt as u64,
+                                        ))
+                                        .await;
+                                        attempt += 1;
+                                    }
+                                    res => break res,
+                                }
+                            };
+                            if let Some(lease) = lease {
+                                lease.release().await;
+                            }
+                            res
+                        }
+                        Destination::Cachix(cachix) => {
+                            cachix
+                                .upload(&path_to_upload, store, Some(&self.signing_key))
+                                .await
+                        }
+                    };
+                    drop(large_upload_permit);
+                    drop(fd_permit);
+                    drop(memory_permit);
+                    match res {
+                        Err(e) => match e.downcast_ref::<NarSizeMismatch>() {
+                            // flag the path but don't fail the whole push
+                            Some(mismatch) => {
+                                warn!("{mismatch}");
+                                self.size_mismatch_paths
+                                    .lock()
+                                    .unwrap()
+                                    .push(mismatch.path.clone());
+                                Ok(())
+                            }
+                            None => {
+                                Err(e.context(format!("upload {}", store_path.to_absolute_path())))
+                            }
+                        },
+                        Ok(entry) => {
+                            self.upload_count.fetch_add(1, Ordering::Relaxed);
+                            if let (Some(index), Some(key)) = (&self.index, index_key) {
+                                index.insert(key);
+                            }
+                            if let Some(bloom) = &self.bloom {
+                                bloom.lock().unwrap().insert(store_path.digest());
+                            }
+                            if let Some(journal) = &self.journal {
+                                journal.record(&store_path)?;
+                            }
+                            self.manifest.lock().unwrap().push(entry);
+                            Ok(())
+                        }
+                    }
+                }
+                .instrument(span)
+            }));
+        }
+        // only now, so that planning an upload never writes to the bucket
+        self.put_resigned().await
+    }
+
+    /// rows of the final report
+    fn summary(&self) -> Vec<(&'static str, String)> {
+        let mut summary = vec![
+            (
+                "uploaded",
+                self.upload_count.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "skipped because of signature match",
+                self.signature_hit_count.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "skipped because of upstream hit",
+                self.upstream_hit_count.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "skipped because already exist",
+                self.already_exists_count
+                    .load(Ordering::Relaxed)
+                    .to_string(),
+            ),
+            (
+                "upstream check errors",
+                self.upstream_error_count
+                    .load(Ordering::Relaxed)
+                    .to_string(),
+            ),
+        ];
+        let deferred_count = self.deferred_count.load(Ordering::Relaxed);
+        if deferred_count > 0 {
+            summary.push((
+                "deferred because of upload budget",
+                format!(
+                    "{} (size: {})",
+                    deferred_count,
+                    format_size(self.deferred_bytes.load(Ordering::Relaxed), DECIMAL)
+                ),
+            ));
+        }
+        if self.index.is_some() {
+            summary.push((
+                "skipped because in local index",
+                self.index_hit_count.load(Ordering::Relaxed).to_string(),
+            ));
+        }
+        if self.since.is_some() {
+            summary.push((
+                "skipped because in --since",
+                self.since_hit_count.load(Ordering::Relaxed).to_string(),
+            ));
+        }
+        if self.journal.is_some() {
+            summary.push((
+                "skipped because finished by an earlier attempt",
+                self.journal_hit_count.load(Ordering::Relaxed).to_string(),
+            ));
+        }
+        if self.bloom.is_some() {
+            summary.push((
+                "existence checks saved by --bloom",
+                self.bloom_skip_count.load(Ordering::Relaxed).to_string(),
+            ));
+        }
+        if self.lease_ttl.is_some() {
+            summary.push((
+                "skipped because another runner uploaded them",
+                self.lost_lease_count.load(Ordering::Relaxed).to_string(),
+            ));
+        }
+        if !self.also_check.is_empty() {
+            summary.push((
+                "skipped because in an --also-check cache",
+                self.also_check_hit_count
+                    .load(Ordering::Relaxed)
+                    .to_string(),
+            ));
+        }
+        if self.only_built_here {
+            summary.push((
+                "skipped because not built here",
+                self.not_built_here_count
+                    .load(Ordering::Relaxed)
+                    .to_string(),
+            ));
+        }
+        let resigned_count = self.resigned_count.load(Ordering::Relaxed);
+        if resigned_count > 0 {
+            summary.push((
+                "already existed but signed by us now",
+                resigned_count.to_string(),
+            ));
+        }
+        let throttled = self.upstream_client.stats().throttled();
+        if throttled > 0 {
+            summary.push(("upstream requests throttled", throttled.to_string()));
+        }
+        let copied_count = self.copied_count.load(Ordering::Relaxed);
+        if copied_count > 0 {
+            summary.push(("copied from upstream", copied_count.to_string()));
+        }
+        if let Some(requests) = self.s3_requests() {
+            summary.push(("s3 requests", requests.to_string()));
+        }
+        summary.extend(timing_summary(&self.manifest.lock().unwrap()));
+        summary
+    }
+
+    /// requests sent to the bucket so far, `None` when pushing to cachix
+    fn s3_requests(&self) -> Option<RequestCounts> {
+        match self.destination {
+            Destination::S3(_) => Some(self.request_stats.snapshot()),
+            Destination::Cachix(_) => None,
+        }
+    }
+
+    /// one line per upstream on how useful and how fast it was
+    fn upstream_stats(&self) -> Vec<(String, String)> {
+        self.upstream_client
+            .stats()
+            .snapshot()
+            .into_iter()
+            .map(|(upstream, counters)| {
+                let stats = format!(
+                    "{} checks, {} hits, {} errors, {:.0?} average latency",
+                    counters.checks,
+                    counters.hits,
+                    counters.errors,
+                    counters.average_latency()
                 );
-                break;
+                (upstream, stats)
+            })
+            .collect()
+    }
+
+    /// the final report for `--json`
+    fn json_summary(&self) -> PushSummary {
+        let count = |counter: &AtomicUsize| counter.load(Ordering::Relaxed);
+        let manifest = self.manifest.lock().unwrap();
+        PushSummary {
+            uploaded: count(&self.upload_count),
+            uploaded_bytes: manifest.iter().filter_map(|x| x.file_size).sum(),
+            signature_hits: count(&self.signature_hit_count),
+            upstream_hits: count(&self.upstream_hit_count),
+            already_exist: count(&self.already_exists_count),
+            upstream_errors: count(&self.upstream_error_count),
+            deferred: count(&self.deferred_count),
+            deferred_bytes: self.deferred_bytes.load(Ordering::Relaxed),
+            index_hits: self.index.is_some().then(|| count(&self.index_hit_count)),
+            since_hits: self.since.is_some().then(|| count(&self.since_hit_count)),
+            journal_hits: self
+                .journal
+                .is_some()
+                .then(|| count(&self.journal_hit_count)),
+            bloom_skips: self.bloom.is_some().then(|| count(&self.bloom_skip_count)),
+            lost_leases: self
+                .lease_ttl
+                .is_some()
+                .then(|| count(&self.lost_lease_count)),
+            also_check_hits: (!self.also_check.is_empty())
+                .then(|| count(&self.also_check_hit_count)),
+            not_built_here: self
+                .only_built_here
+                .then(|| count(&self.not_built_here_count)),
+            resigned: count(&self.resigned_count),
+            copied_from_upstream: count(&self.copied_count),
+            size_mismatch_paths: self.size_mismatch_paths.lock().unwrap().clone(),
+            untrusted_paths: self.unverified_paths.lock().unwrap().clone(),
+            requests: self.s3_requests(),
+        }
+    }
+
+    fn print_summary(&self) -> Result<()> {
+        let summary = self.summary();
+        if self.json {
+            println!("{}", serde_json::to_string(&self.json_summary())?);
+        } else {
+            for (name, value) in &summary {
+                println!("{name}: {value}");
             }
         }
+        let upstream_stats = self.upstream_stats();
+        if !upstream_stats.is_empty() && !self.json {
+            println!("upstream checks:");
+            for (upstream, stats) in &upstream_stats {
+                println!("  {upstream}: {stats}");
+            }
+        }
+        let size_mismatch_paths = self.size_mismatch_paths.lock().unwrap();
+        if !size_mismatch_paths.is_empty() && !self.json {
+            println!("not uploaded because of nar size mismatch:");
+            for path in size_mismatch_paths.iter() {
+                println!("  {path}");
+            }
+        }
+        let unverified_paths = self.unverified_paths.lock().unwrap();
+        if !unverified_paths.is_empty() && !self.json {
+            println!("not uploaded because no signature is trusted:");
+            for path in unverified_paths.iter() {
+                println!("  {path}");
+            }
+        }
+
+        if self.gha {
+            let manifest = self.manifest.lock().unwrap();
+            let uploaded_bytes: u64 = manifest.iter().filter_map(|x| x.file_size).sum();
+            gha::notice(&format!(
+                "pushed {} paths ({})",
+                manifest.len(),
+                format_size(uploaded_bytes, DECIMAL)
+            ));
+
+            let mut markdown = String::from("### nixcp\n\n| | |\n|---|---|\n");
+            for (name, value) in &summary {
+                markdown.push_str(&format!("| {name} | {value} |\n"));
+            }
+            for (upstream, stats) in &upstream_stats {
+                markdown.push_str(&format!("| upstream `{upstream}` | {stats} |\n"));
+            }
+            for path in size_mismatch_paths.iter() {
+                markdown.push_str(&format!("| nar size mismatch | `{path}` |\n"));
+            }
+            for path in unverified_paths.iter() {
+                markdown.push_str(&format!("| no trusted signature | `{path}` |\n"));
+            }
+            gha::append_step_summary(&markdown)?;
+            gha::set_output("uploaded", &manifest.len().to_string())?;
+            gha::set_output("uploaded-bytes", &uploaded_bytes.to_string())?;
+        }
         Ok(())
     }
+
+    /// whether `path` has more than `MANY_FILES` regular files
+    async fn has_many_files(&self, path: &PathInfo) -> bool {
+        // nars of chroot or remote stores aren't read from our /nix/store
+        if self.store.uri().is_some() {
+            return false;
+        }
+        let path = path.clone();
+        match tokio::task::spawn_blocking(move || path.count_files())
+            .await
+            .unwrap()
+        {
+            Ok(count) => count > MANY_FILES,
+            Err(e) => {
+                debug!("couldn't count files: {e}");
+                false
+            }
+        }
+    }
+
+    /// whether a path of `nar_size` still fits in the upload budget given what's already queued
+    /// `path` was uploaded by another runner while we waited for its lease
+    fn lost_lease(&self, path: &PathInfo) {
+        self.skip(path, "uploaded by another runner");
+        self.lost_lease_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn within_budget(&self, queued_count: usize, queued_bytes: u64, nar_size: u64) -> bool {
+        if self.max_upload_count.is_some_and(|max| queued_count >= max) {
+            return false;
+        }
+        if self
+            .max_upload_bytes
+            .is_some_and(|max| queued_bytes + nar_size > max)
+        {
+            return false;
+        }
+        true
+    }
+}
+
+/// store paths a previous revision pushed: the closure of a store path, or the paths listed in
+/// a manifest
+async fn previous_paths(
+    since: &std::path::Path,
+    store: &Store,
+) -> Result<HashSet<StorePath<String>>> {
+    let resolved = fs::canonicalize(since).unwrap_or_else(|_| since.to_path_buf());
+    let is_store_path =
+        StorePath::<String>::from_absolute_path(resolved.as_os_str().as_encoded_bytes()).is_ok();
+    if is_store_path {
+        let path_info = PathInfo::from_derivation(since, store).await?;
+        return Ok(path_info
+            .get_closure(store)
+            .await?
+            .into_iter()
+            .map(|path| path.path)
+            .collect());
+    }
+    manifest::read_ndjson(since)?
+        .into_iter()
+        .map(|entry| {
+            StorePath::from_absolute_path(entry.store_path.as_bytes())
+                .context(format!("{} is not a store path", entry.store_path))
+        })
+        .collect()
+}
+
+/// the store paths `path` stands for in a release. path info is resolved to the derivation,
+/// so this goes back to the output given, or to the outputs in the closure if that was the
+/// derivation itself
+fn release_roots(path: &std::path::Path, drv: &PathInfo, closure: &[PathInfo]) -> Vec<String> {
+    // resolve symlinks like ./result
+    let resolved = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    if let Ok(output) =
+        StorePath::<String>::from_absolute_path(resolved.as_os_str().as_encoded_bytes())
+        && output != drv.path
+    {
+        return vec![output.to_absolute_path()];
+    }
+    closure
+        .iter()
+        .filter(|path| path.deriver.as_ref() == Some(&drv.path))
+        .map(|path| path.path.to_absolute_path())
+        .collect()
+}
+
+/// hand `path` to the upload stage, which only goes away early if it failed
+async fn queue(tx: &mpsc::Sender<PathInfo>, path: PathInfo) -> Result<()> {
+    tx.send(path).await.map_err(|e| {
+        anyhow!(
+            "upload stage stopped before queueing {}",
+            e.0.absolute_path()
+        )
+    })
+}
+
+/// span around everything done for `path`, carrying its name so logs can be filtered by it,
+/// e.g. `RUST_LOG='nixcp[{path=hello-.*}]=debug'`
+fn path_span(path: &PathInfo) -> Span {
+    info_span!("store_path", path = %path.path.name())
+}
+
+/// group paths by package name, largest group first
+fn breakdown(paths: &[PathInfo]) -> Vec<PackageEstimate> {
+    let mut packages: HashMap<&str, PackageEstimate> = HashMap::new();
+    for path in paths {
+        let name = package_name(path.path.name());
+        let package = packages.entry(name).or_insert_with(|| PackageEstimate {
+            name: name.to_string(),
+            paths: 0,
+            nar_size: 0,
+        });
+        package.paths += 1;
+        package.nar_size += path.nar_size;
+    }
+    let mut packages: Vec<_> = packages.into_values().collect();
+    packages.sort_by(|a, b| {
+        b.nar_size
+            .cmp(&a.nar_size)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    packages
+}
+
+/// the name of a store path without its version and output, like nix's `parseDrvName`:
+/// everything before the first dash that is followed by something other than a letter
+pub fn package_name(name: &str) -> &str {
+    name.match_indices('-')
+        .find(|(i, _)| {
+            name[i + 1..]
+                .chars()
+                .next()
+                .is_some_and(|c| !c.is_ascii_alphabetic())
+        })
+        .map_or(name, |(i, _)| &name[..i])
+}
+
+/// percentiles of how long uploads took and how much of it was compression, to tell whether
+/// cpu or network is the bottleneck
+fn timing_summary(manifest: &[ManifestEntry]) -> Vec<(&'static str, String)> {
+    let mut elapsed = Vec::new();
+    let mut compress = Vec::new();
+    let mut throughput = Vec::new();
+    for entry in manifest {
+        let Some(elapsed_secs) = entry.elapsed_secs else {
+            continue;
+        };
+        elapsed.push(elapsed_secs);
+        compress.push(entry.compress_secs.unwrap_or_default());
+        let bytes = entry.file_size.unwrap_or(entry.nar_size);
+        throughput.push(bytes as f64 / elapsed_secs.max(f64::EPSILON) / 1e6);
+    }
+    if elapsed.is_empty() {
+        return Vec::new();
+    }
+
+    let percentiles = |values: &mut Vec<f64>, unit: &str| {
+        values.sort_by(f64::total_cmp);
+        let values: &[f64] = values;
+        format!(
+            "p50 {:.2}{unit}, p90 {:.2}{unit}, p99 {:.2}{unit}",
+            percentile(values, 50),
+            percentile(values, 90),
+            percentile(values, 99)
+        )
+    };
+    // close to 100% means compression is what holds uploads up
+    let compress_share = compress.iter().sum::<f64>() / elapsed.iter().sum::<f64>();
+    vec![
+        ("time per upload", percentiles(&mut elapsed, "s")),
+        (
+            "compression time per upload",
+            percentiles(&mut compress, "s"),
+        ),
+        (
+            "throughput per upload",
+            percentiles(&mut throughput, " MB/s"),
+        ),
+        (
+            "upload time spent compressing",
+            format!("{:.0}%", compress_share * 100.0),
+        ),
+    ]
+}
+
+/// whether `e` is s3 rejecting credentials that were valid when the push started, like
+/// temporary ones from sts or sso
+fn is_expired_credentials(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|cause| cause.downcast_ref::<object_store::Error>())
+        .any(|e| match e {
+            // a 401
+            object_store::Error::Unauthenticated { .. } => true,
+            // expired tokens are a 400 or a 403, only the error code s3 sends tells them apart
+            // from other bad requests or a bucket policy denying us
+            object_store::Error::PermissionDenied { source, .. }
+            | object_store::Error::Generic { source, .. } => {
+                let body = source.to_string();
+                xml_text(&body, "Code").is_some_and(|code| EXPIRED_CREDENTIAL_CODES.contains(&code))
+            }
+            _ => false,
+        })
+}
+
+/// file descriptors uploads may hold if --max-open-files isn't given
+fn default_fd_budget() -> u32 {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit only writes to the struct we pass it
+    let soft_limit = if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == 0 {
+        limit.rlim_cur.min(u32::MAX as libc::rlim_t) as u32
+    } else {
+        1024
+    };
+    soft_limit.saturating_sub(RESERVED_FDS).max(16)
+}
+
+/// take an exclusive lock shared by every push on this machine, waiting for any other push to
+/// finish first. the lock is released when the returned file is dropped
+pub fn lock() -> Result<File> {
+    let path = std::env::temp_dir().join("nixcp-push.lock");
+    // the file may have been created by another user, flock works on a read only handle too
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .or_else(|_| File::open(&path))
+        .context(format!("open lock file {}", path.display()))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            println!("waiting for another nixcp push to finish");
+            file.lock().context(format!("lock {}", path.display()))?;
+        }
+        Err(TryLockError::Error(e)) => {
+            return Err(anyhow::Error::new(e).context(format!("lock {}", path.display())));
+        }
+    }
+    Ok(file)
+}
+
+/// build `installable` without a result link so that its outputs are in the store
+pub async fn build(installable: &str, store_uri: Option<&str>) -> Result<()> {
+    let mut command = Command::new("nix");
+    command.arg("build").arg("--no-link");
+    // with a remote store the build happens there too
+    if let Some(store_uri) = store_uri {
+        command.arg("--store").arg(store_uri);
+    }
+    let status = command
+        .arg(installable)
+        .status()
+        .await
+        .context(format!("run command: nix build {installable}"))?;
+    if !status.success() {
+        bail!("nix build {installable} failed with {status}");
+    }
+    Ok(())
+}
+
+impl PushArgs {
+    /// the store to read paths from, `None` for the local one
+    pub fn store_uri(&self) -> Result<Option<String>> {
+        if let Some(host) = &self.on {
+            return Ok(Some(format!("ssh-ng://{host}")));
+        }
+        self.store_root
+            .as_deref()
+            .map(Store::chroot_uri)
+            .transpose()
+    }
+}
+
+/// read the secret key from --signing-key or the systemd credential named by
+/// --signing-key-credential
+fn read_signing_key(cli: &PushArgs) -> Result<String> {
+    let path = match (&cli.signing_key, &cli.signing_key_credential) {
+        (Some(path), _) => path.clone(),
+        (None, Some(credential)) => {
+            let credentials_directory = std::env::var_os("CREDENTIALS_DIRECTORY")
+                .context("CREDENTIALS_DIRECTORY is not set, is nixcp running under systemd?")?;
+            PathBuf::from(credentials_directory).join(credential)
+        }
+        (None, None) => bail!("either a signing key or a signing key credential must be given"),
+    };
+    fs::read_to_string(&path).context(format!("read signing key from {}", path.display()))
+}
+
+fn upstreams_file(cli: &PushArgs) -> Result<UpstreamsFile> {
+    match &cli.upstreams_file {
+        Some(path) => UpstreamsFile::read(path),
+        None => Ok(UpstreamsFile::default()),
+    }
+}
+
+fn upstreams(cli: &PushArgs, file: &UpstreamsFile) -> Result<Vec<Url>> {
+    file.upstreams(cli.no_default_upstream, &cli.upstreams)
+}
+
+/// the options of the upstreams file for the upstreams it lists, the flags for the rest
+fn upstream_client(cli: &PushArgs, file: &UpstreamsFile) -> Result<UpstreamClient> {
+    let mut rate_limiter = RateLimiter::new(cli.upstream_rps, cli.upstream_burst);
+    for (host, limit) in file.rate_limits()? {
+        rate_limiter = rate_limiter.with_host_limit(host, limit.rps, limit.burst);
+    }
+    let mut headers = file.headers()?;
+    // a header given on the command line replaces the one of the file
+    headers.retain(|header| {
+        !cli.headers.iter().any(|flag| {
+            flag.name == header.name && (flag.host.is_none() || flag.host == header.host)
+        })
+    });
+    headers.extend(cli.headers.iter().cloned());
+    Ok(UpstreamClient::new(rate_limiter, headers)?.with_timeouts(file.timeouts()?))
+}
+
+/// print the settings a push would use after applying flags, store uri parameters and
+/// environment variables. secrets are redacted
+pub fn print_config(cli: &PushArgs) -> Result<()> {
+    let env = |var: &str| std::env::var(var).ok().filter(|x| !x.is_empty());
+    let or_unset = |x: Option<String>| x.unwrap_or_else(|| "(unset)".to_string());
+    let redacted = |x: Option<String>| x.map(|_| "(redacted)".to_string());
+
+    let key = read_signing_key(cli)?;
+    let (_, verifying_key) = narinfo::parse_keypair(key.as_str())?;
+    match (&cli.signing_key, &cli.signing_key_credential) {
+        (Some(path), _) => println!("signing key: {}", path.display()),
+        (None, Some(credential)) => println!("signing key: credential {credential}"),
+        (None, None) => {}
+    }
+    println!("public key: {}", verifying_key.name());
+
+    let s3_uri = S3Uri::from_args(
+        cli.to.as_ref(),
+        cli.bucket.as_deref(),
+        cli.region.as_deref(),
+        cli.endpoint.as_deref(),
+        cli.s3_path_style,
+        cli.allow_http,
+        cli.sts_endpoint.as_deref(),
+    );
+    match (&s3_uri, &cli.cachix) {
+        (_, Some(cachix)) => {
+            println!("destination: cachix {cachix}");
+            println!(
+                "CACHIX_AUTH_TOKEN: {}",
+                or_unset(redacted(env("CACHIX_AUTH_TOKEN")))
+            );
+        }
+        (Some(s3_uri), None) => {
+            println!("destination: s3 bucket {}", s3_uri.bucket);
+            println!("prefix: {}", or_unset(cli.prefix.clone()));
+            let region = s3_uri
+                .region
+                .clone()
+                .or_else(|| env("AWS_DEFAULT_REGION"))
+                .or_else(|| env("AWS_REGION"))
+                .unwrap_or_else(|| "us-east-1".to_string());
+            println!("region: {region}");
+            let endpoint = s3_uri.endpoint.clone().or_else(|| env("AWS_ENDPOINT"));
+            println!("endpoint: {}", or_unset(endpoint.clone()));
+            let sts_endpoint = s3_uri
+                .sts_endpoint
+                .clone()
+                .or_else(|| env("AWS_ENDPOINT_URL_STS"));
+            println!("sts endpoint: {}", or_unset(sts_endpoint));
+            let addressing_style = match s3_uri.path_style {
+                Some(true) => "path",
+                Some(false) => "virtual",
+                None => "auto",
+            };
+            println!("addressing style: {addressing_style}");
+            match &s3_uri.profile {
+                Some(profile) => println!("credentials: profile {profile}"),
+                None => {
+                    println!("AWS_ACCESS_KEY_ID: {}", or_unset(env("AWS_ACCESS_KEY_ID")));
+                    println!(
+                        "AWS_SECRET_ACCESS_KEY: {}",
+                        or_unset(redacted(env("AWS_SECRET_ACCESS_KEY")))
+                    );
+                }
+            }
+            println!(
+                "finalize strategy: {:?}",
+                cli.finalize_strategy.resolve(endpoint.as_deref())
+            );
+            println!(
+                "checksum: {}",
+                if cli.no_checksum { "none" } else { "sha256" }
+            );
+            // what to point an expiration lifecycle rule at, relative to the bucket
+            let temp_prefix: Vec<_> = [
+                cli.prefix.as_deref().unwrap_or_default(),
+                cli.temp_prefix.as_str(),
+            ]
+            .into_iter()
+            .map(|x| x.trim_matches('/'))
+            .filter(|x| !x.is_empty())
+            .collect();
+            if temp_prefix.is_empty() {
+                println!("temp prefix: (bucket root)");
+            } else {
+                println!("temp prefix: {}/", temp_prefix.join("/"));
+            }
+        }
+        (None, None) => bail!("either a bucket or a cachix cache must be given"),
+    }
+
+    if let Some(path) = &cli.upstreams_file {
+        println!("upstreams file: {}", path.display());
+    }
+    for upstream in upstreams(cli, &upstreams_file(cli)?)? {
+        println!("upstream: {upstream}");
+    }
+    for s3_uri in &cli.also_check {
+        println!("also check: s3 bucket {}", s3_uri.bucket);
+    }
+    println!("upstream error policy: {:?}", cli.upstream_error_policy);
+    println!("part concurrency: {}", cli.part_concurrency);
+    println!("direct put threshold: {}", cli.direct_put_threshold);
+    println!(
+        "spill threshold: {}",
+        or_unset(cli.spill_threshold.map(|x| x.to_string()))
+    );
+    if cli.spill_threshold.is_some() {
+        let spill_dir = cli.spill_dir.clone().unwrap_or_else(std::env::temp_dir);
+        println!("spill dir: {}", spill_dir.display());
+    }
+    println!(
+        "zstd long: {}",
+        or_unset(cli.zstd_long.map(|x| x.to_string()))
+    );
+    println!(
+        "incompressible threshold: {}",
+        or_unset(cli.incompressible_threshold.map(|x| x.to_string()))
+    );
+    println!(
+        "encryption key: {}",
+        or_unset(cli.encryption_key.as_ref().map(|x| x.display().to_string()))
+    );
+    println!(
+        "max upload bytes: {}",
+        or_unset(cli.max_upload_bytes.map(|x| x.to_string()))
+    );
+    println!(
+        "max upload count: {}",
+        or_unset(cli.max_upload_count.map(|x| x.to_string()))
+    );
+    println!(
+        "max memory: {}",
+        or_unset(cli.max_memory.map(|x| x.to_string()))
+    );
+    println!(
+        "max open files: {}",
+        cli.max_open_files.unwrap_or_else(default_fd_budget)
+    );
+    println!(
+        "max requests: {}",
+        or_unset(cli.max_requests.map(|x| x.to_string()))
+    );
+    println!("queue depth: {}", cli.queue_depth);
+    println!("closure concurrency: {}", cli.closure_concurrency);
+    println!("release: {}", or_unset(cli.release.clone()));
+    println!("copy from upstream: {}", cli.copy_from_upstream);
+    println!("resign: {}", cli.resign);
+    Ok(())
+}
+
+/// parse a size like `512MiB`, `2G` or a plain number of bytes
+pub fn parse_size(s: &str) -> Result<u64> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (count, unit) = s.split_at(split);
+    let count: u64 = count
+        .parse()
+        .context(format!("{s} does not start with a number"))?;
+    let multiplier = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KiB" => 1 << 10,
+        "M" | "MiB" => 1 << 20,
+        "G" | "GiB" => 1 << 30,
+        "KB" => 1000,
+        "MB" => 1000 * 1000,
+        "GB" => 1000 * 1000 * 1000,
+        unit => bail!("unknown unit {unit} in {s}"),
+    };
+    count
+        .checked_mul(multiplier)
+        .context(format!("{s} is too large"))
 }
@@ -0,0 +1,73 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use url::Url;
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// token bucket per upstream host so that checking a large closure doesn't hammer an upstream
+pub struct RateLimiter {
+    // requests per second, no limit if unset
+    rps: Option<f64>,
+    burst: f64,
+    // host -> requests per second and burst of upstreams with their own limit
+    hosts: HashMap<String, (f64, f64)>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(rps: Option<f64>, burst: u32) -> Self {
+        Self {
+            rps,
+            burst: burst.max(1) as f64,
+            hosts: HashMap::new(),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// limit upstreams on `host` to `rps` instead of the limit all upstreams get. the burst
+    /// defaults to theirs too
+    pub fn with_host_limit(mut self, host: String, rps: f64, burst: Option<u32>) -> Self {
+        let burst = burst.map_or(self.burst, |burst| burst.max(1) as f64);
+        self.hosts.insert(host, (rps, burst));
+        self
+    }
+
+    /// wait until a request to `upstream` is allowed
+    pub async fn acquire(&self, upstream: &Url) {
+        let host = upstream.host_str().unwrap_or_default();
+        let Some((rps, burst)) = self
+            .hosts
+            .get(host)
+            .copied()
+            .or(self.rps.map(|rps| (rps, self.burst)))
+        else {
+            return;
+        };
+        let wait = {
+            let mut buckets = self.buckets.lock().unwrap();
+            let now = Instant::now();
+            let bucket = buckets.entry(host.to_string()).or_insert(Bucket {
+                tokens: burst,
+                refilled_at: now,
+            });
+            bucket.tokens = (bucket.tokens
+                + now.duration_since(bucket.refilled_at).as_secs_f64() * rps)
+                .min(burst);
+            bucket.refilled_at = now;
+            // going negative reserves a slot in the future for this request
+            bucket.tokens -= 1.0;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / rps)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
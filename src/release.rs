@@ -0,0 +1,37 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use object_store::{ObjectStore, path::Path};
+use serde::{Deserialize, Serialize};
+
+use crate::manifest::ManifestEntry;
+
+/// where releases are published, relative to the root of the cache
+pub const RELEASES_PREFIX: &str = "releases";
+
+/// what a push published under a name, so that deployment tooling can find e.g. the latest
+/// pushed system in the cache
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Release {
+    pub name: String,
+    pub rev: String,
+    /// rfc 3339
+    pub pushed_at: String,
+    /// store paths that were pushed with their closures
+    pub roots: Vec<String>,
+    /// the paths this push uploaded, paths that were already cached aren't listed
+    pub manifest: Vec<ManifestEntry>,
+}
+
+/// write `releases/<name>/<rev>.json` and point `releases/<name>/latest.json` at it by
+/// writing the same there
+pub async fn publish(s3: &Arc<dyn ObjectStore>, release: &Release) -> Result<()> {
+    let json = serde_json::to_vec_pretty(release)?;
+    for file in [release.rev.as_str(), "latest"] {
+        let key = Path::from(format!("{RELEASES_PREFIX}/{}/{file}.json", release.name));
+        s3.put(&key, json.clone().into())
+            .await
+            .context(format!("put {key}"))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,126 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result, bail};
+use futures::{StreamExt, TryStreamExt, stream};
+use nix_compat::narinfo::NarInfo;
+use object_store::{ObjectStore, buffered::BufWriter, path::Path};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, error};
+
+use crate::ReplicateArgs;
+
+pub struct Replicate {
+    source: Arc<dyn ObjectStore>,
+    destination: Arc<dyn ObjectStore>,
+    // keep replicating with this pause in between, or replicate once if `None`
+    interval: Option<Duration>,
+}
+
+impl Replicate {
+    pub fn new(cli: &ReplicateArgs) -> Result<Self> {
+        Ok(Self {
+            source: cli.from.build(None).context("connect to source bucket")?,
+            destination: cli
+                .to
+                .build(None)
+                .context("connect to destination bucket")?,
+            interval: cli.interval,
+        })
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        // narinfos known to be in the destination. only the first pass lists it, after that
+        // we only add what we copied
+        let mut replicated = list_narinfos(self.destination.as_ref())
+            .await
+            .context("list destination bucket")?;
+        loop {
+            let failed = self.replicate_once(&mut replicated).await?;
+            let Some(interval) = self.interval else {
+                if failed > 0 {
+                    bail!("failed to replicate {failed} paths");
+                }
+                return Ok(());
+            };
+            // failed paths are retried on the next pass
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// copy narinfos that aren't in `replicated` yet, returns how many failed
+    async fn replicate_once(&self, replicated: &mut HashSet<String>) -> Result<usize> {
+        let missing: Vec<String> = list_narinfos(self.source.as_ref())
+            .await
+            .context("list source bucket")?
+            .into_iter()
+            .filter(|key| !replicated.contains(key))
+            .collect();
+        debug!("{} narinfos to replicate", missing.len());
+
+        let results: Vec<_> = stream::iter(missing)
+            .map(|key| async move {
+                let res = self
+                    .copy_path(&key)
+                    .await
+                    .context(format!("replicate {key}"));
+                (key, res)
+            })
+            .buffer_unordered(16)
+            .collect()
+            .await;
+
+        let mut copied = 0;
+        let mut failed = 0;
+        for (key, res) in results {
+            match res {
+                Ok(()) => {
+                    replicated.insert(key);
+                    copied += 1;
+                }
+                Err(e) => {
+                    error!("{e:#}");
+                    failed += 1;
+                }
+            }
+        }
+        println!("replicated: {copied}, failed: {failed}");
+        Ok(failed)
+    }
+
+    /// copy the nar and then the narinfo so that the narinfo never points to a missing nar
+    async fn copy_path(&self, key: &str) -> Result<()> {
+        let narinfo_path = Path::from(key);
+        let narinfo = self.source.get(&narinfo_path).await?.bytes().await?;
+        let nar_path = {
+            let narinfo = std::str::from_utf8(&narinfo).context("narinfo is not utf-8")?;
+            let narinfo = NarInfo::parse(narinfo).context("parse narinfo")?;
+            Path::from(narinfo.url)
+        };
+
+        match self.destination.head(&nar_path).await {
+            Ok(_) => {}
+            Err(object_store::Error::NotFound { .. }) => {
+                debug!("copying {nar_path}");
+                let mut nar = self.source.get(&nar_path).await?.into_stream();
+                let mut writer = BufWriter::new(self.destination.clone(), nar_path);
+                while let Some(chunk) = nar.try_next().await? {
+                    writer.put(chunk).await?;
+                }
+                writer.shutdown().await?;
+            }
+            Err(e) => return Err(anyhow::Error::new(e).context(format!("check {nar_path}"))),
+        }
+        self.destination.put(&narinfo_path, narinfo.into()).await?;
+        Ok(())
+    }
+}
+
+/// keys of the narinfos at the root of the cache
+async fn list_narinfos(s3: &dyn ObjectStore) -> Result<HashSet<String>> {
+    Ok(s3
+        .list(None)
+        .map_ok(|meta| meta.location.to_string())
+        .try_filter(|key| futures::future::ready(key.ends_with(".narinfo") && !key.contains('/')))
+        .try_collect()
+        .await?)
+}
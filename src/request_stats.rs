@@ -0,0 +1,266 @@
+use std::{
+    fmt,
+    ops::Range,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{StreamExt, TryStreamExt, future, stream::BoxStream};
+use object_store::{
+    GetOptions, GetResult, ListResult, MultipartUpload, ObjectMeta, ObjectStore, PutMultipartOpts,
+    PutOptions, PutPayload, PutResult, Result, UploadPart, path::Path,
+};
+use serde::Serialize;
+
+/// what a request to the bucket is billed as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    Head,
+    Get,
+    /// also the requests that start, add to and finish a multipart upload
+    Put,
+    Copy,
+    /// each key of a bulk delete counts once
+    Delete,
+    /// each listing counts once, however many pages it takes
+    List,
+}
+
+/// requests sent to the bucket by kind
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct RequestCounts {
+    pub head: usize,
+    pub get: usize,
+    pub put: usize,
+    pub copy: usize,
+    pub delete: usize,
+    pub list: usize,
+}
+
+impl RequestCounts {
+    pub fn total(&self) -> usize {
+        self.head + self.get + self.put + self.copy + self.delete + self.list
+    }
+}
+
+impl fmt::Display for RequestCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({} head, {} get, {} put, {} copy, {} delete, {} list)",
+            self.total(),
+            self.head,
+            self.get,
+            self.put,
+            self.copy,
+            self.delete,
+            self.list
+        )
+    }
+}
+
+/// counts requests to the bucket and refuses to send more than `max`, so that a push gone
+/// wrong can't run up the bill
+#[derive(Debug, Default)]
+pub struct RequestStats {
+    head: AtomicUsize,
+    get: AtomicUsize,
+    put: AtomicUsize,
+    copy: AtomicUsize,
+    delete: AtomicUsize,
+    list: AtomicUsize,
+    /// requests that were sent or refused
+    attempted: AtomicUsize,
+    max: Option<usize>,
+}
+
+impl RequestStats {
+    pub fn new(max: Option<usize>) -> Self {
+        Self {
+            max,
+            ..Default::default()
+        }
+    }
+
+    /// count a request that is about to be sent, or fail it once `max` were sent
+    pub fn record(&self, kind: RequestKind) -> Result<()> {
+        let attempted = self.attempted.fetch_add(1, Ordering::Relaxed);
+        if let Some(max) = self.max
+            && attempted >= max
+        {
+            return Err(object_store::Error::Generic {
+                store: "S3",
+                source: format!("reached --max-requests {max}, not sending more to the bucket")
+                    .into(),
+            });
+        }
+        let counter = match kind {
+            RequestKind::Head => &self.head,
+            RequestKind::Get => &self.get,
+            RequestKind::Put => &self.put,
+            RequestKind::Copy => &self.copy,
+            RequestKind::Delete => &self.delete,
+            RequestKind::List => &self.list,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn snapshot(&self) -> RequestCounts {
+        RequestCounts {
+            head: self.head.load(Ordering::Relaxed),
+            get: self.get.load(Ordering::Relaxed),
+            put: self.put.load(Ordering::Relaxed),
+            copy: self.copy.load(Ordering::Relaxed),
+            delete: self.delete.load(Ordering::Relaxed),
+            list: self.list.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// a store that records every request sent through it in `RequestStats`. renames are left
+/// to the default copy and delete so that they count as both, like s3 bills them
+#[derive(Debug)]
+pub struct CountingStore {
+    inner: Arc<dyn ObjectStore>,
+    stats: Arc<RequestStats>,
+}
+
+impl CountingStore {
+    pub fn new(inner: Arc<dyn ObjectStore>, stats: Arc<RequestStats>) -> Self {
+        Self { inner, stats }
+    }
+}
+
+impl fmt::Display for CountingStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.inner)
+    }
+}
+
+#[async_trait]
+impl ObjectStore for CountingStore {
+    async fn put_opts(
+        &self,
+        location: &Path,
+        payload: PutPayload,
+        opts: PutOptions,
+    ) -> Result<PutResult> {
+        self.stats.record(RequestKind::Put)?;
+        self.inner.put_opts(location, payload, opts).await
+    }
+
+    async fn put_multipart_opts(
+        &self,
+        location: &Path,
+        opts: PutMultipartOpts,
+    ) -> Result<Box<dyn MultipartUpload>> {
+        self.stats.record(RequestKind::Put)?;
+        let inner = self.inner.put_multipart_opts(location, opts).await?;
+        Ok(Box::new(CountingUpload {
+            inner,
+            stats: self.stats.clone(),
+        }))
+    }
+
+    async fn get_opts(&self, location: &Path, options: GetOptions) -> Result<GetResult> {
+        let kind = if options.head {
+            RequestKind::Head
+        } else {
+            RequestKind::Get
+        };
+        self.stats.record(kind)?;
+        self.inner.get_opts(location, options).await
+    }
+
+    async fn get_range(&self, location: &Path, range: Range<u64>) -> Result<Bytes> {
+        self.stats.record(RequestKind::Get)?;
+        self.inner.get_range(location, range).await
+    }
+
+    async fn head(&self, location: &Path) -> Result<ObjectMeta> {
+        self.stats.record(RequestKind::Head)?;
+        self.inner.head(location).await
+    }
+
+    async fn delete(&self, location: &Path) -> Result<()> {
+        self.stats.record(RequestKind::Delete)?;
+        self.inner.delete(location).await
+    }
+
+    fn delete_stream<'a>(
+        &'a self,
+        locations: BoxStream<'a, Result<Path>>,
+    ) -> BoxStream<'a, Result<Path>> {
+        let locations = locations
+            .and_then(|location| {
+                future::ready(self.stats.record(RequestKind::Delete).map(|()| location))
+            })
+            .boxed();
+        self.inner.delete_stream(locations)
+    }
+
+    fn list(&self, prefix: Option<&Path>) -> BoxStream<'static, Result<ObjectMeta>> {
+        match self.stats.record(RequestKind::List) {
+            Ok(()) => self.inner.list(prefix),
+            Err(e) => futures::stream::once(future::ready(Err(e))).boxed(),
+        }
+    }
+
+    fn list_with_offset(
+        &self,
+        prefix: Option<&Path>,
+        offset: &Path,
+    ) -> BoxStream<'static, Result<ObjectMeta>> {
+        match self.stats.record(RequestKind::List) {
+            Ok(()) => self.inner.list_with_offset(prefix, offset),
+            Err(e) => futures::stream::once(future::ready(Err(e))).boxed(),
+        }
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&Path>) -> Result<ListResult> {
+        self.stats.record(RequestKind::List)?;
+        self.inner.list_with_delimiter(prefix).await
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+        self.stats.record(RequestKind::Copy)?;
+        self.inner.copy(from, to).await
+    }
+
+    async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> Result<()> {
+        self.stats.record(RequestKind::Copy)?;
+        self.inner.copy_if_not_exists(from, to).await
+    }
+}
+
+#[derive(Debug)]
+struct CountingUpload {
+    inner: Box<dyn MultipartUpload>,
+    stats: Arc<RequestStats>,
+}
+
+#[async_trait]
+impl MultipartUpload for CountingUpload {
+    fn put_part(&mut self, data: PutPayload) -> UploadPart {
+        match self.stats.record(RequestKind::Put) {
+            Ok(()) => self.inner.put_part(data),
+            Err(e) => Box::pin(future::ready(Err(e))),
+        }
+    }
+
+    async fn complete(&mut self) -> Result<PutResult> {
+        self.stats.record(RequestKind::Put)?;
+        self.inner.complete().await
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        // always let an upload be cleaned up, or its parts are billed for storage
+        self.stats.record(RequestKind::Delete).ok();
+        self.inner.abort().await
+    }
+}
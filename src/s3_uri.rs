@@ -0,0 +1,551 @@
+use std::{
+    fs,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use object_store::{
+    ClientOptions, CredentialProvider, ObjectStore,
+    aws::{AmazonS3Builder, AmazonS3ConfigKey, AwsCredential, Checksum, S3ConditionalPut},
+    path::Path,
+    prefix::PrefixStore,
+};
+use reqwest::{Client, header::HeaderValue};
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::{BucketArgs, http::USER_AGENT, versions::BucketVersions};
+
+/// An s3 binary cache store uri as used by nix e.g. in nix.conf substituters
+/// `s3://bucket?region=eu-west-1&endpoint=minio.local&scheme=https&profile=ci`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct S3Uri {
+    pub bucket: String,
+    pub region: Option<String>,
+    /// full endpoint url, with `scheme` already applied
+    pub endpoint: Option<String>,
+    pub profile: Option<String>,
+    /// `Some(true)` to put the bucket in the path, `Some(false)` in the host name. if unset
+    /// it depends on the endpoint and AWS_VIRTUAL_HOSTED_STYLE_REQUEST
+    pub path_style: Option<bool>,
+    /// allow an http:// endpoint. not part of nix uris, set from --allow-http
+    pub allow_http: bool,
+    /// where to assume a role with web identity. not part of nix uris, set from
+    /// --sts-endpoint
+    pub sts_endpoint: Option<String>,
+}
+
+impl FromStr for S3Uri {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let url = Url::parse(s).context(format!("failed to parse {s} as url"))?;
+        if url.scheme() != "s3" {
+            bail!("expected an s3:// uri, got {s}");
+        }
+        let bucket = url
+            .host_str()
+            .filter(|bucket| !bucket.is_empty())
+            .ok_or_else(|| anyhow!("no bucket in {s}"))?
+            .to_string();
+
+        let mut region = None;
+        let mut endpoint = None;
+        let mut scheme = None;
+        let mut profile = None;
+        let mut path_style = None;
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "region" => region = Some(value.into_owned()),
+                "endpoint" => endpoint = Some(value.into_owned()),
+                "scheme" => scheme = Some(value.into_owned()),
+                "profile" => profile = Some(value.into_owned()),
+                "addressing-style" => {
+                    path_style = match value.as_ref() {
+                        "path" => Some(true),
+                        "virtual" => Some(false),
+                        "auto" => None,
+                        _ => bail!("unknown addressing-style {value} in {s}"),
+                    }
+                }
+                // other store settings like compression don't affect where we upload
+                _ => {}
+            }
+        }
+
+        // nix takes the endpoint as a bare host and the scheme separately
+        let endpoint = endpoint.map(|endpoint| {
+            if endpoint.contains("://") {
+                endpoint
+            } else {
+                format!("{}://{endpoint}", scheme.as_deref().unwrap_or("https"))
+            }
+        });
+
+        Ok(Self {
+            bucket,
+            region,
+            endpoint,
+            profile,
+            path_style,
+            allow_http: false,
+            sts_endpoint: None,
+        })
+    }
+}
+
+impl S3Uri {
+    /// fold --bucket, --region, --endpoint, --s3-path-style, --allow-http and --sts-endpoint
+    /// into the same shape as --to. the flags take precedence over parameters of the uri
+    pub fn from_args(
+        to: Option<&S3Uri>,
+        bucket: Option<&str>,
+        region: Option<&str>,
+        endpoint: Option<&str>,
+        path_style: bool,
+        allow_http: bool,
+        sts_endpoint: Option<&str>,
+    ) -> Option<Self> {
+        let mut s3_uri = match (to, bucket) {
+            (Some(to), _) => to.clone(),
+            (None, Some(bucket)) => Self {
+                bucket: bucket.to_string(),
+                region: None,
+                endpoint: None,
+                profile: None,
+                path_style: None,
+                allow_http: false,
+                sts_endpoint: None,
+            },
+            (None, None) => return None,
+        };
+        if let Some(region) = region {
+            s3_uri.region = Some(region.to_string());
+        }
+        if let Some(endpoint) = endpoint {
+            s3_uri.endpoint = Some(endpoint.to_string());
+        }
+        if path_style {
+            s3_uri.path_style = Some(true);
+        }
+        s3_uri.allow_http |= allow_http;
+        if let Some(sts_endpoint) = sts_endpoint {
+            s3_uri.sts_endpoint = Some(sts_endpoint.to_string());
+        }
+        Some(s3_uri)
+    }
+
+    /// connect to the bucket, keeping all objects under `prefix` if given
+    pub fn build(&self, prefix: Option<&str>) -> Result<Arc<dyn ObjectStore>> {
+        self.build_with_checksum(prefix, None)
+    }
+
+    /// like `build`, but every put and part carries a `checksum` of its data that s3
+    /// verifies before accepting it
+    pub fn build_with_checksum(
+        &self,
+        prefix: Option<&str>,
+        checksum: Option<Checksum>,
+    ) -> Result<Arc<dyn ObjectStore>> {
+        let s3 = self.builder(checksum)?.build()?;
+        // nar urls in narinfos are relative to the narinfo so they stay the same under a prefix
+        Ok(match prefix {
+            Some(prefix) => {
+                let prefix = Path::parse(prefix)
+                    .context(format!("failed to parse {prefix} as a key prefix"))?;
+                Arc::new(PrefixStore::new(s3, prefix))
+            }
+            None => Arc::new(s3),
+        })
+    }
+
+    /// the object versions of the bucket under `prefix`, for buckets with versioning enabled
+    pub fn versions(&self, prefix: Option<&str>) -> Result<BucketVersions> {
+        let builder = self.builder(None)?;
+        let config = |key| builder.get_config_value(&key);
+        // the same defaults object_store uses
+        let region = config(AmazonS3ConfigKey::Region).unwrap_or_else(|| "us-east-1".to_string());
+        let virtual_hosted =
+            config(AmazonS3ConfigKey::VirtualHostedStyleRequest).is_some_and(|x| x == "true");
+        let url = match (config(AmazonS3ConfigKey::Endpoint), virtual_hosted) {
+            (Some(endpoint), true) => endpoint,
+            (Some(endpoint), false) => {
+                format!("{}/{}", endpoint.trim_end_matches('/'), self.bucket)
+            }
+            (None, true) => format!("https://{}.s3.{region}.amazonaws.com", self.bucket),
+            (None, false) => format!("https://s3.{region}.amazonaws.com/{}", self.bucket),
+        };
+        let url = Url::parse(&url).context(format!("failed to parse {url} as url"))?;
+        let credentials = builder.build()?.credentials().clone();
+        BucketVersions::new(url, region, credentials, prefix)
+    }
+
+    /// everything the bucket is reached with, for `build_with_checksum` and `versions`
+    fn builder(&self, checksum: Option<Checksum>) -> Result<AmazonS3Builder> {
+        let mut client_options =
+            ClientOptions::new().with_user_agent(HeaderValue::from_static(USER_AGENT));
+        if self.allow_http {
+            // tracing warnings are off by default and this should not go unnoticed
+            eprintln!(
+                "warning: --allow-http is set, requests to {} may be sent unencrypted",
+                self.endpoint.as_deref().unwrap_or(&self.bucket)
+            );
+            client_options = client_options.with_allow_http(true);
+        }
+        // conditional puts back the leases of --coordinate
+        let mut s3_builder = AmazonS3Builder::from_env()
+            .with_bucket_name(&self.bucket)
+            .with_conditional_put(S3ConditionalPut::ETagMatch)
+            .with_client_options(client_options);
+
+        if let Some(checksum) = checksum {
+            s3_builder = s3_builder.with_checksum_algorithm(checksum);
+        }
+        if let Some(region) = &self.region {
+            s3_builder = s3_builder.with_region(region);
+        }
+        let region = self.region.clone().or_else(|| env("AWS_DEFAULT_REGION"));
+        let dns_suffix = region
+            .as_deref()
+            .map_or("amazonaws.com", partition_dns_suffix);
+        // object_store only knows the endpoints of the standard aws partition
+        let other_partition = dns_suffix != "amazonaws.com";
+        let endpoint = match (&self.endpoint, &region) {
+            (None, Some(region)) if other_partition && env("AWS_ENDPOINT").is_none() => {
+                Some(format!("https://s3.{region}.{dns_suffix}"))
+            }
+            _ => self.endpoint.clone(),
+        };
+        // there's no dns for <bucket>.localhost or <bucket>.<ip>
+        let path_style = self.path_style.or_else(|| {
+            endpoint
+                .as_deref()
+                .is_some_and(is_local_endpoint)
+                .then_some(true)
+        });
+        match (path_style, &endpoint) {
+            (Some(true), _) => {
+                s3_builder = s3_builder.with_virtual_hosted_style_request(false);
+            }
+            // object_store expects the bucket to be part of a custom endpoint already
+            (Some(false), Some(endpoint)) => {
+                let mut endpoint =
+                    Url::parse(endpoint).context(format!("failed to parse {endpoint} as url"))?;
+                let host = format!(
+                    "{}.{}",
+                    self.bucket,
+                    endpoint.host_str().unwrap_or_default()
+                );
+                endpoint
+                    .set_host(Some(&host))
+                    .context(format!("invalid host {host}"))?;
+                s3_builder = s3_builder
+                    .with_endpoint(endpoint.as_str().trim_end_matches('/'))
+                    .with_virtual_hosted_style_request(true);
+            }
+            (Some(false), None) => {
+                s3_builder = s3_builder.with_virtual_hosted_style_request(true);
+            }
+            (None, _) => {}
+        }
+        if path_style != Some(false)
+            && let Some(endpoint) = &endpoint
+        {
+            s3_builder = s3_builder.with_endpoint(endpoint);
+        }
+        let sts_endpoint = self
+            .sts_endpoint
+            .clone()
+            .or_else(|| env("AWS_ENDPOINT_URL_STS"));
+        if let Some(profile) = &self.profile {
+            s3_builder =
+                s3_builder.with_credentials(Arc::new(ProfileCredentialProvider::new(profile)?));
+        } else if let (Some(token_file), Some(role_arn)) =
+            (env("AWS_WEB_IDENTITY_TOKEN_FILE"), env("AWS_ROLE_ARN"))
+            && env("AWS_ACCESS_KEY_ID").is_none()
+            && (sts_endpoint.is_some() || other_partition)
+        {
+            // object_store would assume the role at sts.<region>.amazonaws.com
+            let sts_endpoint = match (sts_endpoint, &region) {
+                (Some(sts_endpoint), _) => sts_endpoint,
+                (None, Some(region)) => format!("https://sts.{region}.{dns_suffix}"),
+                (None, None) => unreachable!("only other partitions lack an sts endpoint"),
+            };
+            let provider =
+                WebIdentityCredentialProvider::new(token_file.into(), role_arn, sts_endpoint)?;
+            s3_builder = s3_builder.with_credentials(Arc::new(provider));
+        }
+        Ok(s3_builder)
+    }
+}
+
+impl BucketArgs {
+    /// connect to the bucket given on the command line
+    pub fn build(&self) -> Result<Arc<dyn ObjectStore>> {
+        self.s3_uri()?.build(self.prefix.as_deref())
+    }
+
+    /// the object versions of the bucket given on the command line
+    pub fn versions(&self) -> Result<BucketVersions> {
+        self.s3_uri()?.versions(self.prefix.as_deref())
+    }
+
+    fn s3_uri(&self) -> Result<S3Uri> {
+        S3Uri::from_args(
+            self.to.as_ref(),
+            self.bucket.as_deref(),
+            self.region.as_deref(),
+            self.endpoint.as_deref(),
+            self.s3_path_style,
+            self.allow_http,
+            self.sts_endpoint.as_deref(),
+        )
+        .ok_or_else(|| anyhow!("a bucket must be given"))
+    }
+}
+
+fn env(var: &str) -> Option<String> {
+    std::env::var(var).ok().filter(|x| !x.is_empty())
+}
+
+/// dns suffix of the aws partition `region` is in, e.g. amazonaws.com.cn for the China regions.
+/// GovCloud regions share the one of the standard partition
+pub fn partition_dns_suffix(region: &str) -> &'static str {
+    if region.starts_with("cn-") {
+        "amazonaws.com.cn"
+    } else {
+        "amazonaws.com"
+    }
+}
+
+/// whether `endpoint` is on localhost or an ip address
+fn is_local_endpoint(endpoint: &str) -> bool {
+    match Url::parse(endpoint)
+        .ok()
+        .and_then(|url| url.host().map(|host| host.to_owned()))
+    {
+        Some(url::Host::Domain(domain)) => domain == "localhost",
+        Some(url::Host::Ipv4(_) | url::Host::Ipv6(_)) => true,
+        None => false,
+    }
+}
+
+/// credentials of a profile that are read again whenever the credentials file changes, so
+/// that a long push picks up temporary credentials that `aws sso login` or the like renewed
+#[derive(Debug)]
+pub struct ProfileCredentialProvider {
+    profile: String,
+    path: PathBuf,
+    /// modification time of the file when it was last read, and what it held
+    cached: Mutex<(Option<SystemTime>, Arc<AwsCredential>)>,
+}
+
+impl ProfileCredentialProvider {
+    pub fn new(profile: &str) -> Result<Self> {
+        let path = ProfileCredentials::path()?;
+        let modified = fs::metadata(&path).and_then(|x| x.modified()).ok();
+        let credentials = ProfileCredentials::load(profile)?;
+        Ok(Self {
+            profile: profile.to_string(),
+            path,
+            cached: Mutex::new((modified, Arc::new(credentials.into()))),
+        })
+    }
+}
+
+#[async_trait]
+impl CredentialProvider for ProfileCredentialProvider {
+    type Credential = AwsCredential;
+
+    async fn get_credential(&self) -> object_store::Result<Arc<AwsCredential>> {
+        let modified = fs::metadata(&self.path).and_then(|x| x.modified()).ok();
+        let mut cached = self.cached.lock().unwrap();
+        if modified != cached.0 {
+            // keep using the old credentials while the file is being rewritten
+            match ProfileCredentials::load(&self.profile) {
+                Ok(credentials) => {
+                    debug!("reloaded credentials of profile {}", self.profile);
+                    *cached = (modified, Arc::new(credentials.into()));
+                }
+                Err(e) => warn!("failed to reload credentials: {e:#}"),
+            }
+        }
+        Ok(cached.1.clone())
+    }
+}
+
+/// temporary credentials of a role assumed with AWS_WEB_IDENTITY_TOKEN_FILE, e.g. on EKS or
+/// GitHub Actions, at an sts endpoint object_store doesn't let us choose
+#[derive(Debug)]
+pub struct WebIdentityCredentialProvider {
+    token_file: PathBuf,
+    role_arn: String,
+    session_name: String,
+    sts_endpoint: String,
+    client: Client,
+    /// credentials and when they expire
+    cached: tokio::sync::Mutex<Option<(Arc<AwsCredential>, DateTime<Utc>)>>,
+}
+
+impl WebIdentityCredentialProvider {
+    pub fn new(token_file: PathBuf, role_arn: String, sts_endpoint: String) -> Result<Self> {
+        Ok(Self {
+            token_file,
+            role_arn,
+            session_name: env("AWS_ROLE_SESSION_NAME").unwrap_or_else(|| "nixcp".to_string()),
+            sts_endpoint,
+            client: Client::builder()
+                .user_agent(USER_AGENT)
+                .build()
+                .context("build http client")?,
+            cached: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    /// AssumeRoleWithWebIdentity is one of the few sts calls that isn't signed
+    async fn assume_role(&self) -> Result<(Arc<AwsCredential>, DateTime<Utc>)> {
+        // the token is rotated while we run
+        let token = fs::read_to_string(&self.token_file).context(format!(
+            "read web identity token from {:?}",
+            self.token_file
+        ))?;
+        let res = self
+            .client
+            .get(&self.sts_endpoint)
+            .query(&[
+                ("Action", "AssumeRoleWithWebIdentity"),
+                ("Version", "2011-06-15"),
+                ("RoleArn", self.role_arn.as_str()),
+                ("RoleSessionName", self.session_name.as_str()),
+                ("WebIdentityToken", token.trim()),
+            ])
+            .send()
+            .await
+            .context(format!("assume role at {}", self.sts_endpoint))?;
+        let status = res.status();
+        let body = res.text().await?;
+        ensure!(
+            status.is_success(),
+            "assume role at {} failed with {status}: {body}",
+            self.sts_endpoint
+        );
+
+        let field = |tag| {
+            xml_text(&body, tag)
+                .ok_or_else(|| anyhow!("no {tag} in response of {}", self.sts_endpoint))
+        };
+        let credential = AwsCredential {
+            key_id: field("AccessKeyId")?.to_string(),
+            secret_key: field("SecretAccessKey")?.to_string(),
+            token: Some(field("SessionToken")?.to_string()),
+        };
+        let expiration = DateTime::parse_from_rfc3339(field("Expiration")?)
+            .context("parse expiration of assumed role")?;
+        Ok((Arc::new(credential), expiration.with_timezone(&Utc)))
+    }
+}
+
+#[async_trait]
+impl CredentialProvider for WebIdentityCredentialProvider {
+    type Credential = AwsCredential;
+
+    async fn get_credential(&self) -> object_store::Result<Arc<AwsCredential>> {
+        let mut cached = self.cached.lock().await;
+        // renew a bit early so that requests in flight don't use expired credentials
+        if let Some((credential, expiration)) = &*cached
+            && *expiration - chrono::Duration::minutes(5) > Utc::now()
+        {
+            return Ok(credential.clone());
+        }
+        let (credential, expiration) =
+            self.assume_role()
+                .await
+                .map_err(|e| object_store::Error::Generic {
+                    store: "S3",
+                    source: e.into(),
+                })?;
+        debug!("assumed {} until {expiration}", self.role_arn);
+        *cached = Some((credential.clone(), expiration));
+        Ok(credential)
+    }
+}
+
+/// text of the first `<tag>` in `xml`, enough for the flat responses of sts
+pub(crate) fn xml_text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{tag}>"))? + tag.len() + 2;
+    let len = xml[start..].find(&format!("</{tag}>"))?;
+    Some(&xml[start..start + len])
+}
+
+impl From<ProfileCredentials> for AwsCredential {
+    fn from(credentials: ProfileCredentials) -> Self {
+        Self {
+            key_id: credentials.access_key_id,
+            secret_key: credentials.secret_access_key,
+            token: credentials.session_token,
+        }
+    }
+}
+
+/// static credentials for a profile in the shared aws credentials file
+#[derive(Debug)]
+pub struct ProfileCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl ProfileCredentials {
+    /// `AWS_SHARED_CREDENTIALS_FILE` or ~/.aws/credentials
+    pub fn path() -> Result<PathBuf> {
+        Ok(match std::env::var_os("AWS_SHARED_CREDENTIALS_FILE") {
+            Some(path) => PathBuf::from(path),
+            None => PathBuf::from(std::env::var_os("HOME").context("HOME is not set")?)
+                .join(".aws/credentials"),
+        })
+    }
+
+    /// read `profile` from the shared credentials file
+    pub fn load(profile: &str) -> Result<Self> {
+        let path = Self::path()?;
+        let credentials =
+            fs::read_to_string(&path).context(format!("read credentials from {path:?}"))?;
+
+        let mut in_profile = false;
+        let mut access_key_id = None;
+        let mut secret_access_key = None;
+        let mut session_token = None;
+        for line in credentials.lines().map(str::trim) {
+            if let Some(section) = line.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+                in_profile = section.trim() == profile;
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            if !in_profile {
+                continue;
+            }
+            let value = Some(value.trim().to_string());
+            match key.trim() {
+                "aws_access_key_id" => access_key_id = value,
+                "aws_secret_access_key" => secret_access_key = value,
+                "aws_session_token" => session_token = value,
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            access_key_id: access_key_id
+                .context(format!("no aws_access_key_id for profile {profile}"))?,
+            secret_access_key: secret_access_key
+                .context(format!("no aws_secret_access_key for profile {profile}"))?,
+            session_token,
+        })
+    }
+}
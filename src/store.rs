@@ -1,9 +1,10 @@
-use std::{ffi::OsStr, os::unix::ffi::OsStrExt, sync::Arc};
+use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::Path, sync::Arc};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, anyhow};
 use nix_compat::store_path::StorePath;
 use tokio::{io::AsyncRead, task};
 use tokio_util::io::StreamReader;
+use tracing::debug_span;
 
 use crate::{
     bindings::{self, AsyncWriteAdapter},
@@ -12,16 +13,34 @@ use crate::{
 
 pub struct Store {
     inner: Arc<bindings::FfiNixStore>,
+    /// `None` for the store nix is configured to use
+    uri: Option<String>,
 }
 
 impl Store {
-    pub fn connect() -> Result<Self> {
-        let inner = unsafe { bindings::open_nix_store()? };
+    /// connect to the store at `uri` like `ssh-ng://builder01`, or the store nix is
+    /// configured to use if `None`
+    pub fn connect(uri: Option<&str>) -> Result<Self> {
+        let inner = unsafe { bindings::open_nix_store(uri.unwrap_or_default())? };
         Ok(Self {
             inner: Arc::new(inner),
+            uri: uri.map(str::to_string),
         })
     }
 
+    /// uri of a chroot store whose /nix/store lives under `root` e.g. an image build
+    pub fn chroot_uri(root: &Path) -> Result<String> {
+        let root = root
+            .to_str()
+            .ok_or_else(|| anyhow!("store root {root:?} is not utf-8"))?;
+        Ok(format!("local?root={root}"))
+    }
+
+    /// uri to pass to nix commands with --store, `None` for the default store
+    pub fn uri(&self) -> Option<&str> {
+        self.uri.as_deref()
+    }
+
     pub async fn compute_fs_closure(
         &self,
         path: StorePath<String>,
@@ -44,6 +63,42 @@ impl Store {
         .unwrap()
     }
 
+    /// store paths that gc roots point to
+    pub async fn find_roots(&self) -> Result<Vec<StorePath<String>>> {
+        let inner = self.inner.clone();
+        task::spawn_blocking(move || {
+            inner
+                .store()
+                .find_roots()?
+                .iter()
+                .map(|x| {
+                    StorePath::from_bytes(x.as_bytes())
+                        .context("make StorePath from vector returned by find_roots")
+                })
+                .collect::<Result<_, _>>()
+        })
+        .await
+        .unwrap()
+    }
+
+    /// every valid path in the store
+    pub async fn query_all_valid_paths(&self) -> Result<Vec<StorePath<String>>> {
+        let inner = self.inner.clone();
+        task::spawn_blocking(move || {
+            inner
+                .store()
+                .query_all_valid_paths()?
+                .iter()
+                .map(|x| {
+                    StorePath::from_bytes(x.as_bytes())
+                        .context("make StorePath from vector returned by query_all_valid_paths")
+                })
+                .collect::<Result<_, _>>()
+        })
+        .await
+        .unwrap()
+    }
+
     pub async fn query_path_info(&self, path: StorePath<String>) -> Result<PathInfo> {
         let inner = self.inner.clone();
 
@@ -70,24 +125,58 @@ impl Store {
                 .collect::<Result<_, _>>()
                 .context("get references from pathinfo")?;
             let nar_size = c_path_info.pin_mut().nar_size();
+            let nar_hash = c_path_info
+                .pin_mut()
+                .nar_hash()
+                .try_into()
+                .context("nar hash is not sha256")?;
+            let ultimate = c_path_info.pin_mut().ultimate();
+            let ca = Some(c_path_info.pin_mut().ca()).filter(|ca| !ca.is_empty());
+            let deriver = Some(c_path_info.pin_mut().deriver())
+                .filter(|deriver| !deriver.is_empty())
+                .map(|deriver| StorePath::from_bytes(deriver.as_bytes()))
+                .transpose()
+                .context("get deriver from pathinfo")?;
 
             Ok(PathInfo {
                 path,
                 signatures,
                 references,
                 nar_size,
+                nar_hash,
+                ca,
+                deriver,
+                // reading the derivation is left to the uploads that need it
+                system: None,
+                ultimate,
             })
         })
         .await
         .unwrap()
     }
 
-    pub fn nar_from_path(&self, store_path: StorePath<String>) -> impl AsyncRead {
+    /// the system `drv` builds for, `None` if the derivation isn't in the store
+    pub async fn derivation_system(&self, drv: StorePath<String>) -> Result<Option<String>> {
+        let inner = self.inner.clone();
+        task::spawn_blocking(move || {
+            let system = inner
+                .store()
+                .derivation_system(drv.to_string().as_bytes())
+                .context(format!("read system from {}", drv.to_absolute_path()))?;
+            Ok(Some(system).filter(|system| !system.is_empty()))
+        })
+        .await
+        .unwrap()
+    }
+
+    pub fn nar_from_path(&self, store_path: StorePath<String>) -> impl AsyncRead + use<> {
         let inner = self.inner.clone();
         let (adapter, mut sender) = AsyncWriteAdapter::new();
         let base_name = store_path.to_string().as_bytes().to_vec();
+        let span = debug_span!("nar_from_path", path = %store_path.to_absolute_path());
 
         tokio::task::spawn_blocking(move || {
+            let _span = span.enter();
             // Send all exceptions through the channel, and ignore errors
             // during sending (the channel may have been closed).
             if let Err(e) = inner.store().nar_from_path(base_name, sender.clone()) {
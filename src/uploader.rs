@@ -1,64 +1,355 @@
-use anyhow::Result;
-use bytes::BytesMut;
-use nix_compat::{narinfo::SigningKey, nixbase32};
-use object_store::{ObjectStore, aws::AmazonS3, buffered::BufWriter, path::Path};
-use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tracing::{debug, trace};
+use anyhow::{Context, Result, bail, ensure};
+use bytes::{Bytes, BytesMut};
+use clap::ValueEnum;
+use nix_compat::{
+    narinfo::{NarInfo, SigningKey, VerifyingKey},
+    nixbase32,
+};
+use object_store::{ObjectStore, WriteMultipart, path::Path};
+use std::{
+    fmt,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufWriter},
+};
+use tracing::{Instrument, Span, debug, debug_span, field, instrument, trace};
 use ulid::Ulid;
 
-use crate::{make_nar::MakeNar, path_info::PathInfo, store::Store};
+use crate::{
+    encryption::{self, EncryptionKey},
+    make_nar::MakeNar,
+    manifest::ManifestEntry,
+    path_info::PathInfo,
+    store::Store,
+};
 
-const CHUNK_SIZE: usize = 1024 * 1024 * 5;
+/// part size of multipart uploads
+pub(crate) const CHUNK_SIZE: usize = 1024 * 1024 * 5;
+/// most parts s3 accepts for a multipart upload
+const MAX_PARTS: u64 = 10_000;
+/// window log zstd uses at the default level without long distance matching
+const DEFAULT_WINDOW_LOG: u32 = 21;
+/// file descriptors counted on top for streaming the nar of a path with many files
+const MANY_FILES_FDS: u32 = 16;
+
+/// knobs that apply to every upload of a push
+#[derive(Debug, Clone)]
+pub struct UploadOptions {
+    /// max number of parts of a single nar that are uploaded concurrently
+    pub part_concurrency: usize,
+    /// nars up to this size are buffered in memory and put directly to their final path
+    pub direct_put_threshold: u64,
+    /// larger nars up to this size are compressed to a file in `spill_dir` and uploaded to
+    /// their final path from there
+    pub spill_threshold: Option<u64>,
+    pub spill_dir: PathBuf,
+    /// enable zstd long distance matching with this window log
+    pub zstd_window_log: Option<u32>,
+    /// upload nars uncompressed if a compressed sample is at least this fraction of its
+    /// original size
+    pub incompressible_threshold: Option<f64>,
+    /// encrypt nars with this key after compressing them
+    pub encryption_key: Option<EncryptionKey>,
+    /// how nars that aren't buffered in memory get to their final path
+    pub finalize_strategy: FinalizeStrategy,
+    /// where temp objects go before they are renamed, the root of the cache if `None`
+    pub temp_prefix: Option<Path>,
+    pub nar_url_template: NarUrlTemplate,
+}
+
+impl UploadOptions {
+    /// rough upper bound of the memory an upload of a nar of `nar_size` holds at once
+    pub fn memory_estimate(&self, nar_size: u64) -> u64 {
+        // the encoder keeps its window and about as much again in tables and compressed
+        // chunks waiting to be read
+        let encoder = 2 << self.zstd_window_log.unwrap_or(DEFAULT_WINDOW_LOG);
+        let buffers = if !self.is_multipart(nar_size) {
+            nar_size
+        } else {
+            // parts in flight plus the one being filled
+            (self.part_concurrency as u64 + 1) * part_size(nar_size) as u64
+        };
+        encoder + buffers
+    }
+
+    /// file descriptors an upload of a nar of `nar_size` holds at once. `many_files` is for
+    /// paths whose nar is made from so many files that the store holds more of them open
+    /// while streaming it
+    pub fn fd_estimate(&self, nar_size: u64, many_files: bool) -> u32 {
+        // the nar is streamed from the store over one connection
+        let stream = if many_files { 2 + MANY_FILES_FDS } else { 2 };
+        if self.is_multipart(nar_size) {
+            // a connection per part in flight
+            stream + self.part_concurrency as u32
+        } else {
+            stream
+        }
+    }
+
+    /// whether a nar of `nar_size` is uploaded in parts rather than buffered in memory
+    pub fn is_multipart(&self, nar_size: u64) -> bool {
+        nar_size > self.direct_put_threshold.max(CHUNK_SIZE as u64)
+    }
+}
+
+/// where a compressed nar is uploaded, relative to the root of the cache.
+/// `{filehash}` is replaced with the nixbase32 hash of the compressed nar and `{ext}` with
+/// the extension of its compression, e.g. `.zst`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NarUrlTemplate(String);
+
+impl FromStr for NarUrlTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // nars with different contents would overwrite each other
+        ensure!(
+            s.contains("{filehash}"),
+            "nar url template {s} must contain {{filehash}}"
+        );
+        let template = Self(s.to_string());
+        template.render(&[0; 32], ".zst")?;
+        Ok(template)
+    }
+}
+
+impl NarUrlTemplate {
+    pub fn render(&self, file_hash: &[u8], ext: &str) -> Result<Path> {
+        let url = self
+            .0
+            .replace("{filehash}", &nixbase32::encode(file_hash))
+            .replace("{ext}", ext);
+        Path::parse(&url).context(format!("nar url {url} is not a valid object path"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FinalizeStrategy {
+    /// Rename unless the endpoint is known to lack a native move
+    Auto,
+    /// Upload to a temp path and rename (copy and delete) to the final path
+    Rename,
+    /// Compress once to learn the file hash, then upload straight to the final path
+    Prehash,
+}
+
+impl FinalizeStrategy {
+    /// resolve `Auto` based on the configured endpoint
+    pub fn resolve(self, endpoint: Option<&str>) -> Self {
+        // these emulate rename with a slow (and sometimes size limited) server side copy
+        const NO_NATIVE_MOVE: &[&str] = &["r2.cloudflarestorage.com", "storage.googleapis.com"];
+
+        match self {
+            Self::Auto
+                if endpoint.is_some_and(|endpoint| {
+                    NO_NATIVE_MOVE.iter().any(|host| endpoint.contains(host))
+                }) =>
+            {
+                Self::Prehash
+            }
+            Self::Auto => Self::Rename,
+            strategy => strategy,
+        }
+    }
+}
 
 pub struct Uploader<'a> {
     signing_key: &'a SigningKey<ed25519_dalek::SigningKey>,
+    verifying_key: &'a VerifyingKey,
     path: PathInfo,
+    options: &'a UploadOptions,
+}
+
+/// the nar streamed from the store doesn't have the size the store has on record
+#[derive(Debug)]
+pub struct NarSizeMismatch {
+    pub path: String,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl fmt::Display for NarSizeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "nar size mismatch for {}: store says {} bytes but streamed {} bytes",
+            self.path, self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for NarSizeMismatch {}
+
+/// where the compressed nar is before it is moved to its final path
+enum CompressedNar {
+    Buffered(Vec<u8>),
+    Spilled(SpillFile),
+    Temp(Path),
+    /// already uploaded to its final path, the expected file hash is attached
+    Final(Path, [u8; 32]),
 }
 
 impl<'a> Uploader<'a> {
     pub fn new(
         signing_key: &'a SigningKey<ed25519_dalek::SigningKey>,
+        verifying_key: &'a VerifyingKey,
         path: PathInfo,
+        options: &'a UploadOptions,
     ) -> Result<Self> {
-        Ok(Self { signing_key, path })
+        Ok(Self {
+            signing_key,
+            verifying_key,
+            path,
+            options,
+        })
     }
 
-    pub async fn upload(&self, s3: Arc<AmazonS3>, store: Arc<Store>) -> Result<()> {
-        let mut nar = MakeNar::new(&self.path, store)?;
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(path = %self.path.absolute_path(), nar_size = self.path.nar_size)
+    )]
+    pub async fn upload(
+        &self,
+        s3: Arc<dyn ObjectStore>,
+        store: Arc<Store>,
+    ) -> Result<ManifestEntry> {
+        let start = Instant::now();
+        // time spent on a compression pass that only learns the file hash
+        let mut prehash_time = Duration::ZERO;
+        let mut nar =
+            MakeNar::new(&self.path, store)?.long_distance_matching(self.options.zstd_window_log);
+        if let Some(threshold) = self.options.incompressible_threshold
+            && self.path.nar_size > CHUNK_SIZE as u64
+        {
+            let ratio = nar.sample_compression_ratio().await?;
+            debug!(
+                "sampled compression ratio for {}: {ratio:.3}",
+                self.path.absolute_path()
+            );
+            if ratio >= threshold {
+                nar = nar.without_compression();
+            }
+        }
+        if let Some(key) = &self.options.encryption_key {
+            nar = nar.encrypted(key.clone());
+        }
 
-        // we don't know what the hash of the compressed file will be so upload to a
-        // temp location for now
-        let temp_path = Path::parse(Ulid::new().to_string())?;
-        let mut s3_writer = BufWriter::new(s3.clone(), temp_path.clone());
-        debug!("uploading to temp path: {}", temp_path);
+        let ext = nar.extension().to_string();
 
         // compress and upload nar
-        let mut file_reader = nar.compress_and_hash()?;
-        loop {
-            let mut buf = BytesMut::with_capacity(CHUNK_SIZE);
-            let n = file_reader.read_buf(&mut buf).await?;
-            s3_writer.put(buf.freeze()).await?;
-            if n == 0 {
-                break;
+        let compressed = if self.path.nar_size <= self.options.direct_put_threshold {
+            let mut file_reader = nar.compress_and_hash()?;
+            let mut buf = Vec::new();
+            file_reader.read_to_end(&mut buf).await?;
+            CompressedNar::Buffered(buf)
+        } else if self
+            .options
+            .spill_threshold
+            .is_some_and(|threshold| self.path.nar_size <= threshold)
+        {
+            let spill = SpillFile::create(&self.options.spill_dir).await?;
+            debug!("compressing to {:?}", spill.0);
+            let mut file_reader = nar.compress_and_hash()?;
+            let mut file = BufWriter::with_capacity(CHUNK_SIZE, spill.open_write().await?);
+            tokio::io::copy(&mut file_reader, &mut file)
+                .await
+                .context(format!("write {:?}", spill.0))?;
+            file.flush().await?;
+            CompressedNar::Spilled(spill)
+        } else if self.options.finalize_strategy == FinalizeStrategy::Prehash {
+            // compressing is deterministic so a throwaway pass tells us the final path
+            let mut prehash = nar.clone();
+            let mut file_reader = prehash.compress_and_hash()?;
+            tokio::io::copy(&mut file_reader, &mut tokio::io::sink()).await?;
+            drop(file_reader);
+            prehash_time = prehash.compress_time;
+            let file_hash = prehash
+                .get_narinfo()?
+                .file_hash
+                .expect("file hash must be known at this point");
+
+            let real_path = self.options.nar_url_template.render(&file_hash, &ext)?;
+            let mut file_reader = nar.compress_and_hash()?;
+            self.upload_stream(&s3, &real_path, &mut file_reader)
+                .await?;
+            CompressedNar::Final(real_path, file_hash)
+        } else {
+            // we don't know what the hash of the compressed file will be so upload to a
+            // temp location for now
+            let temp_path = match &self.options.temp_prefix {
+                Some(temp_prefix) => temp_prefix.child(Ulid::new().to_string()),
+                None => Path::parse(Ulid::new().to_string())?,
+            };
+            debug!("uploading to temp path: {}", temp_path);
+            let mut file_reader = nar.compress_and_hash()?;
+            self.upload_stream(&s3, &temp_path, &mut file_reader)
+                .await?;
+            CompressedNar::Temp(temp_path)
+        };
+
+        // the store changed under us or is corrupt, don't publish a narinfo that lies
+        if nar.nar_size != self.path.nar_size {
+            match &compressed {
+                CompressedNar::Temp(path) | CompressedNar::Final(path, _) => {
+                    s3.delete(path).await?
+                }
+                CompressedNar::Buffered(_) | CompressedNar::Spilled(_) => {}
             }
+            return Err(NarSizeMismatch {
+                path: self.path.absolute_path(),
+                expected: self.path.nar_size,
+                actual: nar.nar_size,
+            }
+            .into());
         }
-        drop(file_reader);
 
+        let compress_time = prehash_time + nar.compress_time;
         let mut nar_info = nar.get_narinfo()?;
         nar_info.add_signature(self.signing_key);
 
         // now that we can calculate the file_hash move the nar to where it should be
-        let real_path = nar_url(
+        let real_path = self.options.nar_url_template.render(
             &nar_info
                 .file_hash
                 .expect("file hash must be known at this point"),
-        );
-        debug!("moving {} to {}", temp_path, real_path);
-        // the temp object must be done uploading
-        s3_writer.shutdown().await?;
-        // this is implemented as a copy-and-delete
-        s3.rename(&temp_path, &real_path).await?;
+            &ext,
+        )?;
+        match compressed {
+            CompressedNar::Buffered(buf) => {
+                debug!("uploading {} directly", real_path);
+                let span = debug_span!("put", path = %real_path, bytes = buf.len());
+                s3.put(&real_path, buf.into()).instrument(span).await?;
+            }
+            CompressedNar::Spilled(spill) => {
+                debug!("uploading {:?} to {}", spill.0, real_path);
+                let mut file = File::open(&spill.0)
+                    .await
+                    .context(format!("open {:?}", spill.0))?;
+                self.upload_stream(&s3, &real_path, &mut file).await?;
+            }
+            CompressedNar::Temp(temp_path) => {
+                debug!("moving {} to {}", temp_path, real_path);
+                // this is implemented as a copy-and-delete
+                s3.rename(&temp_path, &real_path)
+                    .instrument(debug_span!("rename", from = %temp_path, to = %real_path))
+                    .await?;
+            }
+            CompressedNar::Final(path, file_hash) => {
+                if nar_info.file_hash != Some(file_hash) {
+                    s3.delete(&path).await?;
+                    bail!(
+                        "compressed nar for {} changed between passes",
+                        self.path.absolute_path()
+                    );
+                }
+            }
+        }
         // set nar url in narinfo
         nar_info.url = real_path.as_ref();
 
@@ -66,15 +357,160 @@ impl<'a> Uploader<'a> {
         let narinfo_path = self.path.narinfo_path();
         debug!("uploading narinfo: {}", narinfo_path);
         trace!("narinfo: {:#}", nar_info);
-        s3.put(&narinfo_path, nar_info.to_string().into()).await?;
+        let entry = ManifestEntry {
+            store_path: self.path.absolute_path(),
+            narinfo_url: narinfo_path.to_string(),
+            nar_url: Some(real_path.to_string()),
+            file_hash: nar_info.file_hash.as_ref().map(ManifestEntry::format_hash),
+            file_size: nar_info.file_size,
+            nar_size: nar_info.nar_size,
+            compress_secs: Some(compress_time.as_secs_f64()),
+            elapsed_secs: None,
+        };
+        let nar_info = nar_info.to_string();
+        validate_narinfo(&nar_info, self.verifying_key).context(format!(
+            "validate narinfo for {}",
+            self.path.absolute_path()
+        ))?;
+        let encryption = self
+            .options
+            .encryption_key
+            .as_ref()
+            .map(|key| key.narinfo_value());
+        let nar_info = encryption::with_narinfo_field(nar_info, encryption.as_deref());
+        s3.put(&narinfo_path, nar_info.into())
+            .instrument(debug_span!("put", path = %narinfo_path))
+            .await?;
+
+        Ok(ManifestEntry {
+            elapsed_secs: Some(start.elapsed().as_secs_f64()),
+            ..entry
+        })
+    }
+
+    /// upload the compressed nar from `file_reader` to `path`
+    #[instrument(level = "debug", skip_all, fields(%path, bytes = field::Empty))]
+    async fn upload_stream(
+        &self,
+        s3: &Arc<dyn ObjectStore>,
+        path: &Path,
+        file_reader: &mut (impl AsyncRead + Unpin),
+    ) -> Result<()> {
+        if self.path.nar_size <= CHUNK_SIZE as u64 {
+            // fits in a single request
+            let mut buf = Vec::with_capacity(self.path.nar_size as usize);
+            file_reader.read_to_end(&mut buf).await?;
+            Span::current().record("bytes", buf.len());
+            s3.put(path, buf.into()).await?;
+            return Ok(());
+        }
+
+        // upload parts of large nars concurrently. each part is read into a buffer of exactly
+        // `part_size` that is handed to the upload as is and reused once the part is done so
+        // at most `part_concurrency` parts are buffered at a time
+        let part_size = part_size(self.path.nar_size);
+        let upload = s3.put_multipart(path).await?;
+        let mut s3_writer = WriteMultipart::new_with_chunk_size(upload, part_size);
+        let mut in_flight = Vec::with_capacity(self.options.part_concurrency + 1);
+        let mut bytes = 0;
+        let res = async {
+            loop {
+                s3_writer
+                    .wait_for_capacity(self.options.part_concurrency)
+                    .await?;
+                let mut buf = reclaim_buffer(&mut in_flight, part_size);
+                while buf.len() < part_size && file_reader.read_buf(&mut buf).await? > 0 {}
+                if buf.is_empty() {
+                    break;
+                }
+                let eof = buf.len() < part_size;
+                bytes += buf.len();
+                let part = buf.freeze();
+                in_flight.push(part.clone());
+                s3_writer.put(part);
+                if eof {
+                    break;
+                }
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        if let Err(e) = res {
+            s3_writer.abort().await?;
+            return Err(e);
+        }
+        // the object must be done uploading
+        s3_writer.finish().await?;
+        Span::current().record("bytes", bytes);
 
         Ok(())
     }
 }
 
-/// calculate url where the compressed nar should be uploaded
-fn nar_url(file_hash: &[u8]) -> Path {
-    let compressed_nar_hash = nixbase32::encode(file_hash);
-    Path::parse(format!("nar/{compressed_nar_hash}.nar.zst"))
-        .expect("should parse to a valid object_store::path::Path")
+/// a compressed nar on local disk, deleted when dropped
+struct SpillFile(PathBuf);
+
+impl SpillFile {
+    async fn create(dir: &std::path::Path) -> Result<Self> {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .context(format!("create {dir:?}"))?;
+        Ok(Self(dir.join(format!("nixcp-{}.nar", Ulid::new()))))
+    }
+
+    async fn open_write(&self) -> Result<File> {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&self.0)
+            .await
+            .context(format!("create {:?}", self.0))
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        // may not have been created if opening it failed
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// size of the parts of a multipart upload of a nar of `nar_size`. nars so large that they
+/// would need more than `MAX_PARTS` parts of `CHUNK_SIZE` get larger parts, with some headroom
+/// for compression growing incompressible data
+fn part_size(nar_size: u64) -> usize {
+    let min_part_size = (nar_size + nar_size / 100).div_ceil(MAX_PARTS);
+    CHUNK_SIZE.max(min_part_size as usize)
+}
+
+/// the buffer of a part that is done uploading, or a new one if all are still in flight
+fn reclaim_buffer(in_flight: &mut Vec<Bytes>, part_size: usize) -> BytesMut {
+    if let Some(i) = in_flight.iter().position(Bytes::is_unique)
+        && let Ok(mut buf) = in_flight.swap_remove(i).try_into_mut()
+    {
+        buf.clear();
+        return buf;
+    }
+    BytesMut::with_capacity(part_size)
+}
+
+/// make sure the narinfo we are about to publish parses back to itself and carries a valid
+/// signature from our key
+fn validate_narinfo(nar_info: &str, verifying_key: &VerifyingKey) -> Result<()> {
+    let parsed = NarInfo::parse(nar_info).context("parse generated narinfo")?;
+    ensure!(
+        parsed.to_string() == nar_info,
+        "narinfo does not round-trip through the parser"
+    );
+
+    let fingerprint = parsed.fingerprint();
+    ensure!(
+        parsed
+            .signatures
+            .iter()
+            .any(|signature| verifying_key.verify(&fingerprint, signature)),
+        "narinfo is not signed by {}",
+        verifying_key.name()
+    );
+    Ok(())
 }
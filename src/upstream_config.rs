@@ -0,0 +1,172 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, ensure};
+use reqwest::header::{HeaderName, HeaderValue};
+use serde::Deserialize;
+use url::Url;
+
+use crate::{http::UpstreamHeader, prune::parse_age};
+
+const DEFAULT_UPSTREAM: &str = "https://cache.nixos.org";
+
+/// upstreams and their options read from `--upstreams-file`, so that many upstreams don't
+/// need a giant command line. upstream flags on the command line add to it
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamsFile {
+    /// leave out cache.nixos.org like --no-default-upstream
+    #[serde(default)]
+    pub no_default_upstream: bool,
+    #[serde(default)]
+    pub upstreams: Vec<UpstreamConfig>,
+}
+
+/// one upstream of an `UpstreamsFile`. its options only apply to upstreams checked over http,
+/// and those are told apart by their host
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    pub url: String,
+    /// disabled upstreams are left out, even cache.nixos.org, unless given with --upstream
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    /// how long a request may take, like `10s`
+    pub timeout: Option<String>,
+    /// sent with every request to this upstream like --header
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// requests per second, instead of --upstream-rps
+    pub rps: Option<f64>,
+    /// instead of --upstream-burst
+    pub burst: Option<u32>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// the rate limit of one upstream. without a burst it gets --upstream-burst
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub rps: f64,
+    pub burst: Option<u32>,
+}
+
+impl UpstreamsFile {
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path).context(format!("read {path:?}"))?;
+        Self::parse(&contents).context(format!("parse {path:?}"))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let file: Self = serde_json::from_str(contents)?;
+        for upstream in &file.upstreams {
+            upstream.parse_url()?;
+            if let Some(timeout) = &upstream.timeout {
+                parse_age(timeout).context(format!("timeout of {}", upstream.url))?;
+            }
+            if let Some(rps) = upstream.rps {
+                ensure!(rps > 0.0, "rps of {} must be positive", upstream.url);
+            }
+        }
+        Ok(file)
+    }
+
+    /// cache.nixos.org unless it is left out, the enabled upstreams of the file and then
+    /// `extra` from the command line, each once
+    pub fn upstreams(&self, no_default_upstream: bool, extra: &[String]) -> Result<Vec<Url>> {
+        let extra = extra
+            .iter()
+            .map(|upstream| {
+                Url::parse(upstream).context(format!("failed to parse {upstream} as url"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let disabled = self
+            .upstreams
+            .iter()
+            .filter(|upstream| !upstream.enabled)
+            .map(UpstreamConfig::parse_url)
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut upstreams = Vec::with_capacity(self.upstreams.len() + extra.len() + 1);
+        if !no_default_upstream && !self.no_default_upstream {
+            upstreams
+                .push(Url::parse(DEFAULT_UPSTREAM).expect("default upstream must be a valid url"));
+        }
+        for upstream in &self.upstreams {
+            if upstream.enabled {
+                upstreams.push(upstream.parse_url()?);
+            }
+        }
+        upstreams.retain(|upstream| !disabled.contains(upstream));
+        upstreams.extend(extra);
+        let mut seen = HashSet::new();
+        upstreams.retain(|upstream| seen.insert(upstream.clone()));
+        Ok(upstreams)
+    }
+
+    /// the headers of every upstream, as if given with `--header HOST=NAME: VALUE`
+    pub fn headers(&self) -> Result<Vec<UpstreamHeader>> {
+        let mut headers = Vec::new();
+        for upstream in self.upstreams.iter().filter(|x| !x.headers.is_empty()) {
+            let host = upstream.host()?;
+            for (name, value) in &upstream.headers {
+                let context = || format!("invalid header {name} for {}", upstream.url);
+                headers.push(UpstreamHeader {
+                    host: Some(host.clone()),
+                    name: HeaderName::try_from(name.as_str()).with_context(context)?,
+                    value: HeaderValue::try_from(value.as_str()).with_context(context)?,
+                });
+            }
+        }
+        Ok(headers)
+    }
+
+    /// request timeouts by host
+    pub fn timeouts(&self) -> Result<Vec<(String, Duration)>> {
+        self.upstreams
+            .iter()
+            .filter_map(|upstream| {
+                let timeout = upstream.timeout.as_deref()?;
+                Some(upstream.host().and_then(|host| {
+                    let timeout =
+                        parse_age(timeout).context(format!("timeout of {}", upstream.url))?;
+                    Ok((host, timeout))
+                }))
+            })
+            .collect()
+    }
+
+    /// rate limits by host
+    pub fn rate_limits(&self) -> Result<Vec<(String, RateLimit)>> {
+        self.upstreams
+            .iter()
+            .filter_map(|upstream| {
+                let rps = upstream.rps?;
+                let limit = RateLimit {
+                    rps,
+                    burst: upstream.burst,
+                };
+                Some(upstream.host().map(|host| (host, limit)))
+            })
+            .collect()
+    }
+}
+
+impl UpstreamConfig {
+    fn parse_url(&self) -> Result<Url> {
+        Url::parse(&self.url).context(format!("failed to parse {} as url", self.url))
+    }
+
+    fn host(&self) -> Result<String> {
+        self.parse_url()?
+            .host_str()
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("{} has no host", self.url))
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use anyhow::{Context, Result, bail, ensure};
+use nix_compat::{
+    narinfo::{NarInfo, SigningKey, VerifyingKey},
+    nixbase32,
+    store_path::StorePath,
+};
+use object_store::{ObjectStore, buffered::BufWriter, path::Path as ObjectPath};
+use reqwest::Method;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, trace};
+use url::Url;
+
+use crate::{http::UpstreamClient, manifest::ManifestEntry};
+
+/// a path that we don't have locally but an upstream does
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UpstreamPath {
+    pub path: StorePath<String>,
+    /// where the narinfo was found, nar urls are relative to this
+    narinfo_url: Url,
+    narinfo: String,
+}
+
+impl UpstreamPath {
+    /// fetch the narinfo for `path` from the first upstream that has it
+    pub async fn find(
+        path: &StorePath<String>,
+        upstreams: &[Url],
+        client: &UpstreamClient,
+    ) -> Result<Option<Self>> {
+        let narinfo_name = format!("{}.narinfo", nixbase32::encode(path.digest()));
+        for upstream in upstreams {
+            let narinfo_url = upstream
+                .join(&narinfo_name)
+                .expect("adding <hash>.narinfo should make a valid url");
+            trace!("querying {}", narinfo_url);
+            let res = client
+                .send(client.request(Method::GET, &narinfo_url).await)
+                .await
+                .context(format!("fetch {narinfo_url}"))?;
+            if res.status().is_success() {
+                let narinfo = res.text().await.context(format!("read {narinfo_url}"))?;
+                return Ok(Some(Self {
+                    path: path.clone(),
+                    narinfo_url,
+                    narinfo,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// the closure of `path` as advertised by the references in upstream narinfos
+    pub async fn closure(
+        path: StorePath<String>,
+        upstreams: &[Url],
+        client: &UpstreamClient,
+    ) -> Result<Vec<Self>> {
+        let mut closure = Vec::new();
+        let mut seen = HashSet::from([path.clone()]);
+        let mut queue = vec![path];
+
+        while let Some(path) = queue.pop() {
+            let upstream_path = Self::find(&path, upstreams, client)
+                .await?
+                .with_context(|| format!("{} is not on any upstream", path.to_absolute_path()))?;
+            for reference in upstream_path.narinfo()?.references {
+                let reference = StorePath::from_bytes(reference.to_string().as_bytes())?;
+                if seen.insert(reference.clone()) {
+                    queue.push(reference);
+                }
+            }
+            closure.push(upstream_path);
+        }
+        Ok(closure)
+    }
+
+    pub fn narinfo(&self) -> Result<NarInfo<'_>> {
+        NarInfo::parse(&self.narinfo).context(format!("parse narinfo from {}", self.narinfo_url))
+    }
+
+    pub fn narinfo_path(&self) -> ObjectPath {
+        ObjectPath::parse(format!("{}.narinfo", nixbase32::encode(self.path.digest())))
+            .expect("must parse to a valid object_store path")
+    }
+
+    /// stream the nar from upstream into `s3` at the same relative url and then publish the
+    /// narinfo. with `resign`, our signature is added once the upstream signature checked out
+    /// against one of the keys and the nar against its FileHash and FileSize
+    pub async fn copy(
+        &self,
+        s3: Arc<dyn ObjectStore>,
+        resign: Option<(&SigningKey<ed25519_dalek::SigningKey>, &[VerifyingKey])>,
+        client: &UpstreamClient,
+    ) -> Result<ManifestEntry> {
+        let mut narinfo = self.narinfo()?;
+        if let Some((_, trusted_keys)) = resign {
+            let fingerprint = narinfo.fingerprint();
+            ensure!(
+                narinfo.signatures.iter().any(|signature| trusted_keys
+                    .iter()
+                    .any(|key| key.verify(&fingerprint, signature))),
+                "no signature from a trusted key, refusing to resign"
+            );
+            ensure!(
+                narinfo.file_hash.is_some() && narinfo.file_size.is_some(),
+                "no FileHash or FileSize to check the nar against, refusing to resign"
+            );
+        }
+
+        let nar_url = self
+            .narinfo_url
+            .join(narinfo.url)
+            .context(format!("resolve nar url {}", narinfo.url))?;
+        // an absolute url keeps its path in our bucket, and our narinfo points there
+        let nar_path = if Url::parse(narinfo.url).is_ok() {
+            ObjectPath::from_url_path(nar_url.path())?
+        } else {
+            ObjectPath::parse(narinfo.url)?
+        };
+        debug!("copying {} to {}", nar_url, nar_path);
+        let mut res = client
+            .send(client.request(Method::GET, &nar_url).await)
+            .await
+            .and_then(|res| res.error_for_status())
+            .context(format!("fetch {nar_url}"))?;
+        let mut s3_writer = BufWriter::new(s3.clone(), nar_path.clone());
+        let mut file_hasher = Sha256::new();
+        let mut file_size = 0;
+        while let Some(chunk) = res.chunk().await.context(format!("read {nar_url}"))? {
+            file_hasher.update(&chunk);
+            file_size += chunk.len() as u64;
+            s3_writer.put(chunk).await?;
+        }
+        let file_hash: [u8; 32] = file_hasher.finalize().into();
+        if narinfo.file_hash.is_some_and(|hash| hash != file_hash)
+            || narinfo.file_size.is_some_and(|size| size != file_size)
+        {
+            s3_writer.abort().await?;
+            bail!("{nar_url} doesn't match the FileHash or FileSize of its narinfo");
+        }
+        s3_writer.shutdown().await?;
+
+        let nar_key = nar_path.to_string();
+        narinfo.url = &nar_key;
+        if let Some((signing_key, _)) = resign {
+            narinfo.add_signature(signing_key);
+        }
+        let narinfo_path = self.narinfo_path();
+        debug!("uploading narinfo: {}", narinfo_path);
+        s3.put(&narinfo_path, narinfo.to_string().into()).await?;
+
+        Ok(ManifestEntry {
+            store_path: self.path.to_absolute_path(),
+            narinfo_url: narinfo_path.to_string(),
+            nar_url: Some(narinfo.url.to_string()),
+            file_hash: narinfo.file_hash.as_ref().map(ManifestEntry::format_hash),
+            file_size: narinfo.file_size,
+            nar_size: narinfo.nar_size,
+            compress_secs: None,
+            elapsed_secs: None,
+        })
+    }
+}
@@ -0,0 +1,75 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+/// how a single upstream check went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckOutcome {
+    Hit,
+    Miss,
+    Error,
+}
+
+/// what one upstream did for a push
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UpstreamCounters {
+    pub checks: usize,
+    /// paths we skipped because of this upstream
+    pub hits: usize,
+    pub errors: usize,
+    pub total_latency: Duration,
+}
+
+impl UpstreamCounters {
+    pub fn average_latency(&self) -> Duration {
+        match self.checks {
+            0 => Duration::ZERO,
+            checks => self.total_latency / checks as u32,
+        }
+    }
+}
+
+/// per upstream counters, so that slow or useless upstreams can be spotted and removed
+#[derive(Debug, Default)]
+pub struct UpstreamStats {
+    upstreams: Mutex<BTreeMap<String, UpstreamCounters>>,
+    /// responses that asked us to slow down
+    throttled: AtomicUsize,
+}
+
+impl UpstreamStats {
+    pub fn record(&self, upstream: &str, latency: Duration, outcome: CheckOutcome) {
+        let mut upstreams = self.upstreams.lock().unwrap();
+        let counters = upstreams.entry(upstream.to_string()).or_default();
+        counters.checks += 1;
+        counters.total_latency += latency;
+        match outcome {
+            CheckOutcome::Hit => counters.hits += 1,
+            CheckOutcome::Miss => {}
+            CheckOutcome::Error => counters.errors += 1,
+        }
+    }
+
+    pub fn record_throttled(&self) {
+        self.throttled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn throttled(&self) -> usize {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    /// counters of every upstream that was checked, by name
+    pub fn snapshot(&self) -> Vec<(String, UpstreamCounters)> {
+        self.upstreams
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(upstream, counters)| (upstream.clone(), counters.clone()))
+            .collect()
+    }
+}
@@ -0,0 +1,189 @@
+use std::{collections::HashMap, io, sync::Arc};
+
+use anyhow::{Context, Result, bail, ensure};
+use futures::{StreamExt, TryStreamExt, stream};
+use nix_compat::{narinfo::NarInfo, nixbase32};
+use object_store::{ObjectMeta, ObjectStore, path::Path};
+use sha2::{Digest, Sha256};
+use tokio::io::BufReader;
+use tokio_util::io::{InspectReader, StreamReader};
+
+use crate::{
+    VerifyArgs,
+    encryption::{self, EncryptionKey},
+    fetch,
+};
+
+pub struct Verify {
+    s3: Arc<dyn ObjectStore>,
+    // download and rehash every nar
+    deep: bool,
+    // to decrypt nars pushed with --encryption-key
+    encryption_key: Option<EncryptionKey>,
+}
+
+/// how much of a path could be checked
+enum Checked {
+    Fully,
+    /// the nar is encrypted with this and we don't have the key, only its size was checked
+    Encrypted(String),
+}
+
+impl Verify {
+    pub fn new(cli: &VerifyArgs) -> Result<Self> {
+        Ok(Self {
+            s3: cli.bucket.build()?,
+            deep: cli.deep,
+            encryption_key: cli
+                .encryption_key
+                .as_deref()
+                .map(EncryptionKey::read)
+                .transpose()?,
+        })
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        let objects: HashMap<String, ObjectMeta> = self
+            .s3
+            .list(None)
+            .map_ok(|meta| (meta.location.to_string(), meta))
+            .try_collect()
+            .await
+            .context("list objects in bucket")?;
+
+        // narinfos live at the root of the cache
+        let narinfo_keys: Vec<&String> = objects
+            .keys()
+            .filter(|key| key.ends_with(".narinfo") && !key.contains('/'))
+            .collect();
+        println!("verifying {} paths", narinfo_keys.len());
+        let objects = &objects;
+        let results: Vec<_> = stream::iter(&narinfo_keys)
+            .map(|key| async move { (key, self.verify_path(key, objects).await) })
+            .buffer_unordered(if self.deep { 8 } else { 32 })
+            .collect()
+            .await;
+
+        let mut corrupt_count = 0;
+        let mut unchecked_count = 0;
+        for (key, res) in results {
+            match res {
+                Ok(Checked::Fully) => {}
+                Ok(Checked::Encrypted(field)) => {
+                    println!(
+                        "unchecked: {key}: nar is encrypted with {field}, pass --encryption-key \
+                         to check it"
+                    );
+                    unchecked_count += 1;
+                }
+                Err(e) => {
+                    println!("corrupt: {key}: {e:#}");
+                    corrupt_count += 1;
+                }
+            }
+        }
+        println!(
+            "verified {} paths, {corrupt_count} corrupt, {unchecked_count} unchecked",
+            narinfo_keys.len()
+        );
+        if corrupt_count > 0 {
+            bail!("found {corrupt_count} corrupt paths");
+        }
+        Ok(())
+    }
+
+    async fn verify_path(
+        &self,
+        key: &str,
+        objects: &HashMap<String, ObjectMeta>,
+    ) -> Result<Checked> {
+        let narinfo = self
+            .s3
+            .get(&Path::from(key))
+            .await?
+            .bytes()
+            .await
+            .context("fetch narinfo")?;
+        let narinfo = std::str::from_utf8(&narinfo).context("narinfo is not utf-8")?;
+        let encryption = encryption::narinfo_field(narinfo);
+        let narinfo = NarInfo::parse(narinfo).context("parse narinfo")?;
+
+        let Some(nar) = objects.get(narinfo.url) else {
+            bail!("nar {} is missing", narinfo.url);
+        };
+        if let Some(file_size) = narinfo.file_size {
+            ensure!(
+                nar.size == file_size,
+                "nar {} is {} bytes but narinfo says {file_size}",
+                narinfo.url,
+                nar.size
+            );
+        }
+        if !self.deep {
+            return Ok(Checked::Fully);
+        }
+        let decryption = match (encryption, &self.encryption_key) {
+            (Some(field), Some(key)) => Some((field, key)),
+            (Some(field), None) => return Ok(Checked::Encrypted(field.to_string())),
+            (None, _) => None,
+        };
+        self.verify_nar(&narinfo, decryption)
+            .await
+            .context(format!("verify nar {}", narinfo.url))?;
+        Ok(Checked::Fully)
+    }
+
+    /// stream the nar, decrypt and decompress it and compare its hashes and size with the
+    /// narinfo
+    async fn verify_nar(
+        &self,
+        narinfo: &NarInfo<'_>,
+        decryption: Option<(&str, &EncryptionKey)>,
+    ) -> Result<()> {
+        let stream = self
+            .s3
+            .get(&Path::from(narinfo.url))
+            .await?
+            .into_stream()
+            .map_err(io::Error::other);
+
+        let mut file_hasher = Sha256::new();
+        let mut nar_hasher = Sha256::new();
+        let mut nar_size = 0;
+        {
+            // reader that hashes the stored nar, encrypted or not, as it is read
+            let file_reader = BufReader::new(InspectReader::new(StreamReader::new(stream), |x| {
+                file_hasher.update(x)
+            }));
+            let nar_reader = fetch::decoded(file_reader, narinfo, decryption, true).await?;
+            let mut nar_reader = InspectReader::new(nar_reader, |x| {
+                nar_size += x.len() as u64;
+                nar_hasher.update(x);
+            });
+            tokio::io::copy(&mut nar_reader, &mut tokio::io::sink()).await?;
+        }
+
+        if let Some(file_hash) = narinfo.file_hash {
+            let actual: [u8; 32] = file_hasher.finalize().into();
+            ensure!(
+                actual == file_hash,
+                "file hash is {} but narinfo says {}",
+                nixbase32::encode(&actual),
+                nixbase32::encode(&file_hash)
+            );
+        }
+        ensure!(
+            nar_size == narinfo.nar_size,
+            "nar size is {nar_size} but narinfo says {}",
+            narinfo.nar_size
+        );
+        let actual: [u8; 32] = nar_hasher.finalize().into();
+        ensure!(
+            actual == narinfo.nar_hash,
+            "nar hash is {} but narinfo says {}",
+            nixbase32::encode(&actual),
+            nixbase32::encode(&narinfo.nar_hash)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+use anyhow::{Context, Result, anyhow, ensure};
+use bytes::Bytes;
+use futures::{StreamExt, TryStreamExt, stream};
+use object_store::{
+    CredentialProvider,
+    aws::{AwsAuthorizer, AwsCredentialProvider},
+    client::{HttpRequest, HttpRequestBody},
+};
+use reqwest::{Client, Method};
+use tracing::debug;
+use url::Url;
+
+use crate::{http::USER_AGENT, s3_uri::xml_text};
+
+/// one version of an object, or a delete marker, in a bucket with versioning enabled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectVersion {
+    /// relative to the root of the cache like the keys of the object store
+    pub key: String,
+    pub version_id: String,
+    pub is_latest: bool,
+    pub delete_marker: bool,
+    /// 0 for delete markers
+    pub size: u64,
+}
+
+/// where a listing left off, to continue it with the next page
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionMarker {
+    pub key: String,
+    pub version_id: String,
+}
+
+/// the object versions of a bucket. object_store has no api for them, so these are signed
+/// requests of our own with the credentials it found
+#[derive(Debug)]
+pub struct BucketVersions {
+    client: Client,
+    /// keys are appended to this
+    url: Url,
+    region: String,
+    credentials: AwsCredentialProvider,
+    /// keys are under this prefix in the bucket
+    prefix: Option<String>,
+}
+
+impl BucketVersions {
+    pub fn new(
+        url: Url,
+        region: String,
+        credentials: AwsCredentialProvider,
+        prefix: Option<&str>,
+    ) -> Result<Self> {
+        Ok(Self {
+            client: Client::builder()
+                .user_agent(USER_AGENT)
+                .build()
+                .context("build http client")?,
+            url,
+            region,
+            credentials,
+            prefix: prefix
+                .map(|prefix| prefix.trim_matches('/').to_string())
+                .filter(|prefix| !prefix.is_empty()),
+        })
+    }
+
+    /// versions that are no longer current and delete markers, which a bucket with
+    /// versioning keeps around and bills for after an object was overwritten or deleted
+    pub async fn noncurrent(&self) -> Result<Vec<ObjectVersion>> {
+        let mut noncurrent = Vec::new();
+        let mut marker = None;
+        loop {
+            let mut url = self.url.clone();
+            {
+                let mut query = url.query_pairs_mut();
+                query.append_pair("versions", "");
+                if let Some(prefix) = &self.prefix {
+                    query.append_pair("prefix", &format!("{prefix}/"));
+                }
+                if let Some(VersionMarker { key, version_id }) = &marker {
+                    query.append_pair("key-marker", key);
+                    query.append_pair("version-id-marker", version_id);
+                }
+            }
+            let body = self.send(Method::GET, url).await?;
+            let (versions, next) = parse_versions(&body)?;
+            noncurrent.extend(
+                versions
+                    .into_iter()
+                    .filter(|version| !version.is_latest || version.delete_marker)
+                    .filter_map(|mut version| {
+                        version.key = self.relative_key(&version.key)?.to_string();
+                        Some(version)
+                    }),
+            );
+            match next {
+                Some(next) => marker = Some(next),
+                None => return Ok(noncurrent),
+            }
+        }
+    }
+
+    /// delete exactly these versions, leaving the current ones alone
+    pub async fn delete(&self, versions: &[ObjectVersion]) -> Result<()> {
+        stream::iter(versions)
+            .map(|version| async move {
+                let mut url = self.url.clone();
+                url.path_segments_mut()
+                    .map_err(|_| anyhow!("{} can't have keys appended", self.url))?
+                    .pop_if_empty()
+                    .extend(self.prefix.iter().map(String::as_str))
+                    .extend(version.key.split('/'));
+                url.query_pairs_mut()
+                    .append_pair("versionId", &version.version_id);
+                debug!("delete {} version {}", version.key, version.version_id);
+                self.send(Method::DELETE, url).await.context(format!(
+                    "delete {} version {}",
+                    version.key, version.version_id
+                ))
+            })
+            .buffer_unordered(32)
+            .try_collect::<Vec<_>>()
+            .await?;
+        Ok(())
+    }
+
+    fn relative_key<'a>(&self, key: &'a str) -> Option<&'a str> {
+        match &self.prefix {
+            Some(prefix) => key.strip_prefix(prefix.as_str())?.strip_prefix('/'),
+            None => Some(key),
+        }
+    }
+
+    async fn send(&self, method: Method, url: Url) -> Result<String> {
+        let credential = self
+            .credentials
+            .get_credential()
+            .await
+            .context("get credentials for the bucket")?;
+        // only used to sign, the request is sent with our own client
+        let mut request = HttpRequest::new(HttpRequestBody::from(Bytes::new()));
+        *request.method_mut() = method.clone();
+        *request.uri_mut() = url.as_str().parse().context(format!("invalid url {url}"))?;
+        AwsAuthorizer::new(&credential, "s3", &self.region).authorize(&mut request, None);
+
+        let res = self
+            .client
+            .request(method.clone(), url.clone())
+            .headers(request.headers().clone())
+            .send()
+            .await
+            .context(format!("{method} {url}"))?;
+        let status = res.status();
+        let body = res.text().await?;
+        ensure!(
+            status.is_success(),
+            "{method} {url} failed with {status}: {body}"
+        );
+        Ok(body)
+    }
+}
+
+/// the versions in a page of ListObjectVersions, and where the next page starts if there is
+/// one
+pub fn parse_versions(xml: &str) -> Result<(Vec<ObjectVersion>, Option<VersionMarker>)> {
+    let mut versions = Vec::new();
+    for (tag, delete_marker) in [("Version", false), ("DeleteMarker", true)] {
+        for element in xml_elements(xml, tag) {
+            let field = |name| {
+                xml_text(element, name)
+                    .map(unescape)
+                    .ok_or_else(|| anyhow!("no {name} in {tag} of version listing"))
+            };
+            let size = if delete_marker {
+                0
+            } else {
+                field("Size")?.parse().context("parse size of version")?
+            };
+            versions.push(ObjectVersion {
+                key: field("Key")?,
+                version_id: field("VersionId")?,
+                is_latest: field("IsLatest")? == "true",
+                delete_marker,
+                size,
+            });
+        }
+    }
+
+    let next = match xml_text(xml, "IsTruncated") {
+        Some("true") => Some(VersionMarker {
+            key: xml_text(xml, "NextKeyMarker")
+                .map(unescape)
+                .context("truncated version listing without NextKeyMarker")?,
+            version_id: xml_text(xml, "NextVersionIdMarker")
+                .map(unescape)
+                .unwrap_or_default(),
+        }),
+        _ => None,
+    };
+    Ok((versions, next))
+}
+
+/// contents of every `<tag>` in `xml`
+fn xml_elements<'a>(xml: &'a str, tag: &str) -> impl Iterator<Item = &'a str> + use<'a> {
+    let open = format!("<{tag}>");
+    let close = format!("</{tag}>");
+    let mut rest = xml;
+    std::iter::from_fn(move || {
+        let start = rest.find(&open)? + open.len();
+        let len = rest[start..].find(&close)?;
+        let element = &rest[start..start + len];
+        rest = &rest[start + len + close.len()..];
+        Some(element)
+    })
+}
+
+/// undo the escaping of xml text, keys may contain any of these
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
@@ -0,0 +1,109 @@
+use std::{fs, path::PathBuf, str::FromStr};
+
+use anyhow::{Context, Result, ensure};
+use chrono::{Local, NaiveTime};
+use tracing::error;
+
+use crate::{WatchArgs, push::Push, store::Store};
+
+/// time of day new generations may be pushed in, e.g. 22:00-06:00 which wraps past midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UploadWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl FromStr for UploadWindow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s
+            .split_once('-')
+            .context(format!("upload window {s} must look like 22:00-06:00"))?;
+        let parse = |time: &str| {
+            NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                .context(format!("invalid time {time} in upload window"))
+        };
+        let window = Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        };
+        ensure!(window.start != window.end, "upload window {s} is empty");
+        Ok(window)
+    }
+}
+
+impl UploadWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+pub struct Watch {
+    args: WatchArgs,
+}
+
+impl Watch {
+    pub fn new(cli: &WatchArgs) -> Self {
+        Self { args: cli.clone() }
+    }
+
+    fn in_upload_window(&self) -> bool {
+        self.args
+            .upload_window
+            .is_none_or(|window| window.contains(Local::now().time()))
+    }
+
+    /// push the current generation of the profile and then every new one as it appears
+    pub async fn run(&self) -> Result<()> {
+        let mut pushed: Option<PathBuf> = None;
+        let mut preflight_done = self.args.push.skip_preflight;
+        // the generation we last said is waiting for the upload window
+        let mut waiting: Option<PathBuf> = None;
+        loop {
+            let generation = fs::canonicalize(&self.args.profile)
+                .context(format!("resolve profile {:?}", self.args.profile))?;
+            if pushed.as_ref() != Some(&generation) && !self.in_upload_window() {
+                // checked again on the next poll, by when there may be a newer generation
+                if waiting.as_ref() != Some(&generation) {
+                    println!(
+                        "generation {} waits for the upload window",
+                        generation.display()
+                    );
+                    waiting = Some(generation);
+                }
+            } else if pushed.as_ref() != Some(&generation) {
+                println!("pushing generation {}", generation.display());
+                let mut args = self.args.push.clone();
+                args.paths.push(generation.clone());
+                // every push gets a fresh `Push` since it keeps counters and seen paths. they
+                // are small and generations don't change often so leaking them is fine
+                let store = Store::connect(args.store_uri()?.as_deref())?;
+                let push = Box::leak(Box::new(Push::new(&args, store).await?));
+                if !preflight_done {
+                    push.preflight()
+                        .await
+                        .context("preflight check of bucket permissions")?;
+                    preflight_done = true;
+                }
+                let res = async {
+                    push.add_paths(args.paths.clone())
+                        .await
+                        .context("add paths to push")?;
+                    push.run().await
+                }
+                .await;
+                match res {
+                    Ok(()) => pushed = Some(generation),
+                    // try again on the next poll
+                    Err(e) => error!("push of {} failed: {e:#}", generation.display()),
+                }
+            }
+            tokio::time::sleep(self.args.poll_interval).await;
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use nixcp::bloom::BloomFilter;
+
+fn digest(i: u32) -> [u8; 20] {
+    let mut digest = [0; 20];
+    digest[..4].copy_from_slice(&i.to_le_bytes());
+    digest
+}
+
+#[test]
+fn bloom_filter_has_no_false_negatives() {
+    let mut filter = BloomFilter::with_capacity(1000);
+    for i in 0..1000 {
+        filter.insert(&digest(i));
+    }
+    assert!((0..1000).all(|i| filter.contains(&digest(i))));
+
+    // about 1% is expected, leave plenty of room
+    let false_positives = (1000..11000)
+        .filter(|&i| filter.contains(&digest(i)))
+        .count();
+    assert!(false_positives < 300, "{false_positives} false positives");
+}
+
+#[test]
+fn bloom_filter_round_trip() {
+    let mut filter = BloomFilter::with_capacity(10);
+    filter.insert(&digest(1));
+    let parsed = BloomFilter::from_bytes(&filter.to_bytes()).unwrap();
+    assert_eq!(parsed, filter);
+    assert!(parsed.contains(&digest(1)));
+
+    assert!(BloomFilter::from_bytes(b"not a filter").is_err());
+}
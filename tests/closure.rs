@@ -0,0 +1,45 @@
+use nix_compat::store_path::StorePath;
+use nixcp::{closure::ClosureGraph, path_info::PathInfo};
+
+fn store_path(name: &str) -> StorePath<String> {
+    StorePath::from_bytes(format!("9bwryidal9q3g91cjm6xschfn4ikd82q-{name}").as_bytes()).unwrap()
+}
+
+fn path_info(name: &str, references: &[&str]) -> PathInfo {
+    PathInfo {
+        path: store_path(name),
+        signatures: Vec::new(),
+        references: references.iter().map(|x| store_path(x)).collect(),
+        nar_size: 0,
+        nar_hash: [0; 32],
+        ca: None,
+        deriver: None,
+        system: None,
+        ultimate: false,
+    }
+}
+
+#[test]
+fn topological_order() {
+    let graph = ClosureGraph::new([
+        path_info("hello", &["hello", "glibc", "libidn"]),
+        path_info("libidn", &["glibc"]),
+        path_info("glibc", &["glibc", "not-in-closure"]),
+    ]);
+    assert_eq!(graph.len(), 3);
+
+    let order: Vec<_> = graph
+        .topological()
+        .iter()
+        .map(|x| x.path.name().to_string())
+        .collect();
+    assert_eq!(order, ["glibc", "libidn", "hello"]);
+
+    let mut referrers: Vec<_> = graph
+        .referrers(&store_path("glibc"))
+        .map(|x| x.path.name().to_string())
+        .collect();
+    referrers.sort();
+    assert_eq!(referrers, ["hello", "libidn"]);
+    assert_eq!(graph.references(&store_path("glibc")).count(), 0);
+}
@@ -19,7 +19,7 @@ impl Context {
     fn new() -> Self {
         // hello must be in the store
         ensure_exists(HELLO);
-        let store = Arc::new(Store::connect().expect("connect to nix store"));
+        let store = Arc::new(Store::connect(None).expect("connect to nix store"));
         Self { store }
     }
 }
@@ -0,0 +1,78 @@
+use std::sync::Arc;
+
+use nixcp::{
+    compression::{Compression, NoCompression, Zstd},
+    encryption::{self, Encrypted, EncryptionKey},
+};
+use tokio::io::AsyncReadExt;
+
+const KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+const OTHER_KEY: &str = "HyAhIiMkJSYnKCkqKywtLi8wMTIzNDU2Nzg5Ojs8PT4=";
+
+fn encrypt(compression: Arc<dyn Compression>, key: &EncryptionKey, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    Encrypted::new(compression, key.clone())
+        .compress(&mut &data[..], &mut out)
+        .unwrap();
+    out
+}
+
+async fn decrypt(encrypted: &[u8], key: &EncryptionKey) -> anyhow::Result<Vec<u8>> {
+    let field = EncryptionKey::parse(KEY)?.narinfo_value();
+    let mut reader = encryption::decrypted(encrypted, &field, key).await?;
+    let mut out = Vec::new();
+    reader.read_to_end(&mut out).await?;
+    Ok(out)
+}
+
+#[tokio::test]
+async fn round_trip() {
+    let key = EncryptionKey::parse(KEY).unwrap();
+    // empty, a partial chunk, exactly two chunks and a bit more
+    for len in [0, 1000, 2 * 64 * 1024, 200_000] {
+        let data: Vec<u8> = (0..len).map(|x| (x % 251) as u8).collect();
+        let encrypted = encrypt(Arc::new(NoCompression), &key, &data);
+        assert_eq!(decrypt(&encrypted, &key).await.unwrap(), data, "len {len}");
+    }
+}
+
+#[tokio::test]
+async fn compresses_before_encrypting() {
+    let key = EncryptionKey::parse(KEY).unwrap();
+    let data = vec![b'a'; 1 << 20];
+    let encrypted = encrypt(Arc::new(Zstd::default()), &key, &data);
+    assert!(encrypted.len() < data.len() / 100);
+    let compressed = decrypt(&encrypted, &key).await.unwrap();
+    assert_eq!(zstd::decode_all(compressed.as_slice()).unwrap(), data);
+}
+
+#[tokio::test]
+async fn rejects_wrong_key_and_truncation() {
+    let key = EncryptionKey::parse(KEY).unwrap();
+    let other = EncryptionKey::parse(OTHER_KEY).unwrap();
+    assert_ne!(key.id(), other.id());
+    let data = vec![7; 200_000];
+    let encrypted = encrypt(Arc::new(NoCompression), &key, &data);
+
+    // the narinfo names a different key
+    assert!(decrypt(&encrypted, &other).await.is_err());
+    // cut at the end of the first chunk
+    assert!(
+        decrypt(&encrypted[..15 + 64 * 1024 + 16], &key)
+            .await
+            .is_err()
+    );
+}
+
+#[test]
+fn narinfo_field() {
+    let narinfo = "StorePath: /nix/store/y4qpcibkj767szhjb58i2sidmz8m24hb-hello-2.12.1\n";
+    assert_eq!(encryption::narinfo_field(narinfo), None);
+    let key = EncryptionKey::parse(KEY).unwrap();
+    let narinfo = encryption::with_narinfo_field(narinfo.to_string(), Some(&key.narinfo_value()));
+    assert_eq!(
+        encryption::narinfo_field(&narinfo),
+        Some(key.narinfo_value().as_str())
+    );
+    assert!(EncryptionKey::parse("dG9vIHNob3J0").is_err());
+}
@@ -0,0 +1,15 @@
+use nixcp::fetch::CacheUri;
+use url::Url;
+
+#[test]
+fn cache_uri_schemes() {
+    assert!(matches!(
+        "s3://nixcache?region=eu-west-1".parse::<CacheUri>().unwrap(),
+        CacheUri::S3(s3_uri) if s3_uri.bucket == "nixcache"
+    ));
+    assert_eq!(
+        "https://cache.nixos.org".parse::<CacheUri>().unwrap(),
+        CacheUri::Http(Url::parse("https://cache.nixos.org").unwrap())
+    );
+    assert!("file:///tmp/cache".parse::<CacheUri>().is_err());
+}
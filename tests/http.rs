@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use nixcp::http::{UpstreamHeader, parse_retry_after};
+
+#[test]
+fn upstream_header() {
+    let header: UpstreamHeader = "X-Auth: secret".parse().unwrap();
+    assert_eq!(header.host, None);
+    assert_eq!(header.name, "x-auth");
+    assert_eq!(header.value, "secret");
+
+    let header: UpstreamHeader = "nixcache.example.com=Authorization: Bearer a=b"
+        .parse()
+        .unwrap();
+    assert_eq!(header.host.as_deref(), Some("nixcache.example.com"));
+    assert_eq!(header.name, "authorization");
+    assert_eq!(header.value, "Bearer a=b");
+
+    assert!("X-Auth".parse::<UpstreamHeader>().is_err());
+    assert!("X Auth: secret".parse::<UpstreamHeader>().is_err());
+}
+
+#[test]
+fn retry_after() {
+    let now = DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+        .unwrap()
+        .with_timezone(&Utc);
+    assert_eq!(
+        parse_retry_after("120", now),
+        Some(Duration::from_secs(120))
+    );
+    assert_eq!(
+        parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+        Some(Duration::from_secs(30))
+    );
+    // already passed
+    assert_eq!(
+        parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+        Some(Duration::ZERO)
+    );
+    assert_eq!(parse_retry_after("soon", now), None);
+}
@@ -0,0 +1,37 @@
+use nix_compat::store_path::StorePath;
+use nixcp::{index::PushIndex, path_info::PathInfo};
+use tempfile::TempDir;
+
+fn path_info(nar_hash: [u8; 32]) -> PathInfo {
+    PathInfo {
+        path: StorePath::from_bytes(b"9bwryidal9q3g91cjm6xschfn4ikd82q-hello-2.12.1").unwrap(),
+        signatures: Vec::new(),
+        references: Vec::new(),
+        nar_size: 234680,
+        nar_hash,
+        ca: None,
+        deriver: None,
+        system: None,
+        ultimate: false,
+    }
+}
+
+#[test]
+fn index_round_trip() {
+    let dir = TempDir::new().unwrap();
+    let file = dir.path().join("nixcp/index");
+    let pushed = path_info([1; 32]);
+
+    let index = PushIndex::open(&file, "s3://nixcache/".to_string()).unwrap();
+    assert!(!index.contains(&pushed));
+    index.insert(index.key(&pushed));
+    index.save().unwrap();
+
+    let index = PushIndex::open(&file, "s3://nixcache/".to_string()).unwrap();
+    assert!(index.contains(&pushed));
+    // same path with different contents
+    assert!(!index.contains(&path_info([2; 32])));
+
+    let other = PushIndex::open(&file, "s3://othercache/".to_string()).unwrap();
+    assert!(!other.contains(&pushed));
+}
@@ -1,5 +1,9 @@
+use std::sync::Arc;
+
 use crate::common::HELLO_PATH;
+use async_compression::tokio::bufread::{XzDecoder, ZstdDecoder};
 use nix_compat::nixbase32;
+use nixcp::compression::Xz;
 use nixcp::make_nar::MakeNar;
 use nixcp::path_info::PathInfo;
 use sha2::Digest;
@@ -24,3 +28,52 @@ async fn nar_size_and_hash() {
     let real_nar_hash = "08za7nnjda8kpdsd73v3mhykjvp0rsmskwsr37winhmzgm6iw79w";
     assert_eq!(nixbase32::encode(nar_hash.as_slice()), real_nar_hash);
 }
+
+#[tokio::test]
+async fn long_distance_matching_decompresses() {
+    let ctx = common::context();
+    let path_info = PathInfo::from_path(HELLO_PATH, &ctx.store).await.unwrap();
+
+    let mut nar = MakeNar::new(&path_info, ctx.store.clone())
+        .unwrap()
+        .long_distance_matching(Some(27));
+    let mut reader = nar.compress_and_hash().unwrap();
+    let mut compressed = Vec::new();
+    reader.read_to_end(&mut compressed).await.unwrap();
+    drop(reader);
+
+    // a decoder with default settings must be able to decompress it
+    let mut decoder = ZstdDecoder::new(compressed.as_slice());
+    let mut decompressed = Vec::new();
+    decoder.read_to_end(&mut decompressed).await.unwrap();
+    assert_eq!(decompressed.len() as u64, nar.nar_size);
+    assert_eq!(
+        sha2::Sha256::digest(&decompressed).as_slice(),
+        nar.nar_hasher.finalize().as_slice()
+    );
+}
+
+#[tokio::test]
+async fn other_compression_decompresses() {
+    let ctx = common::context();
+    let path_info = PathInfo::from_path(HELLO_PATH, &ctx.store).await.unwrap();
+
+    let mut nar = MakeNar::new(&path_info, ctx.store.clone())
+        .unwrap()
+        .with_compression(Arc::new(Xz::default()));
+    assert_eq!(nar.compression(), "xz");
+    assert_eq!(nar.extension(), ".xz");
+    let mut reader = nar.compress_and_hash().unwrap();
+    let mut compressed = Vec::new();
+    reader.read_to_end(&mut compressed).await.unwrap();
+    drop(reader);
+
+    let mut decoder = XzDecoder::new(compressed.as_slice());
+    let mut decompressed = Vec::new();
+    decoder.read_to_end(&mut decompressed).await.unwrap();
+    assert_eq!(decompressed.len() as u64, nar.nar_size);
+    assert_eq!(
+        sha2::Sha256::digest(&decompressed).as_slice(),
+        nar.nar_hasher.finalize().as_slice()
+    );
+}
@@ -1,5 +1,10 @@
-use nixcp::path_info::PathInfo;
-use std::{collections::HashSet, path::PathBuf, process::Command};
+use nix_compat::store_path::StorePath;
+use nixcp::{
+    path_info::{DanglingSymlink, PathInfo},
+    upstream_stats::UpstreamStats,
+};
+use object_store::{ObjectStore, local::LocalFileSystem};
+use std::{collections::HashSet, path::PathBuf, process::Command, sync::Arc};
 
 use tempfile::TempDir;
 
@@ -44,6 +49,26 @@ async fn path_info_symlink() {
     assert_eq!(path_info.path.to_absolute_path(), HELLO_DRV);
 }
 
+#[tokio::test]
+async fn path_info_dangling_symlink() {
+    let ctx = common::context();
+
+    let temp_path = TempDir::new().unwrap();
+    let link_path = temp_path.path().join("result");
+
+    // symlink whose target was garbage collected
+    std::os::unix::fs::symlink(
+        "/nix/store/00000000000000000000000000000000-gone",
+        &link_path,
+    )
+    .unwrap();
+
+    let err = PathInfo::from_derivation(&link_path, &ctx.store)
+        .await
+        .expect_err("dangling symlink should not resolve");
+    assert!(err.is::<DanglingSymlink>());
+}
+
 #[tokio::test]
 async fn closure_includes_nix_store_requisites() {
     let ctx = common::context();
@@ -95,3 +120,45 @@ async fn closure_includes_nix_store_requisites() {
         assert!(closure.contains(path));
     }
 }
+
+#[tokio::test]
+async fn upstream_store_hit_in_local_directory() {
+    let dir = TempDir::new().unwrap();
+    let path_info = PathInfo {
+        path: StorePath::from_absolute_path(HELLO_PATH.as_bytes()).unwrap(),
+        signatures: Vec::new(),
+        references: Vec::new(),
+        nar_size: 0,
+        nar_hash: [0; 32],
+        ca: None,
+        deriver: None,
+        system: None,
+        ultimate: false,
+    };
+    let stores: Vec<Arc<dyn ObjectStore>> = vec![Arc::new(
+        LocalFileSystem::new_with_prefix(dir.path()).unwrap(),
+    )];
+    let stats = UpstreamStats::default();
+
+    assert!(
+        !path_info
+            .check_upstream_store_hit(&stores, &stats)
+            .await
+            .unwrap()
+    );
+    std::fs::write(
+        dir.path().join(path_info.narinfo_path().as_ref()),
+        format!("StorePath: {HELLO_PATH}\n"),
+    )
+    .unwrap();
+    assert!(
+        path_info
+            .check_upstream_store_hit(&stores, &stats)
+            .await
+            .unwrap()
+    );
+
+    let stats = stats.snapshot();
+    assert_eq!(stats.len(), 1);
+    assert_eq!((stats[0].1.checks, stats[0].1.hits), (2, 1));
+}
@@ -0,0 +1,102 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use chrono::{TimeZone, Utc};
+use nixcp::prune::{CachedPath, ManagedKeys, parse_access_log, parse_age, stale_narinfos};
+
+#[test]
+fn age_units() {
+    assert_eq!(
+        parse_age("90d").unwrap(),
+        Duration::from_secs(90 * 24 * 60 * 60)
+    );
+    assert_eq!(parse_age("12h").unwrap(), Duration::from_secs(12 * 60 * 60));
+    assert_eq!(
+        parse_age("2w").unwrap(),
+        Duration::from_secs(14 * 24 * 60 * 60)
+    );
+    assert!(parse_age("90").is_err());
+    assert!(parse_age("d").is_err());
+    assert!(parse_age("90y").is_err());
+}
+
+#[test]
+fn access_log_keeps_latest_read() {
+    let log = "\
+owner nixcache [06/Feb/2025:00:00:38 +0000] 192.0.2.3 - 3E57427F3EXAMPLE REST.GET.OBJECT team-a/abc.narinfo \"GET /nixcache/team-a/abc.narinfo HTTP/1.1\" 200 -
+owner nixcache [08/Feb/2025:12:30:00 +0200] 192.0.2.3 - 3E57427F4EXAMPLE REST.HEAD.OBJECT team-a/abc.narinfo \"HEAD /nixcache/team-a/abc.narinfo HTTP/1.1\" 200 -
+owner nixcache [09/Feb/2025:00:00:00 +0000] 192.0.2.3 - 3E57427F5EXAMPLE REST.PUT.OBJECT team-a/def.narinfo \"PUT /nixcache/team-a/def.narinfo HTTP/1.1\" 200 -
+owner nixcache [09/Feb/2025:00:00:00 +0000] 192.0.2.3 - 3E57427F6EXAMPLE REST.GET.OBJECT team-b/ghi.narinfo \"GET /nixcache/team-b/ghi.narinfo HTTP/1.1\" 200 -
+owner nixcache [07/Feb/2025:00:00:00 +0000] 192.0.2.3 - 3E57427F7EXAMPLE REST.GET.OBJECT team-a%2Fnar%2Fxyz.nar.zst \"GET /nixcache/team-a/nar/xyz.nar.zst HTTP/1.1\" 200 -
+";
+    let last_access = parse_access_log(log, Some("team-a/"));
+    assert_eq!(last_access.len(), 2);
+    assert_eq!(
+        last_access["abc.narinfo"],
+        Utc.with_ymd_and_hms(2025, 2, 8, 10, 30, 0).unwrap()
+    );
+    assert_eq!(
+        last_access["nar/xyz.nar.zst"],
+        Utc.with_ymd_and_hms(2025, 2, 7, 0, 0, 0).unwrap()
+    );
+}
+
+#[test]
+fn managed_keys() {
+    let managed = ManagedKeys::new([
+        "nar/1w1fff338fvdw53sqgamddn1b2xgds473pv6y13gizdbqjv4i5p3.nar.zst",
+        "cache/nars/0zk6l4ykkr2cahw4mbs3z5kmcbpiy6qmmn4l0fr2jxkz4c3amb6z.nar",
+        "1b9p07z77phvv2hf6gm9f28syp39f1ag2f1jaz0w1pdp6x4l2mc9.nar.xz",
+    ]);
+    assert!(managed.contains("y4qpcibkj767szhjb58i2sidmz8m24hb.narinfo"));
+    assert!(managed.contains("nar/1w1fff338fvdw53sqgamddn1b2xgds473pv6y13gizdbqjv4i5p3.nar.zst"));
+    // next to a nar a narinfo still points to
+    assert!(
+        managed.contains("cache/nars/1ngi2dxw1f7khrrjamzkkdrx5a4bwrh8s8dq5fgxyyvcvhq0w2gb.nar")
+    );
+    // nars at the root only count by their exact url
+    assert!(managed.contains("1b9p07z77phvv2hf6gm9f28syp39f1ag2f1jaz0w1pdp6x4l2mc9.nar.xz"));
+    assert!(!managed.contains("nix-cache-info"));
+    assert!(!managed.contains("releases/hello/latest.json"));
+    assert!(!managed.contains("nixcp.bloom"));
+    assert!(!managed.contains("realisations/sha256:abc!out.doi"));
+}
+
+#[test]
+fn keeps_what_fresh_paths_reference() {
+    let cached_path = |references: &[&str]| CachedPath {
+        nar_url: String::new(),
+        references: references.iter().map(|x| x.to_string()).collect(),
+    };
+    let cached_paths = HashMap::from([
+        ("hello.narinfo", cached_path(&["glibc.narinfo"])),
+        ("glibc.narinfo", cached_path(&["glibc.narinfo"])),
+        ("old-tool.narinfo", cached_path(&["old-lib.narinfo"])),
+        ("old-lib.narinfo", cached_path(&[])),
+        ("release.narinfo", cached_path(&["release-dep.narinfo"])),
+        ("release-dep.narinfo", cached_path(&[])),
+    ]);
+    let recently_used = HashSet::from(["hello.narinfo"]);
+
+    let stale = stale_narinfos(
+        &cached_paths,
+        &["release.narinfo".to_string()],
+        &recently_used,
+    )
+    .unwrap();
+    assert_eq!(
+        stale,
+        HashSet::from(["old-tool.narinfo", "old-lib.narinfo"])
+    );
+
+    assert!(
+        stale_narinfos(
+            &cached_paths,
+            &["missing.narinfo".to_string()],
+            &recently_used
+        )
+        .is_err()
+    );
+}
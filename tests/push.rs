@@ -0,0 +1,23 @@
+use nixcp::push::{package_name, parse_size};
+
+#[test]
+fn size_units() {
+    assert_eq!(parse_size("4096").unwrap(), 4096);
+    assert_eq!(parse_size("512MiB").unwrap(), 512 * 1024 * 1024);
+    assert_eq!(parse_size("2G").unwrap(), 2 * 1024 * 1024 * 1024);
+    assert_eq!(parse_size("10MB").unwrap(), 10_000_000);
+    assert!(parse_size("MiB").is_err());
+    assert!(parse_size("5TiB").is_err());
+}
+
+#[test]
+fn package_names() {
+    assert_eq!(package_name("hello-2.12.1"), "hello");
+    assert_eq!(package_name("glibc-2.40-66-dev"), "glibc");
+    assert_eq!(
+        package_name("python3.12-requests-2.32.3"),
+        "python3.12-requests"
+    );
+    assert_eq!(package_name("source"), "source");
+    assert_eq!(package_name("hello-2.12.1.drv"), "hello");
+}
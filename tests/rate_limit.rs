@@ -0,0 +1,23 @@
+use std::time::{Duration, Instant};
+
+use nixcp::rate_limit::RateLimiter;
+use url::Url;
+
+#[tokio::test]
+async fn burst_then_rate() {
+    let limiter = RateLimiter::new(Some(20.0), 2);
+    let upstream = Url::parse("https://cache.nixos.org").unwrap();
+    let other = Url::parse("https://nix-community.cachix.org").unwrap();
+
+    let start = Instant::now();
+    limiter.acquire(&upstream).await;
+    limiter.acquire(&upstream).await;
+    // upstreams have their own buckets
+    limiter.acquire(&other).await;
+    assert!(start.elapsed() < Duration::from_millis(40));
+
+    // two more at 20 rps take at least 100ms
+    limiter.acquire(&upstream).await;
+    limiter.acquire(&upstream).await;
+    assert!(start.elapsed() >= Duration::from_millis(90));
+}
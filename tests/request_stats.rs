@@ -0,0 +1,56 @@
+use std::sync::Arc;
+
+use futures::TryStreamExt;
+use nixcp::request_stats::{CountingStore, RequestCounts, RequestStats};
+use object_store::{ObjectStore, WriteMultipart, memory::InMemory, path::Path};
+
+#[tokio::test]
+async fn counts_by_kind() {
+    let stats = Arc::new(RequestStats::new(None));
+    let store = CountingStore::new(Arc::new(InMemory::new()), stats.clone());
+    let narinfo = Path::from("abc.narinfo");
+    let nar = Path::from("nar/abc.nar.zst");
+
+    store.put(&narinfo, "narinfo".into()).await.unwrap();
+    store.head(&narinfo).await.unwrap();
+    assert!(store.head(&nar).await.is_err());
+    store.get(&narinfo).await.unwrap();
+    store
+        .rename(&narinfo, &Path::from("def.narinfo"))
+        .await
+        .unwrap();
+    let mut upload = WriteMultipart::new(store.put_multipart(&nar).await.unwrap());
+    upload.write(b"nar");
+    upload.finish().await.unwrap();
+    let listed: Vec<_> = store.list(None).try_collect().await.unwrap();
+    assert_eq!(listed.len(), 2);
+
+    let counts = stats.snapshot();
+    assert_eq!(
+        counts,
+        RequestCounts {
+            head: 2,
+            get: 1,
+            // the multipart upload is started, given a part and completed
+            put: 4,
+            copy: 1,
+            delete: 1,
+            list: 1,
+        }
+    );
+    assert_eq!(counts.total(), 10);
+}
+
+#[tokio::test]
+async fn refuses_requests_past_max() {
+    let stats = Arc::new(RequestStats::new(Some(2)));
+    let store = CountingStore::new(Arc::new(InMemory::new()), stats.clone());
+    let key = Path::from("abc.narinfo");
+
+    store.put(&key, "narinfo".into()).await.unwrap();
+    store.head(&key).await.unwrap();
+    let err = store.get(&key).await.unwrap_err();
+    assert!(err.to_string().contains("--max-requests 2"), "{err}");
+    assert!(store.list(None).try_next().await.is_err());
+    assert_eq!(stats.snapshot().total(), 2);
+}
@@ -0,0 +1,61 @@
+use nixcp::s3_uri::{S3Uri, partition_dns_suffix};
+
+#[test]
+fn s3_uri_with_params() {
+    let uri: S3Uri =
+        "s3://nixcache?region=eu-west-1&endpoint=minio.local:9000&scheme=http&profile=ci"
+            .parse()
+            .unwrap();
+    assert_eq!(
+        uri,
+        S3Uri {
+            bucket: "nixcache".to_string(),
+            region: Some("eu-west-1".to_string()),
+            endpoint: Some("http://minio.local:9000".to_string()),
+            profile: Some("ci".to_string()),
+            path_style: None,
+            allow_http: false,
+            sts_endpoint: None,
+        }
+    );
+}
+
+#[test]
+fn s3_uri_defaults_to_https() {
+    let uri: S3Uri = "s3://nixcache?endpoint=s3.example.com&compression=zstd"
+        .parse()
+        .unwrap();
+    assert_eq!(uri.endpoint.as_deref(), Some("https://s3.example.com"));
+    assert_eq!(uri.region, None);
+}
+
+#[test]
+fn s3_uri_rejects_other_schemes() {
+    assert!("https://nixcache".parse::<S3Uri>().is_err());
+    assert!("s3://".parse::<S3Uri>().is_err());
+}
+
+#[test]
+fn s3_uri_addressing_style() {
+    let uri: S3Uri = "s3://nixcache?endpoint=garage.local&addressing-style=path"
+        .parse()
+        .unwrap();
+    assert_eq!(uri.path_style, Some(true));
+    let uri: S3Uri = "s3://nixcache?addressing-style=virtual".parse().unwrap();
+    assert_eq!(uri.path_style, Some(false));
+    assert!(
+        "s3://nixcache?addressing-style=sideways"
+            .parse::<S3Uri>()
+            .is_err()
+    );
+
+    let uri = S3Uri::from_args(Some(&uri), None, None, None, true, false, None).unwrap();
+    assert_eq!(uri.path_style, Some(true));
+}
+
+#[test]
+fn partitions() {
+    assert_eq!(partition_dns_suffix("eu-west-1"), "amazonaws.com");
+    assert_eq!(partition_dns_suffix("us-gov-west-1"), "amazonaws.com");
+    assert_eq!(partition_dns_suffix("cn-northwest-1"), "amazonaws.com.cn");
+}
@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+use nixcp::upstream_config::{RateLimit, UpstreamsFile};
+
+const FILE: &str = r#"{
+  "upstreams": [
+    { "url": "https://cache.nixos.org", "enabled": false },
+    { "url": "https://nixcache.example.com", "timeout": "5s", "headers": { "X-Auth": "secret" } },
+    { "url": "https://slow.example.com", "rps": 2.5 }
+  ]
+}"#;
+
+#[test]
+fn merges_with_flags() {
+    let file = UpstreamsFile::parse(FILE).unwrap();
+    let upstreams: Vec<_> = file
+        .upstreams(false, &["https://slow.example.com".to_string()])
+        .unwrap()
+        .into_iter()
+        .map(|url| url.to_string())
+        .collect();
+    // disabled cache.nixos.org is left out and the flag doesn't add slow.example.com twice
+    assert_eq!(
+        upstreams,
+        ["https://nixcache.example.com/", "https://slow.example.com/"]
+    );
+
+    // --upstream brings back a disabled upstream
+    let upstreams = file
+        .upstreams(false, &["https://cache.nixos.org".to_string()])
+        .unwrap();
+    assert_eq!(
+        upstreams.last().unwrap().as_str(),
+        "https://cache.nixos.org/"
+    );
+}
+
+#[test]
+fn per_upstream_options() {
+    let file = UpstreamsFile::parse(FILE).unwrap();
+    assert_eq!(
+        file.timeouts().unwrap(),
+        [("nixcache.example.com".to_string(), Duration::from_secs(5))]
+    );
+    assert_eq!(
+        file.rate_limits().unwrap(),
+        [(
+            "slow.example.com".to_string(),
+            RateLimit {
+                rps: 2.5,
+                burst: None
+            }
+        )]
+    );
+    let headers = file.headers().unwrap();
+    assert_eq!(headers.len(), 1);
+    assert_eq!(headers[0].host.as_deref(), Some("nixcache.example.com"));
+    assert_eq!(headers[0].name, "x-auth");
+}
+
+#[test]
+fn rejects_bad_files() {
+    assert!(UpstreamsFile::parse(r#"{ "upstreams": [{ "url": "not a url" }] }"#).is_err());
+    assert!(
+        UpstreamsFile::parse(r#"{ "upstreams": [{ "url": "https://a.example.com", "rps": 0 }] }"#)
+            .is_err()
+    );
+    // typos shouldn't be ignored silently
+    assert!(UpstreamsFile::parse(r#"{ "upstream": [] }"#).is_err());
+}
@@ -0,0 +1,39 @@
+use std::time::Duration;
+
+use nixcp::upstream_stats::{CheckOutcome, UpstreamCounters, UpstreamStats};
+
+#[test]
+fn counts_per_upstream() {
+    let stats = UpstreamStats::default();
+    let nixos = "https://cache.nixos.org/";
+    let community = "https://nix-community.cachix.org/";
+    stats.record(nixos, Duration::from_millis(10), CheckOutcome::Hit);
+    stats.record(nixos, Duration::from_millis(30), CheckOutcome::Miss);
+    stats.record(community, Duration::from_millis(50), CheckOutcome::Error);
+
+    let snapshot = stats.snapshot();
+    assert_eq!(
+        snapshot,
+        [
+            (
+                nixos.to_string(),
+                UpstreamCounters {
+                    checks: 2,
+                    hits: 1,
+                    errors: 0,
+                    total_latency: Duration::from_millis(40),
+                }
+            ),
+            (
+                community.to_string(),
+                UpstreamCounters {
+                    checks: 1,
+                    hits: 0,
+                    errors: 1,
+                    total_latency: Duration::from_millis(50),
+                }
+            ),
+        ]
+    );
+    assert_eq!(snapshot[0].1.average_latency(), Duration::from_millis(20));
+}
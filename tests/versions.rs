@@ -0,0 +1,81 @@
+use nixcp::versions::{ObjectVersion, VersionMarker, parse_versions};
+
+#[test]
+fn parses_versions_and_delete_markers() {
+    let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListVersionsResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+  <Name>nixcache</Name>
+  <Prefix></Prefix>
+  <KeyMarker></KeyMarker>
+  <VersionIdMarker></VersionIdMarker>
+  <NextKeyMarker>nar/b&amp;c.nar.zst</NextKeyMarker>
+  <NextVersionIdMarker>v3</NextVersionIdMarker>
+  <MaxKeys>1000</MaxKeys>
+  <IsTruncated>true</IsTruncated>
+  <DeleteMarker>
+    <Key>abc.narinfo</Key>
+    <VersionId>v2</VersionId>
+    <IsLatest>true</IsLatest>
+    <LastModified>2025-02-06T00:00:38.000Z</LastModified>
+    <Owner><ID>owner</ID></Owner>
+  </DeleteMarker>
+  <Version>
+    <Key>abc.narinfo</Key>
+    <VersionId>v1</VersionId>
+    <IsLatest>false</IsLatest>
+    <LastModified>2025-01-06T00:00:38.000Z</LastModified>
+    <ETag>"d41d8cd98f00b204e9800998ecf8427e"</ETag>
+    <Size>512</Size>
+    <StorageClass>STANDARD</StorageClass>
+  </Version>
+  <Version>
+    <Key>nar/b&amp;c.nar.zst</Key>
+    <VersionId>v3</VersionId>
+    <IsLatest>true</IsLatest>
+    <Size>4096</Size>
+  </Version>
+</ListVersionsResult>"#;
+
+    let (versions, next) = parse_versions(xml).unwrap();
+    assert_eq!(
+        versions,
+        [
+            ObjectVersion {
+                key: "abc.narinfo".to_string(),
+                version_id: "v1".to_string(),
+                is_latest: false,
+                delete_marker: false,
+                size: 512,
+            },
+            ObjectVersion {
+                key: "nar/b&c.nar.zst".to_string(),
+                version_id: "v3".to_string(),
+                is_latest: true,
+                delete_marker: false,
+                size: 4096,
+            },
+            ObjectVersion {
+                key: "abc.narinfo".to_string(),
+                version_id: "v2".to_string(),
+                is_latest: true,
+                delete_marker: true,
+                size: 0,
+            },
+        ]
+    );
+    assert_eq!(
+        next,
+        Some(VersionMarker {
+            key: "nar/b&c.nar.zst".to_string(),
+            version_id: "v3".to_string(),
+        })
+    );
+}
+
+#[test]
+fn last_page_has_no_marker() {
+    let xml = "<ListVersionsResult><IsTruncated>false</IsTruncated></ListVersionsResult>";
+    let (versions, next) = parse_versions(xml).unwrap();
+    assert!(versions.is_empty());
+    assert_eq!(next, None);
+}
@@ -0,0 +1,32 @@
+use chrono::NaiveTime;
+use nixcp::watch::UploadWindow;
+
+fn time(s: &str) -> NaiveTime {
+    NaiveTime::parse_from_str(s, "%H:%M").unwrap()
+}
+
+#[test]
+fn upload_window_within_a_day() {
+    let window: UploadWindow = "09:30-17:00".parse().unwrap();
+    assert!(!window.contains(time("09:29")));
+    assert!(window.contains(time("09:30")));
+    assert!(window.contains(time("16:59")));
+    assert!(!window.contains(time("17:00")));
+}
+
+#[test]
+fn upload_window_past_midnight() {
+    let window: UploadWindow = "22:00-06:00".parse().unwrap();
+    assert!(window.contains(time("23:15")));
+    assert!(window.contains(time("00:00")));
+    assert!(window.contains(time("05:59")));
+    assert!(!window.contains(time("06:00")));
+    assert!(!window.contains(time("12:00")));
+}
+
+#[test]
+fn upload_window_rejects_garbage() {
+    assert!("22:00".parse::<UploadWindow>().is_err());
+    assert!("22:00-25:00".parse::<UploadWindow>().is_err());
+    assert!("06:00-06:00".parse::<UploadWindow>().is_err());
+}